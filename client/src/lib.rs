@@ -0,0 +1,639 @@
+//! An async client for mini-dynamo that services can embed directly, rather than shelling out to
+//! the CLI.
+//!
+//! The client only asks the manager *where* keys live, and caches the answer as a partition map.
+//! Requests themselves go straight to the storage nodes.
+//!
+//! Writes that race through different replicas don't overwrite each other. Reads return all of
+//! them as siblings, along with a context. Writing with that context replaces every sibling it
+//! covers, so that's how a client resolves a conflict.
+
+use comm::{pool::Pool, Message, PartitionMap, Role};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{task::JoinSet, time::timeout};
+
+pub use comm::{range_map::after, Consistency, Epoch, Error, ErrorCode, Result, VectorClock};
+
+/// What a read found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned {
+    /// Every version nobody has superseded yet. More than one means writes raced.
+    pub values: Vec<String>,
+    /// Pass this back when writing to supersede everything in `values`.
+    pub context: VectorClock,
+}
+
+impl Versioned {
+    /// The value, as long as there aren't any siblings to pick between.
+    pub fn value(&self) -> Option<&str> {
+        match self.values.as_slice() {
+            [value] => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn has_siblings(&self) -> bool {
+        self.values.len() > 1
+    }
+}
+
+/// How far a storage node has gotten pulling in keys after the ring changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handoff {
+    /// Epoch the ring change started at.
+    pub since: Epoch,
+    /// Buckets of keys pulled in so far, out of `total`.
+    pub done: u32,
+    pub total: u32,
+}
+
+/// One page of a [`Client::scan`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    /// Keys in order, each with every version nobody has superseded yet.
+    pub items: Vec<(String, Vec<String>)>,
+    /// Where the next page starts, or `None` if there's nothing left.
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// How this client introduces itself to the cluster.
+    pub node_id: String,
+    /// How long to wait for a connection to open.
+    pub connect_timeout: Duration,
+    /// How long a single attempt at a request may take.
+    pub request_timeout: Duration,
+    /// How many times to retry a request that failed for reasons that might go away on their own
+    /// (dropped connections, timeouts, busy nodes).
+    pub retries: usize,
+    /// How long to wait before the first retry. Doubles with each retry after that.
+    pub retry_backoff: Duration,
+    /// Connections to keep open per storage node.
+    pub pool_size: usize,
+    /// Storage nodes to ask for a partition map if the manager can't be reached before we have
+    /// one of our own.
+    pub seeds: Vec<SocketAddr>,
+    /// Other managers to try if the one we're talking to can't be reached or doesn't know who's
+    /// leading.
+    pub managers: Vec<SocketAddr>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            node_id: format!("client-{}", std::process::id()),
+            connect_timeout: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(2),
+            retries: 3,
+            retry_backoff: Duration::from_millis(50),
+            pool_size: 4,
+            seeds: Vec::new(),
+            managers: Vec::new(),
+        }
+    }
+}
+
+struct Inner {
+    /// Address of the manager. Moves to whichever manager is leading when one points us there.
+    mgr_addr: RwLock<SocketAddr>,
+    /// Every manager we were told about, to fall back on if that one goes away.
+    managers: Vec<SocketAddr>,
+    config: ClientConfig,
+    pool: Pool,
+    /// Our copy of the manager's partition map. `None` until the first request needs it.
+    map: RwLock<Option<Arc<PartitionMap>>>,
+    /// Held while fetching the first map so a batch doesn't send the manager one request per key.
+    first_fetch: tokio::sync::Mutex<()>,
+}
+
+/// Handle to the cluster. Cheap to clone; clones share a connection pool.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+impl Client {
+    pub fn new(mgr_addr: SocketAddr) -> Self {
+        Self::with_config(mgr_addr, ClientConfig::default())
+    }
+
+    pub fn with_config(mgr_addr: SocketAddr, config: ClientConfig) -> Self {
+        let pool = Pool::new(
+            Role::Client,
+            config.node_id.clone(),
+            config.pool_size,
+            config.connect_timeout,
+        );
+        let mut managers = vec![mgr_addr];
+        managers.extend(&config.managers);
+        Self {
+            inner: Arc::new(Inner {
+                mgr_addr: RwLock::new(mgr_addr),
+                managers,
+                config,
+                pool,
+                map: RwLock::new(None),
+                first_fetch: tokio::sync::Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn mgr_addr(&self) -> SocketAddr {
+        *self.inner.mgr_addr.read().expect("Lock poisoned :(")
+    }
+
+    /// Our cached partition map, fetching it first if we don't have one yet.
+    pub async fn partition_map(&self) -> Result<Arc<PartitionMap>> {
+        let cached = || self.inner.map.read().expect("Lock poisoned :(").clone();
+        if let Some(map) = cached() {
+            return Ok(map);
+        }
+        let _guard = self.inner.first_fetch.lock().await;
+        match cached() {
+            Some(map) => Ok(map),
+            None => self.refresh_partition_map().await,
+        }
+    }
+
+    /// Fetches the manager's current partition map, replacing our copy if it's newer. If the
+    /// manager can't be reached, asks the storage nodes in our copy instead; they pass the map
+    /// around among themselves, so it's no staler than what the manager last handed out.
+    pub async fn refresh_partition_map(&self) -> Result<Arc<PartitionMap>> {
+        let map = match self.ask_manager(Message::GetPartitionMap).await {
+            Ok(Message::PartitionMap { map }) => map,
+            Ok(other) => return Err(comm::unexpected(other)),
+            Err(e) if e.is_transient() => self.fetch_partition_map_from_stores(e).await?,
+            Err(e) => return Err(e),
+        };
+
+        let mut cached = self.inner.map.write().expect("Lock poisoned :(");
+        match &*cached {
+            // Someone else refreshed to something at least as new in the meantime.
+            Some(current) if current.epoch >= map.epoch => Ok(current.clone()),
+            _ => {
+                let map = Arc::new(map);
+                *cached = Some(map.clone());
+                Ok(map)
+            }
+        }
+    }
+
+    /// Sends `msg` to the manager, following it to whichever manager is leading if the one we
+    /// asked isn't. Managers that can't be reached or don't know who's leading get passed over
+    /// for the others we know of. If none of them will answer, waits a bit for an election and
+    /// goes around again before giving up.
+    async fn ask_manager(&self, msg: Message) -> Result<Message> {
+        let mut backoff = self.inner.config.retry_backoff;
+        let mut last_err = None;
+        for _ in 0..=self.inner.config.retries {
+            // Whoever we heard was leading first, then everyone else, each at most once a round.
+            let mut queue: VecDeque<_> = self.inner.managers.iter().copied().collect();
+            queue.push_front(self.mgr_addr());
+            let mut tried = Vec::new();
+            while let Some(addr) = queue.pop_front() {
+                if tried.contains(&addr) {
+                    continue;
+                }
+                tried.push(addr);
+                match self.request_to(addr, msg.clone()).await {
+                    Ok(Message::NotLeader {
+                        leader: Some(leader),
+                    }) => queue.push_front(leader),
+                    Ok(Message::NotLeader { leader: None }) => {}
+                    Err(e) if e.is_transient() => last_err = Some(e),
+                    result => {
+                        *self.inner.mgr_addr.write().expect("Lock poisoned :(") = addr;
+                        return result;
+                    }
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        Err(last_err.unwrap_or(Error::Remote {
+            code: ErrorCode::Unavailable,
+            message: "no manager is leading".to_string(),
+        }))
+    }
+
+    async fn fetch_partition_map(&self, addr: SocketAddr) -> Result<PartitionMap> {
+        match self.request_to(addr, Message::GetPartitionMap).await? {
+            Message::PartitionMap { map } => Ok(map),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// First map any store we know of hands back. `mgr_err` is what we return if none do.
+    async fn fetch_partition_map_from_stores(&self, mgr_err: Error) -> Result<PartitionMap> {
+        let mut known = match &*self.inner.map.read().expect("Lock poisoned :(") {
+            Some(map) => map.members(),
+            None => Vec::new(),
+        };
+        known.extend(&self.inner.config.seeds);
+        for node in known {
+            if let Ok(map) = self.fetch_partition_map(node).await {
+                return Ok(map);
+            }
+        }
+        Err(mgr_err)
+    }
+
+    /// Storage nodes responsible for `key`, in the order they should be tried.
+    pub async fn owners(&self, key: &str) -> Result<Vec<SocketAddr>> {
+        Ok(self.partition_map().await?.owners(key))
+    }
+
+    pub async fn get(&self, key: impl Into<String>) -> Result<Option<Versioned>> {
+        self.get_with(key, Consistency::default()).await
+    }
+
+    /// Like [`Client::get`], but overrides how many replicas the read consults.
+    pub async fn get_with(
+        &self,
+        key: impl Into<String>,
+        consistency: Consistency,
+    ) -> Result<Option<Versioned>> {
+        let key = key.into();
+        let msg = Message::Get {
+            key: key.clone(),
+            consistency,
+        };
+        match self.request(&key, msg).await? {
+            Message::Found { values, context } => Ok(Some(Versioned { values, context })),
+            Message::NotFound => Ok(None),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// Writes `value` over whatever the coordinating replica has for `key`. Versions it hasn't
+    /// heard of yet survive as siblings.
+    pub async fn put(&self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.put_with(key, value, None, Consistency::default())
+            .await
+    }
+
+    /// Like [`Client::put`], but supersedes exactly the versions in `context` (from an earlier
+    /// read) when there is one, and overrides how many replicas have to acknowledge the write.
+    pub async fn put_with(
+        &self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+        context: Option<VectorClock>,
+        consistency: Consistency,
+    ) -> Result<()> {
+        let key = key.into();
+        let msg = Message::Put {
+            key: key.clone(),
+            value: value.into(),
+            consistency,
+            context,
+        };
+        match self.request(&key, msg).await? {
+            Message::DonePut => Ok(()),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    pub async fn delete(&self, key: impl Into<String>) -> Result<()> {
+        self.delete_with(key, None, Consistency::default()).await
+    }
+
+    /// Like [`Client::delete`], but supersedes exactly the versions in `context` when there is
+    /// one, and overrides how many replicas have to acknowledge the delete.
+    pub async fn delete_with(
+        &self,
+        key: impl Into<String>,
+        context: Option<VectorClock>,
+        consistency: Consistency,
+    ) -> Result<()> {
+        let key = key.into();
+        let msg = Message::Delete {
+            key: key.clone(),
+            consistency,
+            context,
+        };
+        match self.request(&key, msg).await? {
+            Message::DoneDelete => Ok(()),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// Up to `limit` keys from `start` up to (not including) `end`, in order, going from range to
+    /// range as needed. Pass the page's `next` back as `start` to keep going. Only works on
+    /// range-partitioned clusters.
+    pub async fn scan(
+        &self,
+        start: impl Into<String>,
+        end: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        self.scan_with(start, end, limit, Consistency::default())
+            .await
+    }
+
+    /// Like [`Client::scan`], but overrides how many replicas each range's read consults.
+    pub async fn scan_with(
+        &self,
+        start: impl Into<String>,
+        end: Option<String>,
+        limit: usize,
+        consistency: Consistency,
+    ) -> Result<ScanPage> {
+        let mut page = ScanPage {
+            items: Vec::new(),
+            next: Some(start.into()),
+        };
+        while page.items.len() < limit {
+            let Some(start) = page.next.take() else {
+                break;
+            };
+            let msg = Message::Scan {
+                start: start.clone(),
+                end: end.clone(),
+                limit: limit - page.items.len(),
+                consistency,
+            };
+            match self.request(&start, msg).await? {
+                Message::Scanned { items, next } => {
+                    page.items.extend(items);
+                    page.next = next;
+                }
+                other => return Err(comm::unexpected(other)),
+            }
+        }
+        Ok(page)
+    }
+
+    /// Counters from the storage node at `addr`, by name.
+    pub async fn stats(&self, addr: SocketAddr) -> Result<BTreeMap<String, u64>> {
+        match self.request_to(addr, Message::GetStats).await? {
+            Message::Stats { counters } => Ok(counters),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// Asks the manager to add the storage node at `addr` to the ring, with `tokens` virtual nodes
+    /// if not the manager's default. It takes over its share of keys once the others have handed
+    /// them over; [`Client::handoff`] says how that's going.
+    pub async fn join(&self, addr: SocketAddr, tokens: Option<usize>) -> Result<()> {
+        match self
+            .ask_manager(Message::Join { node: addr, tokens })
+            .await?
+        {
+            Message::Accepted => Ok(()),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// Asks the manager to take the storage node at `addr` out of the ring. The rest of the ring
+    /// pulls in its keys first, then the manager tells it to shut down.
+    pub async fn decommission(&self, addr: SocketAddr) -> Result<()> {
+        match self
+            .ask_manager(Message::Decommission { node: addr })
+            .await?
+        {
+            Message::Accepted => Ok(()),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// How far the storage node at `addr` is with the latest ring change.
+    pub async fn handoff(&self, addr: SocketAddr) -> Result<Handoff> {
+        match self.request_to(addr, Message::GetHandoff).await? {
+            Message::Handoff { since, done, total } => Ok(Handoff { since, done, total }),
+            other => Err(comm::unexpected(other)),
+        }
+    }
+
+    /// Fetches every key at once. Results line up with `keys`.
+    pub async fn get_many(
+        &self,
+        keys: impl IntoIterator<Item = String>,
+    ) -> Vec<Result<Option<Versioned>>> {
+        self.get_many_with(keys, Consistency::default()).await
+    }
+
+    /// Like [`Client::get_many`], but overrides how many replicas each read consults.
+    pub async fn get_many_with(
+        &self,
+        keys: impl IntoIterator<Item = String>,
+        consistency: Consistency,
+    ) -> Vec<Result<Option<Versioned>>> {
+        self.batch(keys, |client, key| async move {
+            client.get_with(key, consistency).await
+        })
+        .await
+    }
+
+    /// Writes every pair at once. Results line up with `pairs`.
+    pub async fn put_many(
+        &self,
+        pairs: impl IntoIterator<Item = (String, String)>,
+    ) -> Vec<Result<()>> {
+        self.batch(pairs, |client, (key, value)| async move {
+            client.put(key, value).await
+        })
+        .await
+    }
+
+    /// Deletes every key at once. Results line up with `keys`.
+    pub async fn delete_many(&self, keys: impl IntoIterator<Item = String>) -> Vec<Result<()>> {
+        self.batch(keys, |client, key| async move { client.delete(key).await })
+            .await
+    }
+
+    async fn batch<I, T, F, Fut>(&self, items: I, op: F) -> Vec<Result<T>>
+    where
+        I: IntoIterator,
+        F: Fn(Client, I::Item) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut requests = JoinSet::new();
+        for (i, item) in items.into_iter().enumerate() {
+            let request = op(self.clone(), item);
+            requests.spawn(async move { (i, request.await) });
+        }
+
+        let mut results: Vec<_> = (0..requests.len()).map(|_| None).collect();
+        while let Some(joined) = requests.join_next().await {
+            let (i, result) = joined.expect("Request task panicked");
+            results[i] = Some(result);
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("Every request reports back"))
+            .collect()
+    }
+
+    /// Sends a request about `key` to the first of its owners that will answer. If our partition
+    /// map turns out to be out of date, refreshes it and tries once more.
+    async fn request(&self, key: &str, msg: Message) -> Result<Message> {
+        let mut map = self.partition_map().await?;
+        let mut refreshed = false;
+        loop {
+            match self.request_owners(&map, key, msg.clone()).await {
+                Ok(Message::StaleEpoch { .. })
+                | Err(Error::Remote {
+                    code: ErrorCode::NotOwner,
+                    ..
+                }) if !refreshed => {
+                    map = self.refresh_partition_map().await?;
+                    refreshed = true;
+                }
+                Ok(Message::StaleEpoch { current }) => {
+                    return Err(Error::Remote {
+                        code: ErrorCode::NotOwner,
+                        message: format!("still behind epoch {current} after refreshing"),
+                    })
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn request_owners(&self, map: &PartitionMap, key: &str, msg: Message) -> Result<Message> {
+        let mut owners = map.owners(key);
+        // Nodes the manager is worried about go last. Stable, so ring order holds otherwise.
+        owners.sort_by_key(|&owner| map.health(owner));
+        let mut last_err = None;
+        for owner in owners {
+            match self.request_at(owner, msg.clone(), Some(map.epoch)).await {
+                Err(e) if e.is_transient() => last_err = Some(e),
+                result => return result,
+            }
+        }
+        Err(last_err.unwrap_or(Error::Remote {
+            code: ErrorCode::NotOwner,
+            message: format!("nobody owns '{key}'"),
+        }))
+    }
+
+    async fn request_to(&self, addr: SocketAddr, msg: Message) -> Result<Message> {
+        self.request_at(addr, msg, None).await
+    }
+
+    /// Sends `msg` to `addr`, retrying with backoff if the failure looks temporary. `Error`
+    /// responses come back as `Err`.
+    async fn request_at(
+        &self,
+        addr: SocketAddr,
+        msg: Message,
+        epoch: Option<comm::Epoch>,
+    ) -> Result<Message> {
+        let Inner { config, pool, .. } = &*self.inner;
+        let mut backoff = config.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let call = pool.call_at(addr, msg.clone(), epoch);
+            let result = match timeout(config.request_timeout, call).await {
+                Ok(Ok(Message::Error { code, message })) => Err(Error::Remote { code, message }),
+                Ok(result) => result,
+                Err(_) => Err(Error::Timeout),
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < config.retries => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::{
+        handshake, mux,
+        placement::{Placement, DEFAULT_TOKENS},
+        ring_hash::RingHash,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn retries_busy_nodes_and_keeps_batches_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let calls = calls.clone();
+            async move {
+                loop {
+                    let (mut conn, _) = listener.accept().await.unwrap();
+                    handshake::accept(&mut conn, Role::Store, "store")
+                        .await
+                        .unwrap();
+                    let calls = calls.clone();
+                    // Plays manager and store at once: it owns every key itself.
+                    tokio::spawn(mux::serve(conn, Duration::from_secs(5), move |request| {
+                        let msg = request.msg;
+                        let call = match msg {
+                            Message::GetPartitionMap => usize::MAX,
+                            _ => calls.fetch_add(1, Ordering::SeqCst),
+                        };
+                        async move {
+                            match msg {
+                                Message::GetPartitionMap => {
+                                    let mut ring = RingHash::new(1);
+                                    ring.add_node(addr, DEFAULT_TOKENS);
+                                    Message::PartitionMap {
+                                        map: PartitionMap {
+                                            epoch: 1,
+                                            ring: ring.into(),
+                                            quorum: comm::Quorum::default(),
+                                            down: Vec::new(),
+                                            suspect: Vec::new(),
+                                            pending: None,
+                                        },
+                                    }
+                                }
+                                // First request gets turned away once.
+                                _ if call == 0 => Message::error(ErrorCode::Busy, "hold on"),
+                                Message::Get { key, .. } if key == "missing" => Message::NotFound,
+                                Message::Get { key, .. } => Message::Found {
+                                    values: vec![key],
+                                    context: VectorClock::default(),
+                                },
+                                _ => Message::error(ErrorCode::BadRequest, "nope"),
+                            }
+                        }
+                    }));
+                }
+            }
+        });
+
+        let client = Client::new(addr);
+        let got = client.get("first").await.unwrap().unwrap();
+        assert_eq!(got.value(), Some("first"));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let keys = ["a", "missing", "c"].map(String::from);
+        let got = client.get_many(keys).await;
+        let value = |i: usize| got[i].as_ref().unwrap().as_ref().and_then(Versioned::value);
+        assert_eq!(value(0), Some("a"));
+        assert_eq!(value(1), None);
+        assert_eq!(value(2), Some("c"));
+
+        // Non-transient errors aren't retried.
+        let got = client.put("k", "v").await;
+        assert!(matches!(
+            got,
+            Err(Error::Remote {
+                code: ErrorCode::BadRequest,
+                ..
+            })
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+}
@@ -1,24 +1,56 @@
 use clap::{Parser, Subcommand};
-use comm::{recv_msg, send_msg, Message, Result};
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use client::{Client, ClientConfig, Consistency, Result};
+use comm::{placement::Placement, PartitionMap};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct ClientArgs {
+    /// Host of manager node.
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    host: IpAddr,
     /// Port of manager node.
     #[arg(short, long, default_value_t = 50051)]
     mgr_port: u16,
+    /// Another manager to try if that one can't be reached. Can be given more than once.
+    #[arg(long = "manager")]
+    managers: Vec<u16>,
+    /// Largest message body (in bytes) this client will send or accept.
+    #[arg(long, default_value_t = comm::DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// How long (in milliseconds) to wait on a single attempt at a request.
+    #[arg(long, default_value_t = 2000)]
+    timeout: u64,
+    /// How many times to retry requests that fail for transient reasons.
+    #[arg(long, default_value_t = 3)]
+    retries: usize,
+    /// Storage node to ask for the partition map if the manager is down. Can be given more than
+    /// once.
+    #[arg(short, long = "seed")]
+    seeds: Vec<u16>,
+    /// Write to only this many of each key's replicas, instead of the cluster's setting.
+    #[arg(short = 'N', long)]
+    replicas: Option<usize>,
+    /// Replicas that have to answer a read, instead of the cluster's setting.
+    #[arg(short = 'R', long)]
+    read_quorum: Option<usize>,
+    /// Replicas that have to acknowledge a write, instead of the cluster's setting.
+    #[arg(short = 'W', long)]
+    write_quorum: Option<usize>,
     #[command(subcommand)]
     command: DBRequest,
 }
 
 #[derive(Subcommand)]
 enum DBRequest {
-    /// Get a key from the system
+    /// Get keys from the system
     Get {
-        /// Key to fetch.
-        key: String,
+        /// Keys to fetch. These are all sent down one connection at once.
+        #[arg(required = true)]
+        keys: Vec<String>,
     },
     /// Put a key-value pair into the system.
     Put {
@@ -27,44 +59,188 @@ enum DBRequest {
         /// Value to update key with.
         value: String,
     },
+    /// Delete a key from the system.
+    Delete {
+        /// Key to delete.
+        key: String,
+    },
+    /// List keys in order. Only works on range-partitioned clusters.
+    Scan {
+        /// First key to list. Defaults to the start of the keyspace.
+        #[arg(default_value = "")]
+        start: String,
+        /// Stop before this key. Defaults to the end of the keyspace.
+        end: Option<String>,
+        /// Most keys to list. If there are more, prints where to pick up.
+        #[arg(short, long, default_value_t = 100)]
+        limit: usize,
+        /// Start right after `start` instead of at it, to pick up where an earlier scan said.
+        #[arg(long)]
+        after: bool,
+    },
+    /// Show counters from every storage node.
+    Stats,
+    /// Show how the keyspace is split between storage nodes.
+    Ring,
+    /// Add a storage node to the ring, and wait for it to catch up on its keys.
+    Join {
+        /// Port of the storage node to add.
+        port: u16,
+        /// Virtual nodes to give it, instead of the manager's default. More means a bigger share
+        /// of the keys.
+        #[arg(short, long)]
+        tokens: Option<usize>,
+    },
+    /// Take a storage node out of the ring once the others have its keys, then shut it down.
+    Decommission {
+        /// Port of the storage node to remove.
+        port: u16,
+    },
+}
+
+/// Prints how the handoff is going every second until `done` says the ring change went through.
+async fn wait_for_rebalance(client: &Client, done: impl Fn(&PartitionMap) -> bool) -> Result<()> {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let map = client.refresh_partition_map().await?;
+        if done(&map) {
+            eprintln!("OK, epoch {}", map.epoch);
+            return Ok(());
+        }
+        let Some(pending) = &map.pending else {
+            continue;
+        };
+        // Only nodes in the new ring have anything to catch up on.
+        for node in pending.ring.nodes() {
+            match client.handoff(node).await {
+                Ok(handoff) => eprintln!("Handoff, {node}, {}/{}", handoff.done, handoff.total),
+                Err(e) => eprintln!("Error: {node}, {e}"),
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = ClientArgs::parse();
-    let addr = SocketAddr::from(([127, 0, 0, 1], args.mgr_port));
+    comm::set_max_frame_size(args.max_frame_size);
+    let addr = SocketAddr::new(args.host, args.mgr_port);
 
     // First, will a client send multiple requests in its lifetime?
     // For parity, let us assume that it will not.
     // TODO: Bother with manager.
-    // eprintln!("[INFO] Connecting to manager @ {}", addr);
-    // let manager_stream = TcpStream::connect(addr)?;
-    // eprintln!("[INFO] Connected!");
-    eprintln!("[INFO] Connecting to storage node");
-    let mut store_stream = TcpStream::connect(addr).await?;
-    eprintln!("[INFO] Connected!");
-    let peer = store_stream.peer_addr()?;
+    let client = Client::with_config(
+        addr,
+        ClientConfig {
+            request_timeout: Duration::from_millis(args.timeout),
+            retries: args.retries,
+            seeds: args
+                .seeds
+                .iter()
+                .map(|&port| SocketAddr::new(args.host, port))
+                .collect(),
+            managers: args
+                .managers
+                .iter()
+                .map(|&port| SocketAddr::new(args.host, port))
+                .collect(),
+            ..ClientConfig::default()
+        },
+    );
 
+    let consistency = Consistency {
+        n: args.replicas,
+        r: args.read_quorum,
+        w: args.write_quorum,
+    };
     match args.command {
-        DBRequest::Get { key } => {
-            // This clone is sort of cringe and unnecessary -- a product of how I chose to
-            // implement Message. TODO: Make a nice API that avoids this.
-            send_msg(&mut store_stream, Message::Get { key: key.clone() }).await?;
-            let response = recv_msg(&mut store_stream).await?;
-            match response {
-                Message::NotFound => eprintln!("Not Found: {peer}"),
-                Message::Found { value } => eprintln!("OK, {key}, {value}, {peer}"),
-                _ => unreachable!(),
+        DBRequest::Get { keys } => {
+            let results = client.get_many_with(keys.clone(), consistency).await;
+            // One bad key shouldn't hide the answers for the rest.
+            for (key, result) in keys.iter().zip(results) {
+                match result {
+                    Ok(None) => eprintln!("Not Found: {key}, {addr}"),
+                    Ok(Some(found)) => match found.value() {
+                        Some(value) => eprintln!("OK, {key}, {value}, {addr}"),
+                        None => eprintln!("Siblings: {key}, {:?}, {addr}", found.values),
+                    },
+                    Err(e) => eprintln!("Error: {key}, {e}, {addr}"),
+                }
             }
         }
         DBRequest::Put { key, value } => {
-            send_msg(&mut store_stream, Message::Put { key, value }).await?;
-            let response = recv_msg(&mut store_stream).await?;
-            match response {
-                Message::DonePut => eprintln!("OK, {peer}"),
-                _ => unreachable!(),
+            client.put_with(key, value, None, consistency).await?;
+            eprintln!("OK, {addr}");
+        }
+        DBRequest::Delete { key } => {
+            client.delete_with(key, None, consistency).await?;
+            eprintln!("OK, {addr}");
+        }
+        DBRequest::Scan {
+            start,
+            end,
+            limit,
+            after,
+        } => {
+            let start = match after {
+                true => client::after(&start),
+                false => start,
+            };
+            let page = client.scan_with(start, end, limit, consistency).await?;
+            for (key, values) in page.items {
+                match values.as_slice() {
+                    [value] => eprintln!("OK, {key}, {value}"),
+                    _ => eprintln!("Siblings: {key}, {values:?}"),
+                }
+            }
+            // Arguments can't hold the NUL that `after` tacks on, so say it with `--after`.
+            if let Some(next) = page.next {
+                match next.strip_suffix('\0') {
+                    Some(last) => eprintln!("More after, {last}"),
+                    None => eprintln!("More from, {next}"),
+                }
+            }
+        }
+        DBRequest::Stats => {
+            for node in &client.partition_map().await?.members() {
+                match client.stats(*node).await {
+                    Ok(counters) => {
+                        for (name, count) in counters {
+                            eprintln!("{node}, {name}, {count}");
+                        }
+                    }
+                    Err(e) => eprintln!("Error: {node}, {e}"),
+                }
             }
         }
+        DBRequest::Ring => {
+            let map = client.partition_map().await?;
+            for (node, share) in map.ring.shares() {
+                eprintln!(
+                    "{node}, {} tokens, {:.1}% primary, {:.1}% replica",
+                    map.ring.tokens(node),
+                    share.primary * 100.0,
+                    share.replica * 100.0,
+                );
+            }
+            if let Some(ranges) = map.ring.as_ranges() {
+                for range in ranges.ranges() {
+                    eprintln!("Range, {:?}, {:?}", range.start, range.owners);
+                }
+            }
+        }
+        DBRequest::Join { port, tokens } => {
+            let node = SocketAddr::new(args.host, port);
+            client.join(node, tokens).await?;
+            eprintln!("Accepted, {node}");
+            wait_for_rebalance(&client, |map| map.is_settled(node)).await?;
+        }
+        DBRequest::Decommission { port } => {
+            let node = SocketAddr::new(args.host, port);
+            client.decommission(node).await?;
+            eprintln!("Accepted, {node}");
+            wait_for_rebalance(&client, |map| !map.members().contains(&node)).await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,38 @@
+//! How long each placement strategy takes to find a key's replicas, for a few cluster sizes.
+//! `cargo bench -p comm`. See `examples/placement_sim.rs` for how well they spread keys.
+
+use std::{hint::black_box, net::SocketAddr, time::Instant};
+
+use comm::placement::{HashFn, Placement, Strategy, StrategyKind, DEFAULT_TOKENS};
+
+const LOOKUPS: usize = 100_000;
+const REPLICAS: usize = 3;
+
+fn main() {
+    let keys: Vec<_> = (0..LOOKUPS).map(|i| format!("key_{i}")).collect();
+    println!("strategy    hash  nodes  ns/lookup");
+    for kind in [
+        StrategyKind::Ring,
+        StrategyKind::Rendezvous,
+        StrategyKind::Jump,
+    ] {
+        for hash in [HashFn::Sha1, HashFn::Xxh3] {
+            for nodes in [3, 16, 64] {
+                let mut placement = Strategy::new(kind, REPLICAS, hash);
+                for port in 0..nodes {
+                    placement.add_node(SocketAddr::from(([10, 0, 0, 1], port)), DEFAULT_TOKENS);
+                }
+                // Warm up, then time.
+                for key in &keys[..1000] {
+                    black_box(placement.write_group(key));
+                }
+                let start = Instant::now();
+                for key in &keys {
+                    black_box(placement.write_group(black_box(key)));
+                }
+                let per_lookup = start.elapsed().as_nanos() as f64 / LOOKUPS as f64;
+                println!("{kind:<11} {hash:<5} {nodes:>5}  {per_lookup:>9.0}");
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Places a batch of keys with each strategy, then adds and removes nodes and counts what moves.
+//! `cargo run --release -p comm --example placement_sim [NODES] [KEYS]`.
+//!
+//! Balance is each node's share of primaries relative to a perfectly even split, so 1.00 is ideal
+//! and the spread (standard deviation over mean) should be as small as possible. Movement is the
+//! fraction of replicas that end up somewhere new; the best any strategy can do is the share of
+//! the node that came or went.
+
+use std::{env, net::SocketAddr};
+
+use comm::placement::{HashFn, Placement, Strategy, StrategyKind, DEFAULT_TOKENS};
+
+const REPLICAS: usize = 3;
+
+fn node(i: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, (i / 256) as u8, (i % 256) as u8], 50052))
+}
+
+fn place(placement: &Strategy, keys: &[String]) -> Vec<Vec<SocketAddr>> {
+    keys.iter()
+        .map(|key| placement.write_group(key).into_vec())
+        .collect()
+}
+
+/// Fraction of replicas in `after` that weren't in `before` for the same key.
+fn moved(before: &[Vec<SocketAddr>], after: &[Vec<SocketAddr>]) -> f64 {
+    let moved: usize = before
+        .iter()
+        .zip(after)
+        .map(|(old, new)| new.iter().filter(|node| !old.contains(node)).count())
+        .sum();
+    moved as f64 / (before.len() * REPLICAS) as f64
+}
+
+fn balance(placement: &Strategy, groups: &[Vec<SocketAddr>]) -> (f64, f64, f64) {
+    let nodes = placement.nodes();
+    let mean = groups.len() as f64 / nodes.len() as f64;
+    let loads: Vec<f64> = nodes
+        .iter()
+        .map(|node| groups.iter().filter(|group| group[0] == *node).count() as f64 / mean)
+        .collect();
+    let max = loads.iter().copied().fold(f64::MIN, f64::max);
+    let min = loads.iter().copied().fold(f64::MAX, f64::min);
+    let spread =
+        (loads.iter().map(|load| (load - 1.0).powi(2)).sum::<f64>() / loads.len() as f64).sqrt();
+    (max, min, spread)
+}
+
+fn main() {
+    let mut args = env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("expected a number"));
+    let nodes = args.next().unwrap_or(10);
+    let keys: Vec<_> = (0..args.next().unwrap_or(100_000))
+        .map(|i| format!("key_{i}"))
+        .collect();
+
+    println!(
+        "{nodes} nodes, {} keys, {REPLICAS} replicas each. Ideal movement: {:.3} adding, {:.3} removing",
+        keys.len(),
+        1.0 / (nodes + 1) as f64,
+        1.0 / nodes as f64,
+    );
+    println!("strategy     max   min   spread  add    remove first  remove last");
+    for kind in [
+        StrategyKind::Ring,
+        StrategyKind::Rendezvous,
+        StrategyKind::Jump,
+    ] {
+        let mut placement = Strategy::new(kind, REPLICAS, HashFn::Sha1);
+        for i in 0..nodes {
+            placement.add_node(node(i), DEFAULT_TOKENS);
+        }
+        let start = place(&placement, &keys);
+        let (max, min, spread) = balance(&placement, &start);
+
+        let mut grown = placement.clone();
+        grown.add_node(node(nodes), DEFAULT_TOKENS);
+        let add = moved(&start, &place(&grown, &keys));
+
+        // The oldest node is where jump hashing hurts: the newest one has to move into its slot.
+        let mut shrunk = placement.clone();
+        shrunk.remove_node(node(0));
+        let remove_first = moved(&start, &place(&shrunk, &keys));
+
+        let mut shrunk = placement;
+        shrunk.remove_node(node(nodes - 1));
+        let remove_last = moved(&start, &place(&shrunk, &keys));
+
+        println!(
+            "{kind:<11}  {max:.2}  {min:.2}  {spread:.3}   {add:.3}  {remove_first:.3}         {remove_last:.3}"
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! What storage nodes tell each other about who's still around, so they can keep going without
+//! the manager.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// The latest we've heard of one node. Every node bumps its own `heartbeat` each gossip round and
+/// everyone keeps the newest they've heard, so a node whose heartbeat stops moving has stopped
+/// gossiping.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Member {
+    pub addr: SocketAddr,
+    /// When the node started. A restarted node counts heartbeats from zero again, and this keeps
+    /// that from looking older than what everyone remembers from its last life.
+    pub generation: u64,
+    pub heartbeat: u64,
+}
+
+impl Member {
+    /// Which of two reports about the same node is newer.
+    pub fn version(&self) -> (u64, u64) {
+        (self.generation, self.heartbeat)
+    }
+}
@@ -0,0 +1,267 @@
+//! The `Hello` exchange every connection starts with, so mismatched binaries fail loudly instead
+//! of decoding each other's messages into garbage.
+
+use std::{ops::BitOr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::timeout,
+};
+
+use crate::{recv_msg, send_msg, Error, Message, Result};
+
+/// Protocol revision spoken by this build. Bump it whenever an existing message changes shape.
+/// Purely additive changes (new variants, new trailing `#[serde(default)]` fields) don't need a
+/// bump.
+///
+/// - v1: one bare `Message` per frame.
+/// - v2: everything after the handshake is wrapped in an `Envelope`.
+/// - v3: values carry version vectors, and `Found` can return sibling values.
+/// - v4: the ring places nodes by address with fixed-width, big-endian hashes, and the partition
+///   map no longer has a node list.
+/// - v5: the partition map says which placement strategy it uses. Rings are still encoded the
+///   way v4 encoded them, so v4 peers can follow clusters that use one.
+pub const PROTOCOL_VERSION: u32 = 5;
+/// Oldest revision this build can still talk to. Anything older hashes keys to different places,
+/// so there's no point decoding its maps.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// How long either end gets to say `Hello`, so a peer that connects and goes quiet can't tie up
+/// a task forever.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Store,
+    Manager,
+}
+
+/// Optional protocol features. This is a bitset rather than a list of enum variants so a newer
+/// peer can advertise flags we've never heard of without tripping our decoder.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+
+    /// Everything this build knows how to do.
+    pub const fn supported() -> Self {
+        Self::NONE
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// What we learned about the other end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// Revision both ends agreed to speak.
+    pub version: u32,
+    pub peer_role: Role,
+    pub peer_id: String,
+    /// Features both ends support.
+    pub capabilities: Capabilities,
+}
+
+fn hello(role: Role, node_id: &str) -> Message {
+    Message::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        role,
+        node_id: node_id.to_owned(),
+        capabilities: Capabilities::supported(),
+    }
+}
+
+/// Both ends run the same computation on the same pair of `Hello`s, so they always reach the
+/// same verdict without an extra round trip.
+fn negotiate(theirs: Message) -> Result<Session> {
+    let Message::Hello {
+        version,
+        min_version,
+        role,
+        node_id,
+        capabilities,
+    } = theirs
+    else {
+        return Err(Error::Protocol(format!("expected Hello, got '{theirs:?}'")));
+    };
+
+    let agreed = PROTOCOL_VERSION.min(version);
+    if agreed < MIN_PROTOCOL_VERSION.max(min_version) {
+        return Err(Error::IncompatibleVersion {
+            ours: PROTOCOL_VERSION,
+            theirs: version,
+        });
+    }
+
+    Ok(Session {
+        version: agreed,
+        peer_role: role,
+        peer_id: node_id,
+        capabilities: Capabilities::supported().intersection(capabilities),
+    })
+}
+
+/// Opens the conversation. Call this right after connecting, before sending any requests.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    role: Role,
+    node_id: &str,
+) -> Result<Session> {
+    send_msg(conn, hello(role, node_id)).await?;
+    negotiate(recv_msg(conn).await?)
+}
+
+/// Answers a peer's `Hello`. We always reply with our own, even if we're about to reject the
+/// peer, so that it can report the mismatch too.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    role: Role,
+    node_id: &str,
+) -> Result<Session> {
+    let theirs = recv_msg(conn).await?;
+    if !matches!(theirs, Message::Hello { .. }) {
+        return Err(Error::Protocol(format!("expected Hello, got '{theirs:?}'")));
+    }
+    send_msg(conn, hello(role, node_id)).await?;
+    negotiate(theirs)
+}
+
+/// Like [`accept`], but gives up with [`Error::Timeout`] if the peer hasn't said `Hello` within
+/// `limit`.
+pub async fn accept_within<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    role: Role,
+    node_id: &str,
+    limit: Duration,
+) -> Result<Session> {
+    timeout(limit, accept(conn, role, node_id))
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_hello(version: u32, min_version: u32, capabilities: Capabilities) -> Message {
+        Message::Hello {
+            version,
+            min_version,
+            role: Role::Store,
+            node_id: "store@127.0.0.1:50052".into(),
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn same_version_agrees() {
+        let session = negotiate(peer_hello(
+            PROTOCOL_VERSION,
+            MIN_PROTOCOL_VERSION,
+            Capabilities::NONE,
+        ))
+        .unwrap();
+        assert_eq!(session.version, PROTOCOL_VERSION);
+        assert_eq!(session.peer_role, Role::Store);
+        assert_eq!(session.peer_id, "store@127.0.0.1:50052");
+    }
+
+    #[test]
+    fn newer_peer_falls_back_to_our_version() {
+        let session = negotiate(peer_hello(
+            PROTOCOL_VERSION + 3,
+            MIN_PROTOCOL_VERSION,
+            Capabilities::NONE,
+        ))
+        .unwrap();
+        assert_eq!(session.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn previous_revision_is_still_spoken() {
+        let session = negotiate(peer_hello(
+            PROTOCOL_VERSION - 1,
+            PROTOCOL_VERSION - 1,
+            Capabilities::NONE,
+        ))
+        .unwrap();
+        assert_eq!(session.version, PROTOCOL_VERSION - 1);
+    }
+
+    #[test]
+    fn incompatible_versions_are_rejected() {
+        // Peer has moved on and dropped support for what we speak.
+        let got = negotiate(peer_hello(
+            PROTOCOL_VERSION + 2,
+            PROTOCOL_VERSION + 1,
+            Capabilities::NONE,
+        ));
+        assert!(matches!(got, Err(Error::IncompatibleVersion { .. })));
+    }
+
+    #[test]
+    fn unknown_capabilities_are_dropped() {
+        let from_the_future = Capabilities::from_bits(1 << 63);
+        let session = negotiate(peer_hello(
+            PROTOCOL_VERSION,
+            MIN_PROTOCOL_VERSION,
+            Capabilities::supported() | from_the_future,
+        ))
+        .unwrap();
+        assert_eq!(session.capabilities, Capabilities::supported());
+        assert!(!session.capabilities.contains(from_the_future));
+    }
+
+    #[tokio::test]
+    async fn silent_peers_time_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _quiet = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let got = accept_within(&mut conn, Role::Store, "store", Duration::from_millis(50)).await;
+        assert!(matches!(got, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn exchange_over_a_socket() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            accept(&mut conn, Role::Store, "store").await.unwrap()
+        });
+
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let session = initiate(&mut conn, Role::Client, "client").await.unwrap();
+        assert_eq!(session.peer_role, Role::Store);
+        let session = server.await.unwrap();
+        assert_eq!(session.peer_role, Role::Client);
+        assert_eq!(session.peer_id, "client");
+    }
+}
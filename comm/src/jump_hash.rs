@@ -0,0 +1,168 @@
+//! Jump consistent hashing (Lamping and Veach). Nodes are numbered buckets, and a key's bucket is
+//! computed in a handful of arithmetic steps with no table to search, so lookups are the cheapest
+//! of any strategy and the split is as even as it gets. The catch is that only the last bucket can
+//! come or go cleanly: removing any other node moves the last one into its slot, and that node's
+//! keys get scattered too. Weights aren't supported; every node gets the same share.
+//!
+//! Each replica gets its own jump, with the key hash salted by which attempt it is, so they move
+//! about as little as the first one does.
+
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::placement::{HashFn, Placement};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JumpHash {
+    /// Nodes by bucket. New nodes go on the end.
+    buckets: Vec<SocketAddr>,
+    /// What each node was added with. Kept so it can be reported, but it doesn't change anything.
+    tokens: BTreeMap<SocketAddr, usize>,
+    /// Number of distinct nodes each key is written to.
+    repl: usize,
+    hash: HashFn,
+}
+
+/// Spreads attempt numbers across all 64 bits of the key hash (it's 2^64 over the golden ratio).
+const SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+/// Jumps to try per replica before giving up and filling in the rest in order.
+const MAX_JUMPS_PER_REPLICA: u64 = 8;
+
+/// Which of `buckets` buckets `key` lands in. Straight from the paper, down to the constants.
+/// There has to be at least one bucket.
+pub fn jump(mut key: u64, buckets: usize) -> usize {
+    debug_assert!(buckets > 0);
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+impl JumpHash {
+    pub fn new(repl: usize, hash: HashFn) -> Self {
+        Self {
+            buckets: Vec::new(),
+            tokens: BTreeMap::new(),
+            repl,
+            hash,
+        }
+    }
+}
+
+/// Tokens are ignored.
+impl Placement for JumpHash {
+    fn add_node(&mut self, node: SocketAddr, tokens: usize) {
+        if self.tokens.insert(node, tokens).is_none() {
+            self.buckets.push(node);
+        }
+    }
+
+    fn remove_node(&mut self, node: SocketAddr) {
+        if self.tokens.remove(&node).is_some() {
+            let bucket = self.buckets.iter().position(|&n| n == node).unwrap();
+            self.buckets.swap_remove(bucket);
+        }
+    }
+
+    fn tokens(&self, node: SocketAddr) -> usize {
+        self.tokens.get(&node).copied().unwrap_or(0)
+    }
+
+    fn contains(&self, node: SocketAddr) -> bool {
+        self.tokens.contains_key(&node)
+    }
+
+    fn nodes(&self) -> Vec<SocketAddr> {
+        self.tokens.keys().copied().collect()
+    }
+
+    fn replicas(&self) -> usize {
+        self.repl
+    }
+
+    /// Jumps again until it has `len` distinct buckets. Asking for most of the nodes can take a
+    /// lot of jumps to hit the last few, so past a point it just fills in the rest in order.
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]> {
+        let n = self.buckets.len();
+        let len = len.min(n);
+        let hash = self.hash.hash(key.as_bytes());
+        let mut group: SmallVec<[SocketAddr; 16]> = SmallVec::with_capacity(len);
+        for attempt in 0..MAX_JUMPS_PER_REPLICA * len as u64 {
+            if group.len() == len {
+                return group;
+            }
+            let node = self.buckets[jump(hash ^ attempt.wrapping_mul(SALT), n)];
+            if !group.contains(&node) {
+                group.push(node);
+            }
+        }
+        for &node in &self.buckets {
+            if group.len() == len {
+                break;
+            }
+            if !group.contains(&node) {
+                group.push(node);
+            }
+        }
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn matches_the_paper() {
+        // Growing the bucket count only ever moves a key to the new bucket.
+        for key in [0, 1, 0xdead_beef, u64::MAX] {
+            let mut last = jump(key, 1);
+            assert_eq!(last, 0);
+            for buckets in 2..100 {
+                let next = jump(key, buckets);
+                assert!(next == last || next == buckets - 1);
+                last = next;
+            }
+        }
+        assert_eq!(jump(0xdead_beef, 1000), 285);
+        assert_eq!(jump(u64::MAX, 1000), 313);
+    }
+
+    #[test]
+    fn removing_a_node_moves_the_last_one_into_its_place() {
+        let mut jump = JumpHash::new(1, HashFn::Xxh3);
+        for port in 50052..50056 {
+            jump.add_node(addr(port), 1);
+        }
+        jump.remove_node(addr(50053));
+        assert_eq!(jump.buckets, [addr(50052), addr(50055), addr(50054)]);
+        assert!(!jump.contains(addr(50053)));
+        for i in 0..100 {
+            assert_ne!(jump.write_group(&format!("key_{i}"))[0], addr(50053));
+        }
+    }
+
+    #[test]
+    fn owners_are_pinned() {
+        let mut jump = JumpHash::new(2, HashFn::Sha1);
+        for port in 50052..50056 {
+            jump.add_node(addr(port), 8);
+        }
+        for (key, ports) in [
+            ("a", [50055, 50054]),
+            ("b", [50055, 50052]),
+            ("jajaja", [50054, 50055]),
+            ("zzzzzzzzzz", [50054, 50053]),
+        ] {
+            assert_eq!(jump.write_group(key)[..], ports.map(addr), "moved '{key}'");
+        }
+    }
+}
@@ -1,62 +1,473 @@
-use std::fmt::Display;
-
-use rmp_serde::{from_read, Serializer};
-use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+use rmp_serde::{from_slice, Serializer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod gossip;
+pub mod handshake;
+pub mod jump_hash;
+pub mod mux;
+pub mod partition;
+pub mod placement;
+pub mod pool;
+pub mod raft;
+pub mod range_map;
+pub mod rendezvous;
+pub mod ring_hash;
+pub mod version;
+
+pub use gossip::Member;
+pub use handshake::{Capabilities, Role, Session};
+pub use partition::{Consistency, Epoch, Health, PartitionMap, Quorum, Rebalance};
+pub use version::{Entry, VectorClock};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Message {
+    /// First message on every connection, in both directions. See [`handshake`].
+    Hello {
+        version: u32,
+        min_version: u32,
+        role: Role,
+        node_id: String,
+        capabilities: Capabilities,
+    },
+    /// The manager checking a storage node is still there. Answered with another `Heartbeat`.
     Heartbeat,
     Busy,
-    Get { key: String },
-    Put { key: String, value: String },
-    Found { value: String },
+    Get {
+        key: String,
+        #[serde(default)]
+        consistency: Consistency,
+    },
+    /// Writes `value`, superseding every version in `context`. Without a context, it supersedes
+    /// whatever the coordinator has.
+    Put {
+        key: String,
+        value: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        context: Option<VectorClock>,
+    },
+    Delete {
+        key: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        context: Option<VectorClock>,
+    },
+    /// Coordinator asking another replica for its copy of `key`. Answered with `Replica`.
+    ReplicaGet {
+        key: String,
+    },
+    /// Coordinator handing another replica a version of `key` to keep, unless it already has a
+    /// newer one. Answered with `DonePut`.
+    ReplicaPut {
+        key: String,
+        entry: Entry,
+        /// Set when the real owner is down: hold on to the write and hand it over to `hint` once
+        /// it's back.
+        #[serde(default)]
+        hint: Option<SocketAddr>,
+    },
+    /// Asks the manager which storage nodes are responsible for `key`.
+    GetOwners {
+        key: String,
+    },
+    /// Asks for the sender's copy of the partition map.
+    GetPartitionMap,
+    /// Asks a storage node for its counters.
+    GetStats,
+    /// Anti-entropy: asks for the hashes at `indices` on `level` of the Merkle tree over the keys
+    /// the receiver shares with `peer`. Answered with `Merkle`.
+    GetMerkle {
+        peer: SocketAddr,
+        epoch: Epoch,
+        level: u32,
+        indices: Vec<u32>,
+    },
+    /// Anti-entropy: asks for every key in `buckets` the receiver shares with `peer`. Answered
+    /// with `Entries`.
+    GetBuckets {
+        peer: SocketAddr,
+        epoch: Epoch,
+        buckets: Vec<u32>,
+    },
+    /// Push-pull gossip between storage nodes: the sender's partition map and the newest it's
+    /// heard from everyone. Answered with the receiver's own.
+    Gossip {
+        map: PartitionMap,
+        members: Vec<Member>,
+    },
+    /// Raft: a candidate manager asking for another's vote. Answered with `Vote`.
+    RequestVote {
+        term: raft::Term,
+        candidate: SocketAddr,
+        last_log_index: raft::LogIndex,
+        last_log_term: raft::Term,
+    },
+    /// Raft: the leading manager replicating its log, or just letting the others know it's still
+    /// around if `entries` is empty. Answered with `Appended`.
+    AppendEntries {
+        term: raft::Term,
+        leader: SocketAddr,
+        prev_log_index: raft::LogIndex,
+        prev_log_term: raft::Term,
+        entries: Vec<raft::LogEntry>,
+        leader_commit: raft::LogIndex,
+    },
+    /// Raft: the leading manager catching up a follower on entries it has already compacted
+    /// away. Answered with `Appended`.
+    InstallSnapshot {
+        term: raft::Term,
+        leader: SocketAddr,
+        snapshot: raft::Snapshot,
+    },
+    /// Asks the manager to add a storage node to the ring. Answered with `Accepted`; the node
+    /// starts taking reads once it has caught up on its keys.
+    Join {
+        node: SocketAddr,
+        /// Virtual nodes to give it, if not the manager's default.
+        #[serde(default)]
+        tokens: Option<usize>,
+    },
+    /// Asks the manager to take a storage node out of the ring. Answered with `Accepted`; once
+    /// the rest of the ring has its keys, the node is told to shut down.
+    Decommission {
+        node: SocketAddr,
+    },
+    /// Manager telling a storage node it's no longer needed. Answered with `Accepted`, then the
+    /// node saves its state and exits.
+    Shutdown,
+    /// Handoff: asks for every key in `buckets` that `peer` is gaining in the rebalance that
+    /// started at epoch `since`. Answered with `Entries`.
+    GetRange {
+        peer: SocketAddr,
+        since: Epoch,
+        buckets: Vec<u32>,
+    },
+    /// Asks a storage node how far along it is pulling in keys for a rebalance. Answered with
+    /// `Handoff`.
+    GetHandoff,
+    /// Live keys from `start` up to (not including) `end`, in order, at most `limit` of them.
+    /// Only works on range-partitioned clusters, and only covers the range `start` is in; the
+    /// answer says where to pick up. Answered with `Scanned`.
+    Scan {
+        start: String,
+        end: Option<String>,
+        limit: usize,
+        #[serde(default)]
+        consistency: Consistency,
+    },
+    /// Coordinator asking another replica for the first `limit` keys from `start` up to `end`,
+    /// tombstones included. Answered with `Entries`.
+    ReplicaScan {
+        start: String,
+        end: Option<String>,
+        limit: usize,
+    },
+    /// Manager asking a storage node how big the ranges it's first in line for have gotten.
+    /// Answered with `RangeSizes`.
+    GetRangeSizes,
+    /// Manager pushing a new partition map to a storage node.
+    UpdatePartitionMap {
+        map: PartitionMap,
+    },
+    /// Every live version of the key (more than one if writes raced), plus the context to write
+    /// back with to resolve them.
+    Found {
+        values: Vec<String>,
+        context: VectorClock,
+    },
     NotFound,
+    /// A replica's copy of a key, tombstones included.
+    Replica {
+        entry: Option<Entry>,
+    },
     DonePut,
+    DoneDelete,
+    /// The storage nodes responsible for a key, in the order they should be tried.
+    Owners {
+        nodes: Vec<SocketAddr>,
+    },
+    PartitionMap {
+        map: PartitionMap,
+    },
+    Merkle {
+        hashes: Vec<u64>,
+    },
+    /// Keys from a `Scan` with every live version of each. `next` is where the next page starts,
+    /// or `None` if the scan is done.
+    Scanned {
+        items: Vec<(String, Vec<String>)>,
+        next: Option<String>,
+    },
+    RangeSizes {
+        sizes: Vec<range_map::RangeSize>,
+    },
+    Entries {
+        entries: Vec<(String, Entry)>,
+    },
+    Vote {
+        term: raft::Term,
+        granted: bool,
+    },
+    /// `match_index` is how far the follower's log now agrees with the leader's.
+    Appended {
+        term: raft::Term,
+        success: bool,
+        match_index: raft::LogIndex,
+    },
+    /// The request was taken and will be carried out in the background. For changes to the
+    /// cluster, that means they show up in the partition map once the managers agree to them.
+    Accepted,
+    /// A storage node has pulled in `done` of the `total` buckets it needs for the rebalance that
+    /// started at `since`.
+    Handoff {
+        since: Epoch,
+        done: u32,
+        total: u32,
+    },
+    /// Sent by a manager that isn't leading to anything only the leader handles. `leader` is who
+    /// to ask instead, if there is one right now.
+    NotLeader {
+        leader: Option<SocketAddr>,
+    },
+    /// Counter name to value. Which counters there are depends on the node.
+    Stats {
+        counters: BTreeMap<String, u64>,
+    },
+    /// The request was tagged with an older epoch than the node's partition map. Whoever sent it
+    /// should fetch a fresh map and try again.
+    StaleEpoch {
+        current: Epoch,
+    },
+    /// Something went wrong handling the request. Sent instead of whatever the request would
+    /// normally get back.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Message {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request made no sense to whoever got it.
+    BadRequest,
+    /// Too much going on, try again later.
+    Busy,
+    /// This node isn't responsible for that key.
+    NotOwner,
+    /// The request didn't fit in a frame.
+    TooLarge,
+    /// The node fell over trying to handle the request.
+    Internal,
+    /// Not enough replicas answered to make the quorum.
+    Unavailable,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::BadRequest => "bad request",
+            Self::Busy => "busy",
+            Self::NotOwner => "not owner",
+            Self::TooLarge => "too large",
+            Self::Internal => "internal error",
+            Self::Unavailable => "unavailable",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Turns a response we weren't expecting into an error, keeping the details if the peer sent an
+/// `Error` on purpose.
+pub fn unexpected(response: Message) -> Error {
+    match response {
+        Message::Error { code, message } => Error::Remote { code, message },
+        other => Error::Protocol(format!("unexpected response '{other:?}'")),
+    }
+}
+
+/// Tags a request so its response can be matched back up, see [`mux`].
+pub type RequestId = u64;
+
+/// Every message after the handshake travels in one of these.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: RequestId,
+    pub msg: Message,
+    /// Epoch of the partition map the sender routed this request with, if it used one.
+    #[serde(default)]
+    pub epoch: Option<Epoch>,
 }
-const MSG_SIZE: usize = std::mem::size_of::<Message>();
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub async fn send_msg(conn: &mut TcpStream, msg: Message) -> Result<()> {
-    // No variable-length messages for the sake of simplicity.
-    // This is a ~5x pessmization in message size (10 vs 48) although it's a mystery why just the
-    // tags are 10 bytes long and not 1 byte long. It might be alignment, but 10 bytes is a weird
-    // number to choose if that's the case.
-    // I might have fun comparing gRPC vs this too. There are gRPC implementations for Rust!
-    // I might also be underselling the perf of MessagePack here.
-    // TODO: Investigate variable-length encoding. 48 bytes just to say "OK" is stupid.
-    let mut ser_buf = [0u8; MSG_SIZE];
-    msg.serialize(&mut Serializer::new(&mut ser_buf[..]))?;
+/// Every frame on the wire is a big-endian `u32` body length followed by a MessagePack body.
+const LEN_PREFIX_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Frame bodies larger than this are refused unless the limit is raised with
+/// [`set_max_frame_size`]. Big enough for a decent JSON document, small enough that a garbage
+/// length prefix can't make us allocate the world.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+
+/// Sets the frame size limit used by [`send_msg`] and [`recv_msg`]. Clamped to what the length
+/// prefix can express.
+pub fn set_max_frame_size(size: usize) {
+    let size = size.min(u32::MAX as usize);
+    MAX_FRAME_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+pub async fn send_msg<W: AsyncWrite + Unpin>(conn: &mut W, msg: Message) -> Result<()> {
     eprintln!("[INFO] Sending '{msg:?}' over the wire.");
-    conn.write_all(&ser_buf).await?;
+    send_frame(conn, &msg, max_frame_size()).await?;
     eprintln!("[INFO] Sent!");
 
     Ok(())
 }
 
-pub async fn recv_msg(conn: &mut TcpStream) -> Result<Message> {
-    let mut ser_buf = [0u8; MSG_SIZE];
+pub async fn recv_msg<R: AsyncRead + Unpin>(conn: &mut R) -> Result<Message> {
     eprintln!("[INFO] Trying to read a message.");
-    conn.read_exact(&mut ser_buf[..]).await?;
-    // Regarding VLE, I tried just passing the conn into this fn but nooo,
-    // this TcpStream implements AsyncRead, but serde only understands io::Read.
-    // Maybe there's an async version of this? Colored functions smh.
-    let message = from_read(&ser_buf[..])?;
+    let message = recv_frame(conn, max_frame_size()).await?;
     eprintln!("[INFO] Got '{message:?}'");
 
     Ok(message)
 }
 
+pub async fn send_envelope<W: AsyncWrite + Unpin>(conn: &mut W, envelope: Envelope) -> Result<()> {
+    eprintln!("[INFO] Sending '{envelope:?}' over the wire.");
+    send_frame(conn, &envelope, max_frame_size()).await?;
+    eprintln!("[INFO] Sent!");
+
+    Ok(())
+}
+
+pub async fn recv_envelope<R: AsyncRead + Unpin>(conn: &mut R) -> Result<Envelope> {
+    let envelope = recv_frame(conn, max_frame_size()).await?;
+    eprintln!("[INFO] Got '{envelope:?}'");
+
+    Ok(envelope)
+}
+
+/// Serializes `body` into a length-prefixed frame.
+pub fn encode_frame<T: Serialize>(body: &T, max_frame_size: usize) -> Result<Vec<u8>> {
+    let mut frame = vec![0u8; LEN_PREFIX_SIZE];
+    body.serialize(&mut Serializer::new(&mut frame))?;
+    let size = frame.len() - LEN_PREFIX_SIZE;
+    if size > max_frame_size.min(u32::MAX as usize) {
+        return Err(Error::FrameTooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+    frame[..LEN_PREFIX_SIZE].copy_from_slice(&(size as u32).to_be_bytes());
+
+    Ok(frame)
+}
+
+pub async fn send_frame<W: AsyncWrite + Unpin, T: Serialize>(
+    conn: &mut W,
+    body: &T,
+    max_frame_size: usize,
+) -> Result<()> {
+    // Build the whole frame up front so the prefix and body go out in one write.
+    let frame = encode_frame(body, max_frame_size)?;
+    conn.write_all(&frame).await?;
+
+    Ok(())
+}
+
+/// On [`Error::FrameTooLarge`] the body is left unread, so the stream is out of sync until it's
+/// skipped or the connection is dropped.
+pub async fn recv_frame<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    conn: &mut R,
+    max_frame_size: usize,
+) -> Result<T> {
+    let mut len_buf = [0u8; LEN_PREFIX_SIZE];
+    conn.read_exact(&mut len_buf).await?;
+    let size = u32::from_be_bytes(len_buf) as usize;
+    // Check before allocating -- the prefix is the only thing we know about the peer so far.
+    if size > max_frame_size {
+        return Err(Error::FrameTooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+
+    let mut body = vec![0u8; size];
+    conn.read_exact(&mut body).await?;
+    // Decoding from a slice rather than the stream itself: serde only understands io::Read, and
+    // having the whole body in hand means a bad body can't leave the stream half-consumed.
+    let body = from_slice(&body)?;
+
+    Ok(body)
+}
+
 // There has to be a better way to handle errors than this...
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
     RMPEncode(rmp_serde::encode::Error),
     RMPDecode(rmp_serde::decode::Error),
+    /// A frame body was bigger than the configured maximum frame size.
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The peer speaks a protocol revision we can't.
+    IncompatibleVersion {
+        ours: u32,
+        theirs: u32,
+    },
+    /// The peer sent something that makes no sense at this point in the conversation.
+    Protocol(String),
+    /// The connection went away before we got our response.
+    ConnectionClosed,
+    /// The peer answered with an `Error` message.
+    Remote {
+        code: ErrorCode,
+        message: String,
+    },
+    /// We gave up waiting on the peer.
+    Timeout,
+}
+
+impl Error {
+    /// Whether trying the same thing again later might work.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(_)
+                | Self::ConnectionClosed
+                | Self::Timeout
+                | Self::Remote {
+                    code: ErrorCode::Busy | ErrorCode::Unavailable,
+                    ..
+                }
+        )
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -83,6 +494,17 @@ impl Display for Error {
             Self::Io(e) => e.fmt(f),
             Self::RMPEncode(e) => e.fmt(f),
             Self::RMPDecode(e) => e.fmt(f),
+            Self::FrameTooLarge { size, max } => {
+                write!(f, "frame of {size} bytes exceeds the {max} byte limit")
+            }
+            Self::IncompatibleVersion { ours, theirs } => write!(
+                f,
+                "peer speaks protocol v{theirs}, which is incompatible with our v{ours}"
+            ),
+            Self::Protocol(what) => write!(f, "protocol violation: {what}"),
+            Self::ConnectionClosed => write!(f, "connection closed"),
+            Self::Remote { code, message } => write!(f, "{code}: {message}"),
+            Self::Timeout => write!(f, "timed out"),
         }
     }
 }
@@ -92,7 +514,7 @@ mod tests {
     use super::*;
     use std::{
         fs::OpenOptions,
-        io::{Read, Seek, Write},
+        io::{Read, Seek, SeekFrom, Write},
     };
 
     // Checks if the idea is sound (it is)
@@ -102,60 +524,295 @@ mod tests {
         let msg = Message::Put {
             key: "jajaja".into(),
             value: "xdroflmaowwwwwwmdrmdrxaxaxaxa".into(),
+            consistency: Consistency::default(),
+            context: None,
         };
         let mut ser_buf = Vec::new();
         msg.serialize(&mut Serializer::new(&mut ser_buf))
             .expect("Couldn't serialize...");
-        let msg_prime = from_read(&ser_buf[..]).expect("Couldn't deserialize...");
+        let msg_prime = from_slice(&ser_buf[..]).expect("Couldn't deserialize...");
         assert_eq!(msg, msg_prime);
     }
 
     // Test if we can deserialize messages like this.
-    #[test]
-    fn dump_to_file_and_retrieve() {
+    #[tokio::test]
+    async fn dump_to_file_and_retrieve() {
+        let path = std::env::temp_dir().join(format!("the_channel-{}", std::process::id()));
         let mut fake_channel = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(".the_channel")
+            .truncate(true)
+            .open(&path)
             .expect("Couldn't make the file.");
 
         let msgs = [
+            Message::Hello {
+                version: handshake::PROTOCOL_VERSION,
+                min_version: handshake::MIN_PROTOCOL_VERSION,
+                role: Role::Client,
+                node_id: "itsame".into(),
+                capabilities: Capabilities::supported(),
+            },
             Message::Heartbeat,
             Message::Busy,
             Message::Get {
                 key: "considerthefollowing".into(),
+                consistency: Consistency::default(),
             },
             Message::Put {
                 key: "professionalism".into(),
                 value: "mayreflectwell".into(),
+                consistency: Consistency {
+                    w: Some(1),
+                    ..Consistency::default()
+                },
+                context: Some(VectorClock::default()),
             },
             Message::Found {
-                value: "nahiwannabegoofy".into(),
+                values: vec!["nahiwannabegoofy".into(), "orisit".into()],
+                context: VectorClock::default(),
+            },
+            Message::Delete {
+                key: "mistakeswe".into(),
+                consistency: Consistency::default(),
+                context: None,
             },
             Message::NotFound,
             Message::DonePut,
+            Message::DoneDelete,
+            Message::GetOwners {
+                key: "whosinchargehere".into(),
+            },
+            Message::Owners {
+                nodes: vec![SocketAddr::from(([127, 0, 0, 1], 50052))],
+            },
+            Message::GetPartitionMap,
+            Message::StaleEpoch { current: 42 },
+            Message::ReplicaGet {
+                key: "secondopinion".into(),
+            },
+            Message::ReplicaPut {
+                key: "copythat".into(),
+                entry: Entry {
+                    siblings: vec![version::Sibling {
+                        dot: version::Dot {
+                            node: "store@127.0.0.1:50052".into(),
+                            counter: 3,
+                        },
+                        context: VectorClock::default(),
+                        value: None,
+                        timestamp: 1234,
+                    }],
+                },
+                hint: Some(SocketAddr::from(([127, 0, 0, 1], 50053))),
+            },
+            Message::Replica { entry: None },
+            Message::GetMerkle {
+                peer: SocketAddr::from(([127, 0, 0, 1], 50053)),
+                epoch: 2,
+                level: 3,
+                indices: vec![4, 5],
+            },
+            Message::Merkle {
+                hashes: vec![u64::MAX, 0],
+            },
+            Message::GetBuckets {
+                peer: SocketAddr::from(([127, 0, 0, 1], 50053)),
+                epoch: 2,
+                buckets: vec![255],
+            },
+            Message::Entries {
+                entries: vec![("treeshake".into(), Entry::default())],
+            },
+            Message::Gossip {
+                map: PartitionMap::default(),
+                members: vec![Member {
+                    addr: SocketAddr::from(([127, 0, 0, 1], 50052)),
+                    generation: 1,
+                    heartbeat: 99,
+                }],
+            },
+            Message::GetStats,
+            Message::Stats {
+                counters: BTreeMap::from([("read_repairs".into(), 3)]),
+            },
+            Message::RequestVote {
+                term: 3,
+                candidate: SocketAddr::from(([127, 0, 0, 1], 50051)),
+                last_log_index: 10,
+                last_log_term: 2,
+            },
+            Message::Vote {
+                term: 3,
+                granted: true,
+            },
+            Message::AppendEntries {
+                term: 3,
+                leader: SocketAddr::from(([127, 0, 0, 1], 50051)),
+                prev_log_index: 10,
+                prev_log_term: 2,
+                entries: vec![raft::LogEntry {
+                    term: 3,
+                    command: raft::Command::SetHealth {
+                        node: SocketAddr::from(([127, 0, 0, 1], 50052)),
+                        health: Health::Dead,
+                    },
+                }],
+                leader_commit: 10,
+            },
+            Message::InstallSnapshot {
+                term: 3,
+                leader: SocketAddr::from(([127, 0, 0, 1], 50051)),
+                snapshot: raft::Snapshot {
+                    last_index: 1000,
+                    last_term: 2,
+                    map: PartitionMap::default(),
+                },
+            },
+            Message::Appended {
+                term: 3,
+                success: false,
+                match_index: 0,
+            },
+            Message::NotLeader {
+                leader: Some(SocketAddr::from(([127, 0, 0, 1], 50051))),
+            },
+            Message::Join {
+                node: SocketAddr::from(([127, 0, 0, 1], 50055)),
+                tokens: Some(128),
+            },
+            Message::Accepted,
+            Message::Decommission {
+                node: SocketAddr::from(([127, 0, 0, 1], 50052)),
+            },
+            Message::Shutdown,
+            Message::GetRange {
+                peer: SocketAddr::from(([127, 0, 0, 1], 50055)),
+                since: 4,
+                buckets: vec![0, 1, 2],
+            },
+            Message::GetHandoff,
+            Message::Scan {
+                start: "a".into(),
+                end: Some("m".into()),
+                limit: 10,
+                consistency: Consistency::default(),
+            },
+            Message::ReplicaScan {
+                start: "a".into(),
+                end: None,
+                limit: 10,
+            },
+            Message::Scanned {
+                items: vec![("apple".into(), vec!["red".into()])],
+                next: Some("banana".into()),
+            },
+            Message::GetRangeSizes,
+            Message::RangeSizes {
+                sizes: vec![range_map::RangeSize {
+                    start: "".into(),
+                    keys: 12,
+                    middle: Some("lemon".into()),
+                }],
+            },
+            Message::Handoff {
+                since: 4,
+                done: 3,
+                total: 256,
+            },
+            Message::error(ErrorCode::NotOwner, "wrongnumber"),
         ];
 
-        // Even though this encoding claims to have zero-copy deserialization,
-        // will deserializing a type with `String`s allocate two heap buffers?
-        let mut write_scratch = [0u8; MSG_SIZE];
-        let mut read_scratch = [0u8; MSG_SIZE];
-        for msg in msgs {
-            msg.serialize(&mut Serializer::new(&mut write_scratch[..]))
-                .expect("Failed to serialize.");
+        for msg in &msgs {
+            let frame = encode_frame(msg, DEFAULT_MAX_FRAME_SIZE).expect("Failed to serialize.");
             fake_channel
-                .write_all(&write_scratch)
+                .write_all(&frame)
                 .expect("Failed to write to file.");
-            fake_channel
-                .seek_relative(-(MSG_SIZE as i64))
-                .expect("Didn't seek ahead as far as I expected");
-            let bytes_read = fake_channel
-                .read(&mut read_scratch)
-                .expect("Failed to read file.");
-            assert_eq!(bytes_read, MSG_SIZE);
-            let recvd_msg = from_read(&read_scratch[..]).expect("Failed to parse from file.");
+        }
+
+        // Frames are variable-length now, so read the whole thing back and pull them off one at
+        // a time.
+        let mut contents = Vec::new();
+        fake_channel
+            .seek(SeekFrom::Start(0))
+            .expect("Couldn't rewind the file.");
+        fake_channel
+            .read_to_end(&mut contents)
+            .expect("Failed to read file.");
+        let mut channel = &contents[..];
+        for msg in msgs {
+            let recvd_msg: Message = recv_frame(&mut channel, DEFAULT_MAX_FRAME_SIZE)
+                .await
+                .expect("Failed to parse from file.");
             assert_eq!(msg, recvd_msg);
         }
+        assert!(channel.is_empty(), "Trailing bytes in the channel");
+        std::fs::remove_file(&path).expect("Couldn't clean up the file.");
+    }
+
+    #[test]
+    fn small_messages_stay_small() {
+        let frame = encode_frame(&Message::DonePut, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        // Prefix + a fixstr tag. Nowhere near 48 bytes.
+        assert_eq!(frame.len(), LEN_PREFIX_SIZE + 1 + "DonePut".len());
+    }
+
+    #[tokio::test]
+    async fn large_key_value_round_trip() {
+        let key = "user/".repeat(2048);
+        let value = format!(
+            "{{\"items\": [{}]}}",
+            (0..20_000)
+                .map(|i| format!("{{\"id\": {i}, \"name\": \"item-{i}\"}}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        assert!(value.len() > 512 * 1024);
+
+        let msg = Message::Put {
+            key: key.clone(),
+            value: value.clone(),
+            consistency: Consistency::default(),
+            context: None,
+        };
+        let mut wire = Vec::new();
+        send_frame(&mut wire, &msg, DEFAULT_MAX_FRAME_SIZE)
+            .await
+            .expect("Failed to send.");
+        let found = Message::Found {
+            values: vec![value],
+            context: VectorClock::default(),
+        };
+        send_frame(&mut wire, &found, DEFAULT_MAX_FRAME_SIZE)
+            .await
+            .expect("Failed to send.");
+
+        let mut wire = &wire[..];
+        let got: Message = recv_frame(&mut wire, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+        assert_eq!(got, msg);
+        let got: Message = recv_frame(&mut wire, DEFAULT_MAX_FRAME_SIZE).await.unwrap();
+        assert_eq!(got, found);
+        assert!(wire.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversize_frames_are_refused() {
+        let msg = Message::Put {
+            key: "k".into(),
+            value: "v".repeat(1024),
+            consistency: Consistency::default(),
+            context: None,
+        };
+        assert!(matches!(
+            encode_frame(&msg, 512),
+            Err(Error::FrameTooLarge { max: 512, .. })
+        ));
+
+        // A peer with a bigger limit sends it anyway; we should bail on the prefix alone.
+        let frame = encode_frame(&msg, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        let mut wire = &frame[..LEN_PREFIX_SIZE];
+        let got = recv_frame::<_, Message>(&mut wire, 512).await;
+        assert!(matches!(got, Err(Error::FrameTooLarge { max: 512, .. })));
     }
 }
@@ -0,0 +1,615 @@
+//! Many requests in flight over one `TcpStream`. Every message after the handshake travels in an
+//! [`Envelope`] tagged with a request ID, and responses come back in whatever order the other end
+//! finishes them.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, oneshot, Mutex as AsyncMutex, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    handshake, recv_envelope, send_envelope, Envelope, Epoch, Error, ErrorCode, Message, RequestId,
+    Result, Role, Session,
+};
+
+/// Most requests a single connection may have in flight before the server starts answering
+/// `Busy`.
+pub const MAX_IN_FLIGHT: usize = 256;
+
+#[derive(Default)]
+struct Pending {
+    /// Set by the reader task once the connection is gone. Lives under the same lock as the map
+    /// so nobody can register a waiter after the reader has given up.
+    closed: bool,
+    waiters: HashMap<RequestId, oneshot::Sender<Message>>,
+}
+
+type SharedPending = Arc<Mutex<Pending>>;
+
+/// Forgets about a request if the caller stops waiting on it (e.g. it timed out).
+struct WaiterGuard<'a> {
+    pending: &'a SharedPending,
+    id: RequestId,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .expect("Lock poisoned :(")
+            .waiters
+            .remove(&self.id);
+    }
+}
+
+/// Client end of a multiplexed connection. Cheap to share behind an `Arc`; every `call` gets its
+/// own request ID, and a background task routes responses back to whoever asked.
+pub struct Connection {
+    peer: SocketAddr,
+    session: Session,
+    next_id: AtomicU64,
+    writer: AsyncMutex<OwnedWriteHalf>,
+    pending: SharedPending,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    pub async fn connect(addr: SocketAddr, role: Role, node_id: &str) -> Result<Self> {
+        let mut conn = TcpStream::connect(addr).await?;
+        let session = timeout(
+            handshake::HANDSHAKE_TIMEOUT,
+            handshake::initiate(&mut conn, role, node_id),
+        )
+        .await
+        .map_err(|_| Error::Timeout)??;
+        let (mut read_half, write_half) = conn.into_split();
+
+        let pending = SharedPending::default();
+        let reader = tokio::spawn({
+            let pending = pending.clone();
+            async move {
+                loop {
+                    let Envelope { id, msg, .. } = match recv_envelope(&mut read_half).await {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            eprintln!("[INFO] Connection to {addr} closed: {e}");
+                            break;
+                        }
+                    };
+                    let waiter = pending
+                        .lock()
+                        .expect("Lock poisoned :(")
+                        .waiters
+                        .remove(&id);
+                    match waiter {
+                        // The caller may have stopped caring in the meantime. That's fine.
+                        Some(tx) => {
+                            let _ = tx.send(msg);
+                        }
+                        None => eprintln!("[WARN] {addr} answered unknown request {id}"),
+                    }
+                }
+
+                // Dropping the senders wakes everyone still waiting with an error.
+                let mut pending = pending.lock().expect("Lock poisoned :(");
+                pending.closed = true;
+                pending.waiters.clear();
+            }
+        });
+
+        Ok(Self {
+            peer: addr,
+            session,
+            next_id: AtomicU64::new(0),
+            writer: AsyncMutex::new(write_half),
+            pending,
+            reader,
+        })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().expect("Lock poisoned :(").closed
+    }
+
+    /// Sends `msg` and waits for the matching response. Other calls can be in flight at the same
+    /// time.
+    pub async fn call(&self, msg: Message) -> Result<Message> {
+        self.call_at(msg, None).await
+    }
+
+    /// Like [`Connection::call`], but tags the request with the epoch of the partition map it was
+    /// routed with.
+    pub async fn call_at(&self, msg: Message, epoch: Option<Epoch>) -> Result<Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().expect("Lock poisoned :(");
+            if pending.closed {
+                return Err(Error::ConnectionClosed);
+            }
+            pending.waiters.insert(id, tx);
+        }
+        let _guard = WaiterGuard {
+            pending: &self.pending,
+            id,
+        };
+
+        let envelope = Envelope { id, msg, epoch };
+        send_envelope(&mut *self.writer.lock().await, envelope).await?;
+        rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// What a handler answers with. Usually just a [`Message`], but a handler that has to do
+/// something only once the peer has its answer can ask to hear when it's been written out.
+pub struct Reply {
+    msg: Message,
+    sent: Option<oneshot::Sender<()>>,
+}
+
+impl Reply {
+    /// Answers with `msg`, then fires `sent` once it's on the wire. `sent` is dropped instead if
+    /// the connection breaks first.
+    pub fn then_notify(msg: Message, sent: oneshot::Sender<()>) -> Self {
+        Self {
+            msg,
+            sent: Some(sent),
+        }
+    }
+}
+
+impl From<Message> for Reply {
+    fn from(msg: Message) -> Self {
+        Self { msg, sent: None }
+    }
+}
+
+/// Serves requests on a connection that has already been through the handshake, until the peer
+/// hangs up or goes quiet for `idle_timeout`. Each request runs in its own task so a slow one
+/// doesn't hold up the rest. Handlers get the whole envelope so they can check its epoch.
+pub async fn serve<H, Fut, R>(conn: TcpStream, idle_timeout: Duration, handler: H) -> Result<()>
+where
+    H: Fn(Envelope) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: Into<Reply> + Send + 'static,
+{
+    let peer = conn.peer_addr()?;
+    let (mut read_half, mut write_half) = conn.into_split();
+
+    // Responses finish in any order, so funnel them through one writer. If it can't write any
+    // more, it says why and the reader hangs up too.
+    let (resp_tx, mut resp_rx) =
+        mpsc::unbounded_channel::<(Envelope, Option<oneshot::Sender<()>>)>();
+    let (broken_tx, mut broken) = oneshot::channel();
+    let writer = tokio::spawn(async move {
+        while let Some((envelope, sent)) = resp_rx.recv().await {
+            if let Err(e) = respond(&mut write_half, peer, envelope).await {
+                eprintln!("[ERROR] Failed to respond to {peer}: {e}");
+                let _ = broken_tx.send(e);
+                break;
+            }
+            if let Some(sent) = sent {
+                let _ = sent.send(());
+            }
+        }
+    });
+
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let result = loop {
+        let received = tokio::select! {
+            received = timeout(idle_timeout, recv_envelope(&mut read_half)) => received,
+            Ok(e) = &mut broken => break Err(e),
+        };
+        let request = match received {
+            Ok(Ok(envelope)) => envelope,
+            Ok(Err(Error::Io(e))) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+            Ok(Err(Error::FrameTooLarge { size, max })) => {
+                match skip_body(&mut read_half, size).await {
+                    Ok(Some(id)) => {
+                        let message =
+                            format!("request of {size} bytes exceeds the {max} byte limit");
+                        let envelope = Envelope {
+                            id,
+                            msg: Message::error(ErrorCode::TooLarge, message),
+                            epoch: None,
+                        };
+                        let _ = resp_tx.send((envelope, None));
+                        continue;
+                    }
+                    // Can't tell who to complain to, so give up on the connection.
+                    Ok(None) => break Err(Error::FrameTooLarge { size, max }),
+                    Err(e) => break Err(e),
+                }
+            }
+            Ok(Err(e)) => break Err(e),
+            Err(_) => {
+                eprintln!("[INFO] {peer} went quiet, hanging up.");
+                break Ok(());
+            }
+        };
+
+        let id = request.id;
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+            let envelope = Envelope {
+                id,
+                msg: Message::error(ErrorCode::Busy, "too many requests in flight"),
+                epoch: None,
+            };
+            let _ = resp_tx.send((envelope, None));
+            continue;
+        };
+        // The handler gets a task of its own so that if it panics, the peer hears about it
+        // instead of waiting forever.
+        let response = tokio::spawn(handler(request));
+        let resp_tx = resp_tx.clone();
+        tokio::spawn(async move {
+            let Reply { msg, sent } = match response.await {
+                Ok(reply) => reply.into(),
+                Err(e) => {
+                    eprintln!("[ERROR] Handling request {id} from {peer} failed: {e}");
+                    Message::error(ErrorCode::Internal, "request handler crashed").into()
+                }
+            };
+            let envelope = Envelope {
+                id,
+                msg,
+                epoch: None,
+            };
+            let _ = resp_tx.send((envelope, sent));
+            drop(permit);
+        });
+    };
+
+    // The writer finishes once every in-flight request has answered and dropped its sender.
+    drop(resp_tx);
+    let _ = writer.await;
+    result
+}
+
+/// Sends one response. One that can't be encoded never makes it onto the wire, so the stream is
+/// still in sync and the caller gets an error in its place.
+async fn respond(conn: &mut OwnedWriteHalf, peer: SocketAddr, envelope: Envelope) -> Result<()> {
+    let id = envelope.id;
+    let msg = match send_envelope(conn, envelope).await {
+        Err(e @ Error::FrameTooLarge { .. }) => {
+            eprintln!("[WARN] Response to request {id} from {peer} is too big to send: {e}");
+            Message::error(ErrorCode::TooLarge, format!("response {e}"))
+        }
+        Err(e @ Error::RMPEncode(_)) => {
+            eprintln!("[ERROR] Couldn't encode the response to request {id} from {peer}: {e}");
+            Message::error(ErrorCode::Internal, "couldn't encode the response")
+        }
+        sent => return sent,
+    };
+    send_envelope(
+        conn,
+        Envelope {
+            id,
+            msg,
+            epoch: None,
+        },
+    )
+    .await
+}
+
+/// Reads and throws away the body of an oversize frame, fishing the request ID out of the first
+/// few bytes on the way past.
+async fn skip_body<R: AsyncRead + Unpin>(conn: &mut R, size: usize) -> Result<Option<RequestId>> {
+    // An envelope starts with a 2-element array marker and then the ID, which is at most 9 bytes.
+    let mut head = [0u8; 10];
+    let head_len = head.len().min(size);
+    conn.read_exact(&mut head[..head_len]).await?;
+    let rest = (size - head_len) as u64;
+    tokio::io::copy(&mut conn.take(rest), &mut tokio::io::sink()).await?;
+
+    let mut head = &head[..head_len];
+    let id = rmp::decode::read_array_len(&mut head)
+        .ok()
+        .and_then(|_| rmp::decode::read_int(&mut head).ok());
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Consistency, VectorClock};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn out_of_order_responses_find_their_caller() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            handshake::accept(&mut conn, Role::Store, "store")
+                .await
+                .unwrap();
+            serve(conn, Duration::from_secs(5), |request| async move {
+                let Message::Get { key, .. } = request.msg else {
+                    unreachable!()
+                };
+                // Earlier keys take longer, so responses come back in reverse.
+                let delay: u64 = key.parse().unwrap();
+                tokio::time::sleep(Duration::from_millis(50 * (5 - delay))).await;
+                Message::Found {
+                    values: vec![key],
+                    context: VectorClock::default(),
+                }
+            })
+            .await
+            .unwrap();
+        });
+
+        let conn = Arc::new(
+            Connection::connect(addr, Role::Client, "client")
+                .await
+                .unwrap(),
+        );
+        let mut calls = Vec::new();
+        for i in 0..5 {
+            let conn = conn.clone();
+            calls.push(tokio::spawn(async move {
+                conn.call(Message::Get {
+                    key: i.to_string(),
+                    consistency: Consistency::default(),
+                })
+                .await
+            }));
+        }
+        for (i, call) in calls.into_iter().enumerate() {
+            let got = call.await.unwrap().unwrap();
+            assert_eq!(
+                got,
+                Message::Found {
+                    values: vec![i.to_string()],
+                    context: VectorClock::default(),
+                }
+            );
+        }
+    }
+
+    async fn spawn_server<H, Fut, R>(handler: H) -> SocketAddr
+    where
+        H: Fn(Envelope) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: Into<Reply> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            handshake::accept(&mut conn, Role::Store, "store")
+                .await
+                .unwrap();
+            serve(conn, Duration::from_secs(5), handler).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn panicking_handlers_become_internal_errors() {
+        let addr = spawn_server(|request| async move {
+            match request.msg {
+                Message::Get { .. } => panic!("oh no"),
+                _ => Message::DonePut,
+            }
+        })
+        .await;
+
+        let conn = Connection::connect(addr, Role::Client, "client")
+            .await
+            .unwrap();
+        let got = conn
+            .call(Message::Get {
+                key: "k".into(),
+                consistency: Consistency::default(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            got,
+            Message::Error {
+                code: ErrorCode::Internal,
+                ..
+            }
+        ));
+        // Connection is still good afterwards.
+        let got = conn
+            .call(Message::Delete {
+                key: "k".into(),
+                consistency: Consistency::default(),
+                context: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(got, Message::DonePut);
+    }
+
+    #[tokio::test]
+    async fn oversize_requests_are_bounced_not_fatal() {
+        let addr = spawn_server(|_| async { Message::DonePut }).await;
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        handshake::initiate(&mut conn, Role::Client, "client")
+            .await
+            .unwrap();
+        // Hand-roll a frame one byte over the limit, with a real envelope header up front.
+        let size = crate::max_frame_size() + 1;
+        let envelope = Envelope {
+            id: 41,
+            msg: Message::Get {
+                key: "k".into(),
+                consistency: Consistency::default(),
+            },
+            epoch: None,
+        };
+        let mut body = rmp_serde::to_vec(&envelope).unwrap();
+        body.resize(size, 0);
+        let mut frame = (size as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        tokio::io::AsyncWriteExt::write_all(&mut conn, &frame)
+            .await
+            .unwrap();
+        crate::send_envelope(
+            &mut conn,
+            Envelope {
+                id: 42,
+                msg: Message::Get {
+                    key: "k".into(),
+                    consistency: Consistency::default(),
+                },
+                epoch: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let got = recv_envelope(&mut conn).await.unwrap();
+        assert_eq!(got.id, 41);
+        assert!(matches!(
+            got.msg,
+            Message::Error {
+                code: ErrorCode::TooLarge,
+                ..
+            }
+        ));
+        let got = recv_envelope(&mut conn).await.unwrap();
+        assert_eq!(got.id, 42);
+        assert_eq!(got.msg, Message::DonePut);
+    }
+
+    #[tokio::test]
+    async fn oversize_responses_become_errors() {
+        let addr = spawn_server(|request| async move {
+            match request.msg {
+                Message::Get { .. } => Message::Found {
+                    values: vec!["x".repeat(crate::max_frame_size())],
+                    context: VectorClock::default(),
+                },
+                _ => Message::DonePut,
+            }
+        })
+        .await;
+
+        let conn = Connection::connect(addr, Role::Client, "client")
+            .await
+            .unwrap();
+        let got = conn
+            .call(Message::Get {
+                key: "k".into(),
+                consistency: Consistency::default(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            got,
+            Message::Error {
+                code: ErrorCode::TooLarge,
+                ..
+            }
+        ));
+        // The writer carries on with everyone else's responses.
+        let got = conn
+            .call(Message::Delete {
+                key: "k".into(),
+                consistency: Consistency::default(),
+                context: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(got, Message::DonePut);
+    }
+
+    #[tokio::test]
+    async fn handlers_hear_once_their_reply_is_sent() {
+        let (sent_tx, sent) = oneshot::channel();
+        let sent_tx = Mutex::new(Some(sent_tx));
+        let addr = spawn_server(move |_| {
+            let sent_tx = sent_tx.lock().unwrap().take().unwrap();
+            async move { Reply::then_notify(Message::Accepted, sent_tx) }
+        })
+        .await;
+
+        let conn = Connection::connect(addr, Role::Client, "client")
+            .await
+            .unwrap();
+        assert_eq!(
+            conn.call(Message::Shutdown).await.unwrap(),
+            Message::Accepted
+        );
+        timeout(Duration::from_secs(1), sent)
+            .await
+            .expect("Never heard the reply went out")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn callers_hear_about_a_dead_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            handshake::accept(&mut conn, Role::Store, "store")
+                .await
+                .unwrap();
+            // Read the request, then hang up without answering.
+            let _ = recv_envelope(&mut conn).await;
+        });
+
+        let conn = Connection::connect(addr, Role::Client, "client")
+            .await
+            .unwrap();
+        let got = conn
+            .call(Message::Get {
+                key: "k".into(),
+                consistency: Consistency::default(),
+            })
+            .await;
+        assert!(matches!(got, Err(Error::ConnectionClosed)));
+        assert!(conn.is_closed());
+    }
+
+    #[tokio::test]
+    async fn peers_that_never_say_hello_are_given_up_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Take the connection and sit on it.
+            let (conn, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(handshake::HANDSHAKE_TIMEOUT * 2).await;
+            drop(conn);
+        });
+
+        let got = Connection::connect(addr, Role::Client, "client").await;
+        assert!(matches!(got, Err(Error::Timeout)));
+    }
+}
@@ -0,0 +1,308 @@
+//! The manager's view of who owns what, in a form it can hand out. Clients cache it and route
+//! keys themselves, and the epoch lets storage nodes tell when a client's copy is out of date.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::placement::{Placement, Strategy};
+
+/// Version number of a [`PartitionMap`]. Only ever goes up.
+pub type Epoch = u64;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartitionMap {
+    /// Bumped by the manager every time the map changes. Epoch 0 means "haven't heard from the
+    /// manager yet".
+    pub epoch: Epoch,
+    /// Which storage nodes own which keys. Usually a ring, but see [`Strategy`]. Nodes are on it
+    /// by address; see [`PartitionMap::members`] for everyone taking part.
+    pub ring: Strategy,
+    /// Replication settings for the cluster. Individual requests can override them, see
+    /// [`Consistency`].
+    #[serde(default)]
+    pub quorum: Quorum,
+    /// Nodes that stopped answering the manager's heartbeats. Writes meant for them go to the
+    /// next node along the ring instead, see [`PartitionMap::preference_list`].
+    #[serde(default)]
+    pub down: Vec<SocketAddr>,
+    /// Nodes that have missed heartbeats but haven't been given up on yet. They keep their keys;
+    /// clients just try them after everyone else.
+    #[serde(default)]
+    pub suspect: Vec<SocketAddr>,
+    /// A change to the ring whose keys are still being handed off. Writes go to the owners under
+    /// both rings, but reads stay on `ring` until every node has caught up.
+    #[serde(default)]
+    pub pending: Option<Rebalance>,
+}
+
+/// A ring change in progress. See [`PartitionMap::pending`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Rebalance {
+    /// What `ring` becomes once the handoff is done.
+    pub ring: Strategy,
+    /// Epoch of the first map with this change in it, which is how nodes tell handoffs apart.
+    pub since: Epoch,
+}
+
+/// What the manager's failure detector thinks of a node. Ordered from most to least trustworthy.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Health {
+    Alive,
+    /// Missed a heartbeat or two. Could just be slow.
+    Suspect,
+    /// Hasn't answered for long enough that we've stopped counting on it.
+    Dead,
+}
+
+/// How many replicas each key is written to (`n`), and how many of them have to answer a read
+/// (`r`) or acknowledge a write (`w`) before the coordinator replies. With `r + w > n`, every read
+/// overlaps the latest acknowledged write.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Quorum {
+    pub n: usize,
+    pub r: usize,
+    pub w: usize,
+}
+
+impl Default for Quorum {
+    /// A lone node with no manager: it's the only replica there is.
+    fn default() -> Self {
+        Self { n: 1, r: 1, w: 1 }
+    }
+}
+
+impl Quorum {
+    /// Reads and writes both wait for a majority of the `n` replicas.
+    pub fn majority(n: usize) -> Self {
+        let n = n.max(1);
+        Self {
+            n,
+            r: n / 2 + 1,
+            w: n / 2 + 1,
+        }
+    }
+
+    /// Applies per-request overrides. `n` can't go past the cluster's, and `r` and `w` are kept
+    /// between 1 and `n`.
+    pub fn with(self, overrides: Consistency) -> Self {
+        let n = overrides.n.unwrap_or(self.n).clamp(1, self.n.max(1));
+        Self {
+            n,
+            r: overrides.r.unwrap_or(self.r).clamp(1, n),
+            w: overrides.w.unwrap_or(self.w).clamp(1, n),
+        }
+    }
+}
+
+/// Per-request overrides for the cluster's [`Quorum`]. Anything left `None` uses the cluster's
+/// setting. `n` can only shrink the write group; keys never have more replicas than the ring gives
+/// them.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Consistency {
+    pub n: Option<usize>,
+    pub r: Option<usize>,
+    pub w: Option<usize>,
+}
+
+impl PartitionMap {
+    /// Addresses of the storage nodes responsible for `key`, in the order they should be tried.
+    pub fn owners(&self, key: &str) -> Vec<SocketAddr> {
+        self.ring.write_group(key).into_vec()
+    }
+
+    /// Every storage node, in the order they should take responsibility for `key`. The first
+    /// `repl` are the owners.
+    pub fn preference_list(&self, key: &str) -> Vec<SocketAddr> {
+        self.ring
+            .preference_list(key, self.ring.node_count())
+            .into_vec()
+    }
+
+    /// Nodes in the ring, plus any joining it, in address order. Nodes that have left aren't.
+    pub fn members(&self) -> Vec<SocketAddr> {
+        let mut members = self.ring.nodes();
+        if let Some(pending) = &self.pending {
+            members.extend(pending.ring.nodes());
+            members.sort();
+            members.dedup();
+        }
+        members
+    }
+
+    /// Whether `addr` is in the ring and isn't on its way out.
+    pub fn is_settled(&self, addr: SocketAddr) -> bool {
+        self.ring.contains(addr) && self.pending.as_ref().is_none_or(|p| p.ring.contains(addr))
+    }
+
+    pub fn is_down(&self, addr: SocketAddr) -> bool {
+        self.down.contains(&addr)
+    }
+
+    pub fn health(&self, addr: SocketAddr) -> Health {
+        if self.is_down(addr) {
+            Health::Dead
+        } else if self.suspect.contains(&addr) {
+            Health::Suspect
+        } else {
+            Health::Alive
+        }
+    }
+
+    pub fn is_owner(&self, addr: SocketAddr, key: &str) -> bool {
+        self.ring.write_group(key).contains(&addr)
+    }
+
+    /// Nodes that will own `key` once the pending rebalance is done but don't yet, in ring order.
+    pub fn gaining(&self, key: &str) -> Vec<SocketAddr> {
+        let Some(pending) = &self.pending else {
+            return Vec::new();
+        };
+        let owners = self.owners(key);
+        pending
+            .ring
+            .write_group(key)
+            .into_iter()
+            .filter(|addr| !owners.contains(addr))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{placement::DEFAULT_TOKENS, ring_hash::RingHash};
+
+    #[test]
+    fn owners_match_the_ring() {
+        let nodes: Vec<_> = (0..4)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 50052 + i)))
+            .collect();
+        let mut ring = Strategy::from(RingHash::new(2));
+        for &node in &nodes {
+            ring.add_node(node, DEFAULT_TOKENS);
+        }
+        let map = PartitionMap {
+            epoch: 1,
+            ring,
+            quorum: Quorum::majority(2),
+            down: Vec::new(),
+            suspect: Vec::new(),
+            pending: None,
+        };
+
+        for key in ["a", "b", "c", "onetwothree"] {
+            let owners = map.owners(key);
+            assert_eq!(owners.len(), 2);
+            for node in &nodes {
+                assert_eq!(map.is_owner(*node, key), owners.contains(node));
+            }
+        }
+    }
+
+    #[test]
+    fn only_new_owners_are_gaining() {
+        let nodes: Vec<_> = (0..4)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 50052 + i)))
+            .collect();
+        let mut ring = Strategy::from(RingHash::new(2));
+        for &node in &nodes[..3] {
+            ring.add_node(node, DEFAULT_TOKENS);
+        }
+        let mut next = ring.clone();
+        next.add_node(nodes[3], DEFAULT_TOKENS);
+        let mut map = PartitionMap {
+            epoch: 1,
+            ring,
+            quorum: Quorum::majority(2),
+            down: Vec::new(),
+            suspect: Vec::new(),
+            pending: None,
+        };
+        assert!(map.gaining("a").is_empty());
+
+        map.pending = Some(Rebalance {
+            ring: next,
+            since: 1,
+        });
+        let mut moved = 0;
+        for i in 0..64 {
+            let key = format!("key_{i}");
+            let gaining = map.gaining(&key);
+            match gaining.as_slice() {
+                [] => {}
+                [node] => {
+                    assert_eq!(*node, nodes[3]);
+                    moved += 1;
+                }
+                more => panic!("{more:?} can't all be new to '{key}'"),
+            }
+        }
+        // Some keys move to the new node, but reads keep going to the old owners.
+        assert!(moved > 0);
+        assert!((0..64).all(|i| !map.is_owner(nodes[3], &format!("key_{i}"))));
+        assert_eq!(map.members(), nodes);
+        assert!(!map.is_settled(nodes[3]));
+
+        // Once it's in, leaving works the same way in reverse.
+        map.ring = map.pending.take().unwrap().ring;
+        assert!(map.is_settled(nodes[3]));
+        let mut next = map.ring.clone();
+        next.remove_node(nodes[0]);
+        map.pending = Some(Rebalance {
+            ring: next.clone(),
+            since: 2,
+        });
+        assert!(!map.is_settled(nodes[0]));
+        assert_eq!(map.members(), nodes);
+        map.ring = next;
+        map.pending = None;
+        assert_eq!(map.members(), nodes[1..]);
+    }
+
+    #[test]
+    fn survives_the_wire() {
+        let mut ring = Strategy::from(RingHash::new(3));
+        ring.add_node(SocketAddr::from(([127, 0, 0, 1], 50052)), DEFAULT_TOKENS);
+        let map = PartitionMap {
+            epoch: 7,
+            ring,
+            quorum: Quorum::majority(3),
+            down: vec![SocketAddr::from(([127, 0, 0, 1], 50053))],
+            suspect: Vec::new(),
+            pending: None,
+        };
+        let bytes = rmp_serde::to_vec(&map).unwrap();
+        let map_prime: PartitionMap = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(map, map_prime);
+    }
+
+    #[test]
+    fn overrides_stay_in_bounds() {
+        let quorum = Quorum::majority(3);
+        assert_eq!(quorum, Quorum { n: 3, r: 2, w: 2 });
+        assert_eq!(quorum.with(Consistency::default()), quorum);
+
+        let one = Consistency {
+            r: Some(1),
+            ..Consistency::default()
+        };
+        assert_eq!(quorum.with(one), Quorum { n: 3, r: 1, w: 2 });
+
+        // Asking for more acknowledgements than there are replicas means "all of them".
+        let everyone = Consistency {
+            n: Some(2),
+            w: Some(5),
+            ..Consistency::default()
+        };
+        assert_eq!(quorum.with(everyone), Quorum { n: 2, r: 2, w: 2 });
+
+        // Nor can a request have more replicas than the cluster keeps.
+        let more = Consistency {
+            n: Some(5),
+            w: Some(5),
+            ..Consistency::default()
+        };
+        assert_eq!(quorum.with(more), Quorum { n: 3, r: 2, w: 3 });
+    }
+}
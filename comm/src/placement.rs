@@ -0,0 +1,455 @@
+//! Deciding which storage nodes hold which keys. [`RingHash`] is what the cluster has always used;
+//! [`Rendezvous`] and [`JumpHash`] trade its virtual nodes for less bookkeeping, and [`RangeMap`]
+//! keeps keys that sort together on the same nodes so they can be scanned. A cluster picks one
+//! when it's first started, and [`Strategy`] is whichever that was.
+//!
+//! Every strategy has to come to the same answer in every process, so they only look at the key,
+//! the nodes' addresses and tokens, and (for jump hashing) the order nodes were added in, which is
+//! part of the map everyone is handed.
+
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use sha1::{Digest, Sha1};
+use smallvec::SmallVec;
+use std::{collections::BTreeMap, fmt, net::SocketAddr, str::FromStr};
+
+use crate::{
+    jump_hash::JumpHash, range_map::RangeMap, rendezvous::Rendezvous, ring_hash::RingHash,
+};
+
+/// Tokens a node gets unless it's told otherwise. Enough virtual nodes that ring nodes with the
+/// same count end up with roughly the same share of keys.
+pub const DEFAULT_TOKENS: usize = 64;
+
+/// What turns keys and node names into numbers. It's part of the placement, so everyone holding a
+/// copy hashes the same way.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HashFn {
+    /// First 8 bytes of the SHA-1 digest, read big-endian.
+    #[default]
+    Sha1,
+    /// 64-bit XXH3. Much cheaper than SHA-1, and keys don't need to be hard to forge.
+    Xxh3,
+}
+
+impl HashFn {
+    pub fn hash(self, bytes: &[u8]) -> u64 {
+        match self {
+            Self::Sha1 => u64::from_be_bytes(Sha1::digest(bytes)[..8].try_into().unwrap()),
+            Self::Xxh3 => xxhash_rust::xxh3::xxh3_64(bytes),
+        }
+    }
+}
+
+impl fmt::Display for HashFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Sha1 => "sha1",
+            Self::Xxh3 => "xxh3",
+        })
+    }
+}
+
+impl FromStr for HashFn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(Self::Sha1),
+            "xxh3" => Ok(Self::Xxh3),
+            _ => Err(format!("unknown hash '{s}', expected sha1 or xxh3")),
+        }
+    }
+}
+
+/// How much of the keyspace a node is responsible for, as fractions of the whole.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Share {
+    /// Keys it's first in line for.
+    pub primary: f64,
+    /// Keys it's one of the replicas for. These add up to the replica count across every node, as
+    /// long as there are at least that many nodes.
+    pub replica: f64,
+}
+
+/// Keys [`Placement::shares`] looks at when there's no way to work them out exactly.
+const SHARE_SAMPLES: usize = 10_000;
+
+pub trait Placement {
+    /// Places `node` with `tokens` as its weight, replacing whatever it had before.
+    fn add_node(&mut self, node: SocketAddr, tokens: usize);
+
+    fn remove_node(&mut self, node: SocketAddr);
+
+    /// `node`'s weight, or 0 if it isn't placed.
+    fn tokens(&self, node: SocketAddr) -> usize;
+
+    fn contains(&self, node: SocketAddr) -> bool;
+
+    /// Every node, in address order.
+    fn nodes(&self) -> Vec<SocketAddr>;
+
+    /// Number of distinct nodes each key is written to.
+    fn replicas(&self) -> usize;
+
+    /// The first `len` distinct nodes responsible for `key`, most responsible first. The first
+    /// [`Placement::replicas`] are the write group; the rest are who to fall back on when some of
+    /// those are down.
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]>;
+
+    fn node_count(&self) -> usize {
+        self.nodes().len()
+    }
+
+    // A little bruh to be boxing when the size of each of these is technically
+    // known at compile-time. I do NOT trust the compiler to infer that the ACTUAL
+    // type is [SocketAddr; self.repl] and unbox all these slices.
+    fn write_group(&self, key: &str) -> SmallVec<[SocketAddr; 16]> {
+        self.preference_list(key, self.replicas())
+    }
+
+    /// How the keyspace is split up between the nodes. Estimated from where a sample of keys
+    /// lands, unless the strategy knows better.
+    fn shares(&self) -> BTreeMap<SocketAddr, Share> {
+        let mut shares = BTreeMap::new();
+        let weight = 1.0 / SHARE_SAMPLES as f64;
+        for i in 0..SHARE_SAMPLES {
+            for (rank, node) in self
+                .write_group(&format!("sample_{i}"))
+                .into_iter()
+                .enumerate()
+            {
+                let share: &mut Share = shares.entry(node).or_default();
+                if rank == 0 {
+                    share.primary += weight;
+                }
+                share.replica += weight;
+            }
+        }
+        shares
+    }
+}
+
+/// Which [`Placement`] a cluster uses, picked when it's first started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StrategyKind {
+    #[default]
+    Ring,
+    Rendezvous,
+    Jump,
+    Range,
+}
+
+impl fmt::Display for StrategyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Ring => "ring",
+            Self::Rendezvous => "rendezvous",
+            Self::Jump => "jump",
+            Self::Range => "range",
+        })
+    }
+}
+
+impl FromStr for StrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(Self::Ring),
+            "rendezvous" | "hrw" => Ok(Self::Rendezvous),
+            "jump" => Ok(Self::Jump),
+            "range" => Ok(Self::Range),
+            _ => Err(format!(
+                "unknown placement '{s}', expected ring, rendezvous, jump or range"
+            )),
+        }
+    }
+}
+
+/// A cluster's placement, whichever kind it is.
+///
+/// On the wire a ring is sent bare, the way it was before there were other kinds, and everything
+/// else is tagged with its kind. That keeps ring clusters readable by peers that predate this.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    Ring(RingHash),
+    Rendezvous(Rendezvous),
+    Jump(JumpHash),
+    Range(RangeMap),
+}
+
+impl Serialize for Strategy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Ring(ring) => ring.serialize(serializer),
+            Self::Rendezvous(hrw) => {
+                serializer.serialize_newtype_variant("Strategy", 1, "Rendezvous", hrw)
+            }
+            Self::Jump(jump) => serializer.serialize_newtype_variant("Strategy", 2, "Jump", jump),
+            Self::Range(ranges) => {
+                serializer.serialize_newtype_variant("Strategy", 3, "Range", ranges)
+            }
+        }
+    }
+}
+
+/// How a tagged [`Strategy`] is decoded. A tagged ring is accepted too, since that's how they
+/// were written for a while.
+#[derive(Deserialize)]
+#[serde(rename = "Strategy")]
+enum Tagged {
+    Ring(RingHash),
+    Rendezvous(Rendezvous),
+    Jump(JumpHash),
+    Range(RangeMap),
+}
+
+impl<'de> Deserialize<'de> for Strategy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StrategyVisitor;
+
+        impl<'de> Visitor<'de> for StrategyVisitor {
+            type Value = Strategy;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a ring, or a placement tagged with its kind")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Strategy, A::Error> {
+                RingHash::deserialize(SeqAccessDeserializer::new(seq)).map(Strategy::Ring)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Strategy, A::Error> {
+                Ok(
+                    match Tagged::deserialize(MapAccessDeserializer::new(map))? {
+                        Tagged::Ring(ring) => Strategy::Ring(ring),
+                        Tagged::Rendezvous(hrw) => Strategy::Rendezvous(hrw),
+                        Tagged::Jump(jump) => Strategy::Jump(jump),
+                        Tagged::Range(ranges) => Strategy::Range(ranges),
+                    },
+                )
+            }
+        }
+
+        deserializer.deserialize_any(StrategyVisitor)
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Self::Ring(RingHash::default())
+    }
+}
+
+impl From<RingHash> for Strategy {
+    fn from(ring: RingHash) -> Self {
+        Self::Ring(ring)
+    }
+}
+
+impl Strategy {
+    pub fn new(kind: StrategyKind, repl: usize, hash: HashFn) -> Self {
+        match kind {
+            StrategyKind::Ring => Self::Ring(RingHash::with_hash(repl, hash)),
+            StrategyKind::Rendezvous => Self::Rendezvous(Rendezvous::new(repl, hash)),
+            StrategyKind::Jump => Self::Jump(JumpHash::new(repl, hash)),
+            // Split points are keys themselves, so there's nothing to hash.
+            StrategyKind::Range => Self::Range(RangeMap::new(repl)),
+        }
+    }
+
+    pub fn kind(&self) -> StrategyKind {
+        match self {
+            Self::Ring(_) => StrategyKind::Ring,
+            Self::Rendezvous(_) => StrategyKind::Rendezvous,
+            Self::Jump(_) => StrategyKind::Jump,
+            Self::Range(_) => StrategyKind::Range,
+        }
+    }
+
+    /// The ranges, if keys are range-partitioned.
+    pub fn as_ranges(&self) -> Option<&RangeMap> {
+        match self {
+            Self::Range(ranges) => Some(ranges),
+            _ => None,
+        }
+    }
+
+    pub fn as_ranges_mut(&mut self) -> Option<&mut RangeMap> {
+        match self {
+            Self::Range(ranges) => Some(ranges),
+            _ => None,
+        }
+    }
+
+    fn inner(&self) -> &dyn Placement {
+        match self {
+            Self::Ring(ring) => ring,
+            Self::Rendezvous(hrw) => hrw,
+            Self::Jump(jump) => jump,
+            Self::Range(ranges) => ranges,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn Placement {
+        match self {
+            Self::Ring(ring) => ring,
+            Self::Rendezvous(hrw) => hrw,
+            Self::Jump(jump) => jump,
+            Self::Range(ranges) => ranges,
+        }
+    }
+}
+
+impl Placement for Strategy {
+    fn add_node(&mut self, node: SocketAddr, tokens: usize) {
+        self.inner_mut().add_node(node, tokens)
+    }
+
+    fn remove_node(&mut self, node: SocketAddr) {
+        self.inner_mut().remove_node(node)
+    }
+
+    fn tokens(&self, node: SocketAddr) -> usize {
+        self.inner().tokens(node)
+    }
+
+    fn contains(&self, node: SocketAddr) -> bool {
+        self.inner().contains(node)
+    }
+
+    fn nodes(&self) -> Vec<SocketAddr> {
+        self.inner().nodes()
+    }
+
+    fn replicas(&self) -> usize {
+        self.inner().replicas()
+    }
+
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]> {
+        self.inner().preference_list(key, len)
+    }
+
+    fn node_count(&self) -> usize {
+        self.inner().node_count()
+    }
+
+    fn shares(&self) -> BTreeMap<SocketAddr, Share> {
+        self.inner().shares()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    const KINDS: [StrategyKind; 4] = [
+        StrategyKind::Ring,
+        StrategyKind::Rendezvous,
+        StrategyKind::Jump,
+        StrategyKind::Range,
+    ];
+
+    #[test]
+    fn hashes_are_pinned() {
+        // Reference values, so a dependency bump or a different platform can't quietly move keys.
+        assert_eq!(HashFn::Sha1.hash(b""), 0xda39a3ee5e6b4b0d);
+        assert_eq!(HashFn::Sha1.hash(b"a"), 0x86f7e437faa5a7fc);
+        assert_eq!(HashFn::Xxh3.hash(b""), 0x2d06800538d394c2);
+        assert_eq!(HashFn::Xxh3.hash(b"a"), 0xe6c632b61e964e1f);
+    }
+
+    #[test]
+    fn rings_decode_the_way_v4_sent_them() {
+        let mut ring = RingHash::new(2);
+        ring.add_node(addr(50052), DEFAULT_TOKENS);
+        let bare = rmp_serde::to_vec(&ring).unwrap();
+        let placement = Strategy::from(ring);
+        assert_eq!(rmp_serde::to_vec(&placement).unwrap(), bare);
+        assert_eq!(rmp_serde::from_slice::<Strategy>(&bare).unwrap(), placement);
+
+        for kind in KINDS {
+            let mut placement = Strategy::new(kind, 3, HashFn::Xxh3);
+            placement.add_node(addr(50052), DEFAULT_TOKENS);
+            let bytes = rmp_serde::to_vec(&placement).unwrap();
+            assert_eq!(
+                rmp_serde::from_slice::<Strategy>(&bytes).unwrap(),
+                placement
+            );
+        }
+    }
+
+    #[test]
+    fn every_strategy_hands_out_distinct_replicas() {
+        for kind in KINDS {
+            let mut placement = Strategy::new(kind, 3, HashFn::Xxh3);
+            assert!(placement.write_group("anything").is_empty());
+            let nodes: Vec<_> = (50052..50057).map(addr).collect();
+            for &node in &nodes {
+                placement.add_node(node, DEFAULT_TOKENS);
+            }
+            assert_eq!(placement.nodes(), nodes);
+            for i in 0..64 {
+                let key = format!("key_{i}");
+                let list = placement.preference_list(&key, 10);
+                assert_eq!(list[..3], placement.write_group(&key)[..], "{kind}");
+                let mut sorted = list.into_vec();
+                sorted.sort();
+                assert_eq!(sorted, nodes, "{kind}");
+            }
+
+            let shares = placement.shares();
+            let total = |f: fn(&Share) -> f64| shares.values().map(f).sum::<f64>();
+            assert!((total(|s| s.primary) - 1.0).abs() < 1e-6, "{kind}");
+            assert!((total(|s| s.replica) - 3.0).abs() < 1e-6, "{kind}");
+        }
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        // Ranges only move when there's more than one of them, see `range_map`.
+        for kind in KINDS[..3].iter().copied() {
+            let mut placement = Strategy::new(kind, 1, HashFn::Xxh3);
+            for port in 50052..50062 {
+                placement.add_node(addr(port), DEFAULT_TOKENS);
+            }
+            let before: Vec<_> = (0..1000)
+                .map(|i| placement.write_group(&format!("key_{i}"))[0])
+                .collect();
+            placement.add_node(addr(50062), DEFAULT_TOKENS);
+            let mut moved = 0;
+            for (i, old) in before.into_iter().enumerate() {
+                let new = placement.write_group(&format!("key_{i}"))[0];
+                if new != old {
+                    assert_eq!(new, addr(50062), "{kind}");
+                    moved += 1;
+                }
+            }
+            // About 1/11 of the keys, give or take.
+            assert!((40..150).contains(&moved), "{kind} moved {moved}");
+        }
+    }
+
+    #[test]
+    fn strategy_survives_the_wire() {
+        for kind in KINDS {
+            let mut placement = Strategy::new(kind, 2, HashFn::Sha1);
+            placement.add_node(addr(50052), 8);
+            placement.add_node(addr(50053), 16);
+            let bytes = rmp_serde::to_vec(&placement).unwrap();
+            let placement_prime: Strategy = rmp_serde::from_slice(&bytes).unwrap();
+            assert_eq!(placement_prime.kind(), kind);
+            assert_eq!(placement, placement_prime);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Keeps a few multiplexed connections open to each peer so callers don't pay for a handshake per
+//! request.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::timeout;
+
+use crate::{mux::Connection, Epoch, Error, Message, Result, Role};
+
+#[derive(Default)]
+struct Slot {
+    conns: Vec<Arc<Connection>>,
+    next: usize,
+}
+
+pub struct Pool {
+    role: Role,
+    node_id: String,
+    /// Connections to keep per peer. Each one multiplexes, so this only needs to be big enough to
+    /// keep a single socket from being the bottleneck.
+    size: usize,
+    connect_timeout: Duration,
+    slots: Mutex<HashMap<SocketAddr, Slot>>,
+}
+
+impl Pool {
+    pub fn new(role: Role, node_id: String, size: usize, connect_timeout: Duration) -> Self {
+        Self {
+            role,
+            node_id,
+            size: size.max(1),
+            connect_timeout,
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Hands out an open connection to `addr`, opening a new one if the pool isn't full yet.
+    pub async fn get(&self, addr: SocketAddr) -> Result<Arc<Connection>> {
+        {
+            let mut slots = self.slots.lock().expect("Lock poisoned :(");
+            let slot = slots.entry(addr).or_default();
+            slot.conns.retain(|conn| !conn.is_closed());
+            if slot.conns.len() >= self.size {
+                let conn = slot.conns[slot.next % slot.conns.len()].clone();
+                slot.next = slot.next.wrapping_add(1);
+                return Ok(conn);
+            }
+        }
+
+        let conn = timeout(
+            self.connect_timeout,
+            Connection::connect(addr, self.role, &self.node_id),
+        )
+        .await
+        .map_err(|_| Error::Timeout)??;
+        let conn = Arc::new(conn);
+
+        // Someone else may have filled the slot while we were connecting. Use ours for this
+        // request either way, but only keep it if there's room.
+        let mut slots = self.slots.lock().expect("Lock poisoned :(");
+        let slot = slots.entry(addr).or_default();
+        if slot.conns.len() < self.size {
+            slot.conns.push(conn.clone());
+        }
+        Ok(conn)
+    }
+
+    pub async fn call(&self, addr: SocketAddr, msg: Message) -> Result<Message> {
+        self.call_at(addr, msg, None).await
+    }
+
+    pub async fn call_at(
+        &self,
+        addr: SocketAddr,
+        msg: Message,
+        epoch: Option<Epoch>,
+    ) -> Result<Message> {
+        self.get(addr).await?.call_at(msg, epoch).await
+    }
+}
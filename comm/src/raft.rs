@@ -0,0 +1,52 @@
+//! What managers replicate between themselves with Raft. The log is a list of changes to the
+//! partition map; every manager applies the same changes in the same order, so whichever of them
+//! is leading hands out the same map.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Epoch, Health, PartitionMap};
+
+/// Raft election term. Only ever goes up.
+pub type Term = u64;
+/// Position in the log, counting from 1. 0 means "before the first entry".
+pub type LogIndex = u64;
+
+/// The start of the log, squashed down to the map it adds up to so the log doesn't grow forever.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Last entry it covers, and that entry's term.
+    pub last_index: LogIndex,
+    pub last_term: Term,
+    pub map: PartitionMap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Term of the leader that appended it.
+    pub term: Term,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Command {
+    /// Appended by every new leader so it can commit whatever its predecessors left behind.
+    Noop,
+    /// The cluster as the first leader found it. Replaces the whole map.
+    Bootstrap { map: PartitionMap },
+    /// The leader's failure detector changed its mind about a storage node.
+    SetHealth { node: SocketAddr, health: Health },
+    /// Starts adding a storage node to the ring with `tokens` virtual nodes. See
+    /// [`PartitionMap::pending`].
+    Join { node: SocketAddr, tokens: usize },
+    /// Starts taking a storage node out of the ring.
+    Leave { node: SocketAddr },
+    /// Every node has caught up on the rebalance that started at `since`, so switch rings.
+    FinishRebalance { since: Epoch },
+    /// The rebalance that started at `since` stopped making progress, so drop it and stay on the
+    /// current ring.
+    AbortRebalance { since: Epoch },
+    /// Cuts the range holding `at` in two. Only for range-partitioned clusters.
+    Split { at: String },
+}
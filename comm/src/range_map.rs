@@ -0,0 +1,326 @@
+//! Range partitioning. The keyspace is cut into contiguous ranges at split points, and each range
+//! is handed to its own set of replicas. Keys that sort together live together, so a scan only has
+//! to visit the ranges it covers instead of every node in the cluster.
+//!
+//! Ranges start out as one and get split by the manager once the stores report that one has too
+//! many keys; see [`RangeSize`]. Which nodes own a range is written down rather than computed, so
+//! every change to it goes through the map like any other ring change.
+
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::placement::{Placement, Share};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RangeMap {
+    /// In key order. The first starts at `""`, and each runs up to where the next one starts.
+    ranges: Vec<Range>,
+    /// Every node and its weight. Heavier nodes are given more replicas when ranges get handed
+    /// out.
+    tokens: BTreeMap<SocketAddr, usize>,
+    /// Number of distinct nodes each key is written to.
+    repl: usize,
+}
+
+/// One contiguous slice of the keyspace.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Range {
+    /// First key in the range. It runs up to the next range's start.
+    pub start: String,
+    /// Who holds it, in the order they should be tried.
+    pub owners: Vec<SocketAddr>,
+}
+
+/// How big a range has gotten, as reported by the first of its owners.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RangeSize {
+    pub start: String,
+    /// Live keys in the range.
+    pub keys: u64,
+    /// Where to split it so about half its keys end up on each side. `None` if it can't be split.
+    pub middle: Option<String>,
+}
+
+/// The first key that sorts after `key`, for picking a scan up right past it.
+pub fn after(key: &str) -> String {
+    format!("{key}\0")
+}
+
+impl Default for RangeMap {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RangeMap {
+    pub fn new(repl: usize) -> Self {
+        Self {
+            ranges: vec![Range {
+                start: String::new(),
+                owners: Vec::new(),
+            }],
+            tokens: BTreeMap::new(),
+            repl,
+        }
+    }
+
+    pub fn ranges(&self) -> &[Range] {
+        &self.ranges
+    }
+
+    fn index(&self, key: &str) -> usize {
+        // The first range starts at "", so there's always one at or before `key`.
+        self.ranges
+            .partition_point(|range| range.start.as_str() <= key)
+            - 1
+    }
+
+    /// Where the range holding `key` starts, and where the next one does, if there is one.
+    pub fn bounds(&self, key: &str) -> (&str, Option<&str>) {
+        let i = self.index(key);
+        let end = self.ranges.get(i + 1).map(|range| range.start.as_str());
+        (&self.ranges[i].start, end)
+    }
+
+    /// Cuts the range holding `at` in two, with `at` starting the second half. Both halves keep
+    /// the same owners. Returns whether there was anything to cut.
+    pub fn split(&mut self, at: &str) -> bool {
+        let i = self.index(at);
+        if self.ranges[i].start == at {
+            return false;
+        }
+        let owners = self.ranges[i].owners.clone();
+        self.ranges.insert(
+            i + 1,
+            Range {
+                start: at.to_owned(),
+                owners,
+            },
+        );
+        true
+    }
+
+    /// Moves the replicas of the range starting at `start` onto whichever nodes have the fewest,
+    /// so freshly split ranges don't all stay where their parent was. Returns whether any moved.
+    pub fn spread(&mut self, start: &str) -> bool {
+        let i = self.index(start);
+        let mut moved = false;
+        for slot in 0..self.ranges[i].owners.len() {
+            let current = self.ranges[i].owners[slot];
+            let lightest = self
+                .tokens
+                .keys()
+                .copied()
+                .filter(|node| !self.ranges[i].owners.contains(node))
+                .min_by_key(|&node| (self.surplus(node), node));
+            if let Some(lightest) = lightest {
+                // Only worth the move if it leaves things more even than they were.
+                if self.surplus(lightest) + 1 < self.surplus(current) {
+                    self.ranges[i].owners[slot] = lightest;
+                    moved = true;
+                }
+            }
+        }
+        moved
+    }
+
+    fn load(&self, node: SocketAddr) -> usize {
+        self.ranges
+            .iter()
+            .filter(|range| range.owners.contains(&node))
+            .count()
+    }
+
+    /// How many more replicas `node` holds than its weight says it should. Negative if it's
+    /// holding fewer.
+    fn surplus(&self, node: SocketAddr) -> isize {
+        let replicas: usize = self.ranges.iter().map(|range| range.owners.len()).sum();
+        let weight: usize = self.tokens.values().sum();
+        let fair = (replicas * self.tokens(node))
+            .checked_div(weight)
+            .unwrap_or(0);
+        self.load(node) as isize - fair as isize
+    }
+}
+
+/// Tokens are weights: a node with twice as many is handed about twice the replicas.
+impl Placement for RangeMap {
+    /// Fills in ranges that are short of replicas first, then takes ranges off whoever has the
+    /// most more than their share until the new node has its own.
+    fn add_node(&mut self, node: SocketAddr, tokens: usize) {
+        if self.tokens.insert(node, tokens).is_some() {
+            return;
+        }
+        for range in &mut self.ranges {
+            if range.owners.len() < self.repl {
+                range.owners.push(node);
+            }
+        }
+        while self.surplus(node) < 0 {
+            let donor = self
+                .tokens
+                .keys()
+                .copied()
+                .filter(|&other| other != node)
+                .max_by_key(|&other| (self.surplus(other), std::cmp::Reverse(other)));
+            let Some(donor) = donor.filter(|&donor| self.surplus(donor) > 0) else {
+                break;
+            };
+            let range = self
+                .ranges
+                .iter_mut()
+                .find(|range| range.owners.contains(&donor) && !range.owners.contains(&node));
+            let Some(range) = range else {
+                break;
+            };
+            for owner in &mut range.owners {
+                if *owner == donor {
+                    *owner = node;
+                }
+            }
+        }
+    }
+
+    /// Hands each of the node's replicas to whoever has the fewest.
+    fn remove_node(&mut self, node: SocketAddr) {
+        if self.tokens.remove(&node).is_none() {
+            return;
+        }
+        for i in 0..self.ranges.len() {
+            let Some(slot) = self.ranges[i].owners.iter().position(|&o| o == node) else {
+                continue;
+            };
+            self.ranges[i].owners.remove(slot);
+            let lightest = self
+                .tokens
+                .keys()
+                .copied()
+                .filter(|other| !self.ranges[i].owners.contains(other))
+                .min_by_key(|&other| (self.surplus(other), other));
+            if let Some(lightest) = lightest {
+                self.ranges[i].owners.insert(slot, lightest);
+            }
+        }
+    }
+
+    fn tokens(&self, node: SocketAddr) -> usize {
+        self.tokens.get(&node).copied().unwrap_or(0)
+    }
+
+    fn contains(&self, node: SocketAddr) -> bool {
+        self.tokens.contains_key(&node)
+    }
+
+    fn nodes(&self) -> Vec<SocketAddr> {
+        self.tokens.keys().copied().collect()
+    }
+
+    fn replicas(&self) -> usize {
+        self.repl
+    }
+
+    /// The range's owners, then everyone else in address order.
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]> {
+        let owners = &self.ranges[self.index(key)].owners;
+        let rest = self.tokens.keys().filter(|node| !owners.contains(node));
+        owners.iter().chain(rest).copied().take(len).collect()
+    }
+
+    /// By range count, since how many keys land in each depends on the keys.
+    fn shares(&self) -> BTreeMap<SocketAddr, Share> {
+        let mut shares = BTreeMap::new();
+        let width = 1.0 / self.ranges.len() as f64;
+        for range in &self.ranges {
+            for (rank, &node) in range.owners.iter().enumerate() {
+                let share: &mut Share = shares.entry(node).or_default();
+                if rank == 0 {
+                    share.primary += width;
+                }
+                share.replica += width;
+            }
+        }
+        shares
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement::DEFAULT_TOKENS;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn loads(map: &RangeMap) -> Vec<usize> {
+        map.nodes().into_iter().map(|node| map.load(node)).collect()
+    }
+
+    #[test]
+    fn keys_go_to_the_range_they_sort_into() {
+        let mut map = RangeMap::new(1);
+        map.add_node(addr(50052), DEFAULT_TOKENS);
+        assert!(map.split("m"));
+        assert!(map.split("t"));
+        assert!(!map.split("m"));
+        assert_eq!(map.bounds("a"), ("", Some("m")));
+        assert_eq!(map.bounds("m"), ("m", Some("t")));
+        assert_eq!(map.bounds("moose"), ("m", Some("t")));
+        assert_eq!(map.bounds("zebra"), ("t", None));
+        assert_eq!(map.bounds(&after("l")), ("", Some("m")));
+        // Splits keep the owners, so no keys have to move.
+        assert!(map
+            .ranges()
+            .iter()
+            .all(|range| range.owners == [addr(50052)]));
+    }
+
+    #[test]
+    fn nodes_get_their_share_of_ranges() {
+        let mut map = RangeMap::new(2);
+        map.add_node(addr(50052), DEFAULT_TOKENS);
+        map.add_node(addr(50053), DEFAULT_TOKENS);
+        for at in ["b", "d", "f", "h", "j", "l", "n"] {
+            map.split(at);
+        }
+        // Everything's on the first two until someone else shows up.
+        assert_eq!(loads(&map), [8, 8]);
+
+        map.add_node(addr(50054), DEFAULT_TOKENS);
+        map.add_node(addr(50055), DEFAULT_TOKENS);
+        assert_eq!(loads(&map), [4, 4, 4, 4]);
+        for range in map.ranges() {
+            assert_eq!(range.owners.len(), 2);
+            assert_ne!(range.owners[0], range.owners[1]);
+        }
+
+        map.remove_node(addr(50052));
+        assert!(!map.contains(addr(50052)));
+        let loads = loads(&map);
+        assert_eq!(loads.iter().sum::<usize>(), 16);
+        assert!(
+            loads.iter().all(|&load| (5..=6).contains(&load)),
+            "{loads:?}"
+        );
+        assert!(map
+            .ranges()
+            .iter()
+            .all(|range| !range.owners.contains(&addr(50052))));
+    }
+
+    #[test]
+    fn split_ranges_spread_out() {
+        let mut map = RangeMap::new(1);
+        for port in 50052..50055 {
+            map.add_node(addr(port), DEFAULT_TOKENS);
+        }
+        assert_eq!(map.ranges()[0].owners, [addr(50052)]);
+        map.split("m");
+        assert!(map.spread("m"));
+        assert_eq!(map.bounds("m"), ("m", None));
+        assert_eq!(map.ranges()[1].owners, [addr(50053)]);
+        // Nothing to gain from moving it again.
+        assert!(!map.spread("m"));
+    }
+}
@@ -0,0 +1,135 @@
+//! Rendezvous (highest random weight) hashing. Every node scores every key, and a key belongs to
+//! the nodes with the highest scores. There's nothing to keep besides the nodes themselves, and a
+//! node coming or going only moves the keys it wins or loses, but every lookup scores every node.
+
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::placement::{HashFn, Placement};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Rendezvous {
+    /// Every node and its weight.
+    nodes: BTreeMap<SocketAddr, usize>,
+    /// Number of distinct nodes each key is written to.
+    repl: usize,
+    hash: HashFn,
+}
+
+impl Rendezvous {
+    pub fn new(repl: usize, hash: HashFn) -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            repl,
+            hash,
+        }
+    }
+
+    /// How much `node` wants the key at the front of `buf`. Uses the logarithmic method, so a
+    /// node's share of the keys grows in proportion to its weight.
+    fn score(&self, buf: &mut Vec<u8>, key_len: usize, node: SocketAddr, tokens: usize) -> f64 {
+        // The key, then the node's address in network order. Formatting the address as a string
+        // would be most of the work.
+        buf.truncate(key_len);
+        match node {
+            SocketAddr::V4(addr) => buf.extend(addr.ip().octets()),
+            SocketAddr::V6(addr) => buf.extend(addr.ip().octets()),
+        }
+        buf.extend(node.port().to_be_bytes());
+        let hash = self.hash.hash(buf);
+        // Top 53 bits as a float strictly between 0 and 1, so the log is finite and negative.
+        let uniform = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        tokens as f64 / -uniform.ln()
+    }
+}
+
+/// Tokens are weights: a node with twice as many wins about twice the keys.
+impl Placement for Rendezvous {
+    fn add_node(&mut self, node: SocketAddr, tokens: usize) {
+        self.nodes.insert(node, tokens);
+    }
+
+    fn remove_node(&mut self, node: SocketAddr) {
+        self.nodes.remove(&node);
+    }
+
+    fn tokens(&self, node: SocketAddr) -> usize {
+        self.nodes.get(&node).copied().unwrap_or(0)
+    }
+
+    fn contains(&self, node: SocketAddr) -> bool {
+        self.nodes.contains_key(&node)
+    }
+
+    fn nodes(&self) -> Vec<SocketAddr> {
+        self.nodes.keys().copied().collect()
+    }
+
+    fn replicas(&self) -> usize {
+        self.repl
+    }
+
+    /// Highest score first.
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]> {
+        let mut buf = Vec::with_capacity(key.len() + 18);
+        buf.extend(key.as_bytes());
+        let mut scored: SmallVec<[(f64, SocketAddr); 16]> = self
+            .nodes
+            .iter()
+            .map(|(&node, &tokens)| (self.score(&mut buf, key.len(), node, tokens), node))
+            .collect();
+        // Ties are all but impossible, but break them by address so everyone agrees anyway.
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        scored.into_iter().take(len).map(|(_, node)| node).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn heavier_nodes_win_more_keys() {
+        let mut hrw = Rendezvous::new(1, HashFn::Xxh3);
+        hrw.add_node(addr(50052), 64);
+        hrw.add_node(addr(50053), 64);
+        hrw.add_node(addr(50054), 256);
+        let shares = hrw.shares();
+        // Two thirds of the weight, so about two thirds of the keys.
+        assert!(
+            (0.6..0.73).contains(&shares[&addr(50054)].primary),
+            "{shares:?}"
+        );
+
+        // Taking it back out hands its keys to the others and moves nothing else.
+        let before: Vec<_> = (0..1000)
+            .map(|i| hrw.write_group(&format!("key_{i}"))[0])
+            .collect();
+        hrw.remove_node(addr(50054));
+        for (i, old) in before.into_iter().enumerate() {
+            let new = hrw.write_group(&format!("key_{i}"))[0];
+            assert!(old == addr(50054) || new == old);
+        }
+    }
+
+    #[test]
+    fn owners_are_pinned() {
+        let mut hrw = Rendezvous::new(2, HashFn::Sha1);
+        for port in (50052..50056).rev() {
+            hrw.add_node(addr(port), 8);
+        }
+        for (key, ports) in [
+            ("a", [50052, 50053]),
+            ("b", [50054, 50052]),
+            ("jajaja", [50055, 50052]),
+            ("zzzzzzzzzz", [50055, 50054]),
+        ] {
+            assert_eq!(hrw.write_group(key)[..], ports.map(addr), "moved '{key}'");
+        }
+    }
+}
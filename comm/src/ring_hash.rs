@@ -0,0 +1,301 @@
+//! Consistent hashing. Keys and virtual nodes are hashed to 64-bit positions on a ring, and a key
+//! belongs to the first `repl` distinct nodes clockwise from it. Positions are read big-endian and
+//! virtual nodes are named after their node's address, so every process on every platform puts
+//! everything in the same place no matter what order nodes were added in.
+
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::Bound::{Excluded, Unbounded},
+};
+
+use crate::placement::{HashFn, Placement, Share};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RingHash {
+    /// Relates positions on the ring to the nodes whose virtual nodes sit there.
+    ring_hash: BTreeMap<u64, SocketAddr>,
+    /// Number of distinct nodes each key is written to.
+    repl: usize,
+    /// Virtual nodes each node has on the ring. A node's share of the keys grows with its count,
+    /// so bigger machines get more.
+    tokens: BTreeMap<SocketAddr, usize>,
+    hash: HashFn,
+}
+
+impl RingHash {
+    pub fn new(repl: usize) -> Self {
+        Self::with_hash(repl, HashFn::default())
+    }
+
+    pub fn with_hash(repl: usize, hash: HashFn) -> Self {
+        Self {
+            ring_hash: BTreeMap::new(),
+            repl,
+            tokens: BTreeMap::new(),
+            hash,
+        }
+    }
+
+    pub fn hash_fn(&self) -> HashFn {
+        self.hash
+    }
+
+    /// Where `key` sits on the ring.
+    pub fn position(&self, key: &str) -> u64 {
+        self.hash.hash(key.as_bytes())
+    }
+
+    fn token_position(&self, node: SocketAddr, token: usize) -> u64 {
+        self.hash.hash(format!("{node}#{token}").as_bytes())
+    }
+}
+
+/// Tokens are virtual nodes: a node with twice as many gets about twice the keys.
+impl Placement for RingHash {
+    fn add_node(&mut self, node: SocketAddr, tokens: usize) {
+        self.remove_node(node);
+        for i in 0..tokens {
+            self.ring_hash.insert(self.token_position(node, i), node);
+        }
+        self.tokens.insert(node, tokens);
+    }
+
+    fn remove_node(&mut self, node: SocketAddr) {
+        for i in 0..self.tokens.remove(&node).unwrap_or(0) {
+            let position = self.token_position(node, i);
+            // On the off chance two virtual nodes collide, don't take the other one with us.
+            if self.ring_hash.get(&position) == Some(&node) {
+                self.ring_hash.remove(&position);
+            }
+        }
+    }
+
+    fn tokens(&self, node: SocketAddr) -> usize {
+        self.tokens.get(&node).copied().unwrap_or(0)
+    }
+
+    fn contains(&self, node: SocketAddr) -> bool {
+        self.tokens.contains_key(&node)
+    }
+
+    fn nodes(&self) -> Vec<SocketAddr> {
+        self.tokens.keys().copied().collect()
+    }
+
+    fn replicas(&self) -> usize {
+        self.repl
+    }
+
+    /// Exact, unlike the default: each arc between virtual nodes is measured.
+    fn shares(&self) -> BTreeMap<SocketAddr, Share> {
+        let mut shares = BTreeMap::new();
+        let positions: Vec<_> = self.ring_hash.iter().map(|(&h, &n)| (h, n)).collect();
+        let whole = u64::MAX as f64 + 1.0;
+        for (i, &(hash, _)) in positions.iter().enumerate() {
+            // Keys hashing anywhere from the previous virtual node up to this one land here.
+            let width = match i {
+                0 => hash.wrapping_sub(positions[positions.len() - 1].0),
+                _ => hash - positions[i - 1].0,
+            };
+            let width = match (width, positions.len()) {
+                // A lone virtual node gets the whole ring, which doesn't fit in a u64.
+                (0, 1) => whole,
+                (width, _) => width as f64,
+            } / whole;
+
+            let mut group: SmallVec<[SocketAddr; 16]> = SmallVec::new();
+            for &(_, node) in positions[i..].iter().chain(&positions[..i]) {
+                if group.len() == self.repl {
+                    break;
+                }
+                if !group.contains(&node) {
+                    group.push(node);
+                }
+            }
+            for (rank, node) in group.into_iter().enumerate() {
+                let share: &mut Share = shares.entry(node).or_default();
+                if rank == 0 {
+                    share.primary += width;
+                }
+                share.replica += width;
+            }
+        }
+        shares
+    }
+
+    /// Walks clockwise from `key`.
+    fn preference_list(&self, key: &str, len: usize) -> SmallVec<[SocketAddr; 16]> {
+        let key_hash = self.position(key);
+
+        // self.repl is small so `contains` on a `Vec` will be much faster than hashing.
+        // This might actually be a good case for SmallVec, no reason this can't live on the stack.
+        let mut group = SmallVec::with_capacity(len);
+        // Walk clockwise from the key, wrapping around once. One full lap visits every node, so
+        // if there are fewer nodes than that we just stop early.
+        let nodes_iter = self
+            .ring_hash
+            .range((Excluded(key_hash), Unbounded))
+            .chain(self.ring_hash.iter());
+        // It might be a little expensive to remove a node from the ring every time it goes down.
+        for (_, node) in nodes_iter {
+            if group.len() == len {
+                break;
+            }
+            if !group.contains(node) {
+                group.push(*node);
+            }
+        }
+
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement::DEFAULT_TOKENS;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn golden_ring(hash: HashFn) -> RingHash {
+        let mut ring = RingHash::with_hash(2, hash);
+        // Added backwards on purpose: where nodes land can't depend on the order they joined in.
+        for port in (50052..50056).rev() {
+            ring.add_node(addr(port), 8);
+        }
+        ring
+    }
+
+    #[test]
+    fn write_group_is_distinct_nodes() {
+        let mut ring = RingHash::new(3);
+        for port in 50052..50057 {
+            ring.add_node(addr(port), DEFAULT_TOKENS);
+        }
+        for key in ["a", "b", "jajaja", "zzzzzzzzzz"] {
+            let group = ring.write_group(key);
+            assert_eq!(group.len(), 3);
+            for (i, node) in group.iter().enumerate() {
+                assert!(!group[i + 1..].contains(node));
+            }
+        }
+    }
+
+    #[test]
+    fn write_group_with_fewer_nodes_than_replicas() {
+        let mut ring = RingHash::new(3);
+        assert!(ring.write_group("anything").is_empty());
+        ring.add_node(addr(50052), DEFAULT_TOKENS);
+        ring.add_node(addr(50053), DEFAULT_TOKENS);
+        // Try enough keys that some hash past the last virtual node and have to wrap around.
+        for i in 0..64 {
+            let mut group = ring.write_group(&format!("key_{i}")).into_vec();
+            group.sort();
+            assert_eq!(group, [addr(50052), addr(50053)]);
+        }
+    }
+
+    #[test]
+    fn bigger_nodes_get_more_keys() {
+        for hash in [HashFn::Sha1, HashFn::Xxh3] {
+            let mut ring = RingHash::with_hash(2, hash);
+            ring.add_node(addr(50052), 64);
+            ring.add_node(addr(50053), 64);
+            ring.add_node(addr(50054), 256);
+            assert_eq!(ring.tokens(addr(50054)), 256);
+
+            let shares = ring.shares();
+            let total = |f: fn(&Share) -> f64| shares.values().map(f).sum::<f64>();
+            assert!((total(|s| s.primary) - 1.0).abs() < 1e-9);
+            assert!((total(|s| s.replica) - 2.0).abs() < 1e-9);
+            // Two thirds of the tokens, so about two thirds of the keys it's first in line for.
+            assert!(shares[&addr(50054)].primary > 0.55, "{hash}: {shares:?}");
+            assert!(
+                shares[&addr(50052)].primary < 0.25 && shares[&addr(50053)].primary < 0.25,
+                "{hash}: {shares:?}"
+            );
+
+            let owned = (0..1000)
+                .filter(|i| ring.write_group(&format!("key_{i}"))[0] == addr(50054))
+                .count();
+            assert!(owned > 550, "{hash}: only owned {owned} keys");
+        }
+    }
+
+    #[test]
+    fn removing_a_node_takes_all_its_tokens() {
+        let mut ring = RingHash::new(2);
+        ring.add_node(addr(50052), 8);
+        ring.add_node(addr(50053), 100);
+        ring.remove_node(addr(50053));
+        assert_eq!(ring.tokens(addr(50053)), 0);
+        assert!(!ring.contains(addr(50053)));
+        assert_eq!(ring.shares()[&addr(50052)].primary, 1.0);
+    }
+
+    #[test]
+    fn preference_list_extends_the_write_group() {
+        let mut ring = RingHash::new(2);
+        let nodes: Vec<_> = (50052..50057).map(addr).collect();
+        for &node in &nodes {
+            ring.add_node(node, DEFAULT_TOKENS);
+        }
+        for key in ["a", "b", "jajaja", "zzzzzzzzzz"] {
+            let list = ring.preference_list(key, 10);
+            assert_eq!(list[..2], ring.write_group(key)[..]);
+            let mut sorted = list.into_vec();
+            sorted.sort();
+            assert_eq!(sorted, nodes);
+        }
+    }
+
+    #[test]
+    fn owners_are_pinned() {
+        let expect = |hash, owners: [(&str, [u16; 2]); 6]| {
+            let ring = golden_ring(hash);
+            for (key, ports) in owners {
+                assert_eq!(
+                    ring.write_group(key)[..],
+                    ports.map(addr),
+                    "{hash} moved '{key}'"
+                );
+            }
+        };
+        expect(
+            HashFn::Sha1,
+            [
+                ("a", [50053, 50055]),
+                ("b", [50052, 50053]),
+                ("jajaja", [50054, 50055]),
+                ("zzzzzzzzzz", [50054, 50055]),
+                ("key_0", [50052, 50053]),
+                ("key_1", [50053, 50054]),
+            ],
+        );
+        expect(
+            HashFn::Xxh3,
+            [
+                ("a", [50053, 50052]),
+                ("b", [50053, 50052]),
+                ("jajaja", [50052, 50053]),
+                ("zzzzzzzzzz", [50053, 50054]),
+                ("key_0", [50054, 50053]),
+                ("key_1", [50055, 50052]),
+            ],
+        );
+    }
+
+    #[test]
+    fn hash_survives_the_wire() {
+        let ring = golden_ring(HashFn::Xxh3);
+        let bytes = rmp_serde::to_vec(&ring).unwrap();
+        let ring_prime: RingHash = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(ring_prime.hash_fn(), HashFn::Xxh3);
+        assert_eq!(ring, ring_prime);
+    }
+}
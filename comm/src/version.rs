@@ -0,0 +1,213 @@
+//! Stored values as they travel between replicas, along with enough version information to tell
+//! which copies supersede which.
+//!
+//! Every write gets a [`Dot`]: the ID of the node that coordinated it plus that node's counter.
+//! It also remembers the context the writer had read, i.e. every dot it knew about. A write makes
+//! whatever its context covers obsolete, and anything it didn't know about sticks around as a
+//! sibling until someone writes with a context that covers both. These are dotted version vectors
+//! as in the Dynamo and Riak papers. Plain vector clocks can't tell two writes through the same
+//! coordinator apart, so one of them would quietly vanish.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// How many writes each storage node has coordinated, keyed by node ID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Whether this clock has seen the write `dot`.
+    pub fn covers(&self, dot: &Dot) -> bool {
+        self.get(&dot.node) >= dot.counter
+    }
+
+    pub fn add(&mut self, dot: &Dot) {
+        let counter = self.0.entry(dot.node.clone()).or_default();
+        *counter = (*counter).max(dot.counter);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (node, &counter) in &other.0 {
+            let ours = self.0.entry(node.clone()).or_default();
+            *ours = (*ours).max(counter);
+        }
+    }
+
+    /// Whether this clock has seen everything `other` has.
+    pub fn descends(&self, other: &Self) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(node, &counter)| self.get(node) >= counter)
+    }
+}
+
+/// Identifies a single write: the node that coordinated it, and a counter that goes up with every
+/// write that node coordinates.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot {
+    pub node: String,
+    pub counter: u64,
+}
+
+/// One version of a key.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Sibling {
+    pub dot: Dot,
+    /// What the writer had seen when it wrote this. Any sibling whose dot is in here is obsolete.
+    pub context: VectorClock,
+    /// `None` means the key was deleted. The tombstone sticks around for a grace period so that
+    /// an older copy of the value (from a stale replica, or an old snapshot) can't bring the key
+    /// back from the dead.
+    pub value: Option<String>,
+    /// Milliseconds since the Unix epoch when this version was written. Only used to decide when
+    /// a tombstone is old enough to forget.
+    pub timestamp: u64,
+}
+
+/// Every version of a key that hasn't been superseded yet. Usually just one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    pub siblings: Vec<Sibling>,
+}
+
+impl Entry {
+    /// Context to hand out with a read: every write these siblings know about, themselves
+    /// included. Writing with it supersedes all of them.
+    pub fn context(&self) -> VectorClock {
+        let mut clock = VectorClock::default();
+        for sibling in &self.siblings {
+            clock.merge(&sibling.context);
+            clock.add(&sibling.dot);
+        }
+        clock
+    }
+
+    /// The live values, tombstones left out.
+    pub fn values(&self) -> Vec<String> {
+        self.siblings
+            .iter()
+            .filter_map(|sibling| sibling.value.clone())
+            .collect()
+    }
+
+    /// Whether every surviving version is a delete.
+    pub fn is_deleted(&self) -> bool {
+        self.siblings.iter().all(|sibling| sibling.value.is_none())
+    }
+
+    /// When the newest version was written.
+    pub fn timestamp(&self) -> u64 {
+        self.siblings
+            .iter()
+            .map(|sibling| sibling.timestamp)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The oldest dot the next write `node` coordinates could have, given the context the writer
+    /// read. Anything older would look like a write that's already been superseded. It's only a
+    /// lower bound: two writes racing through `node` both see the same one, so the node has to
+    /// hand out counters past it itself.
+    pub fn next_dot(&self, node: &str, context: &VectorClock) -> Dot {
+        Dot {
+            node: node.to_owned(),
+            counter: self.context().get(node).max(context.get(node)) + 1,
+        }
+    }
+
+    /// Folds in another replica's versions, dropping whatever is now obsolete. Returns whether
+    /// anything changed.
+    pub fn merge(&mut self, other: Entry) -> bool {
+        let before: Vec<Dot> = self.siblings.iter().map(|s| s.dot.clone()).collect();
+        for sibling in other.siblings {
+            if !self.siblings.iter().any(|ours| ours.dot == sibling.dot) {
+                self.siblings.push(sibling);
+            }
+        }
+
+        let all = std::mem::take(&mut self.siblings);
+        self.siblings = all
+            .iter()
+            .filter(|sibling| !all.iter().any(|other| other.context.covers(&sibling.dot)))
+            .cloned()
+            .collect();
+        // Keep a stable order so replicas that agree look identical.
+        self.siblings.sort_by(|a, b| a.dot.cmp(&b.dot));
+
+        self.siblings.len() != before.len()
+            || self
+                .siblings
+                .iter()
+                .zip(&before)
+                .any(|(s, dot)| s.dot != *dot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(entry: &Entry, node: &str, context: &VectorClock, value: Option<&str>) -> Entry {
+        Entry {
+            siblings: vec![Sibling {
+                dot: entry.next_dot(node, context),
+                context: context.clone(),
+                value: value.map(Into::into),
+                timestamp: 0,
+            }],
+        }
+    }
+
+    #[test]
+    fn writes_that_saw_each_other_replace_each_other() {
+        let mut entry = Entry::default();
+        assert!(entry.merge(write(&entry, "a", &VectorClock::default(), Some("1"))));
+        let read = entry.context();
+        assert!(entry.merge(write(&entry, "b", &read, Some("2"))));
+        assert_eq!(entry.values(), ["2"]);
+        assert!(entry.context().descends(&read));
+
+        let read = entry.context();
+        assert!(entry.merge(write(&entry, "a", &read, None)));
+        assert!(entry.is_deleted());
+    }
+
+    #[test]
+    fn concurrent_writes_become_siblings_until_resolved() {
+        let mut a = Entry::default();
+        a.merge(write(&a, "a", &VectorClock::default(), Some("base")));
+        let mut b = a.clone();
+        let read = a.context();
+
+        // Two clients read the same thing, then write through different nodes.
+        a.merge(write(&a, "a", &read, Some("left")));
+        b.merge(write(&b, "b", &read, Some("right")));
+        assert!(a.merge(b.clone()));
+        assert!(b.merge(a.clone()));
+        assert_eq!(a, b);
+        assert_eq!(a.values(), ["left", "right"]);
+
+        // Writing with the merged context settles it.
+        let read = a.context();
+        a.merge(write(&a, "b", &read, Some("both")));
+        assert_eq!(a.values(), ["both"]);
+        // Stale replicas can't bring the old siblings back.
+        assert!(!a.merge(b));
+        assert_eq!(a.values(), ["both"]);
+    }
+
+    #[test]
+    fn racing_writes_through_one_node_both_survive() {
+        let mut entry = Entry::default();
+        let read = entry.context();
+        entry.merge(write(&entry, "a", &read, Some("x")));
+        entry.merge(write(&entry, "a", &read, Some("y")));
+        assert_eq!(entry.values(), ["x", "y"]);
+    }
+}
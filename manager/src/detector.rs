@@ -0,0 +1,249 @@
+//! Ways of deciding a storage node is gone, given when its heartbeats have been arriving.
+//!
+//! [`Timeout`] gives up after a fixed stretch of silence. [`PhiAccrual`] learns how regular each
+//! node usually is and only gives up once the silence would be unusual for that node, so a GC
+//! pause or a busy machine doesn't have nodes flapping in and out of the ring. See Hayashibara et
+//! al., "The φ Accrual Failure Detector".
+
+use clap::ValueEnum;
+use comm::Health;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+pub trait FailureDetector: Send {
+    /// The node answered a heartbeat at `now`.
+    fn heard(&mut self, now: Instant);
+
+    /// The node answered at `now` after we'd given up on it. How long it was gone says nothing
+    /// about how often it usually answers, so it shouldn't count as a normal gap.
+    fn revived(&mut self, now: Instant) {
+        self.heard(now);
+    }
+
+    /// What we make of the node at `now`, given it just missed a heartbeat.
+    fn after_miss(&self, now: Instant) -> Health;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DetectorKind {
+    /// Suspect a node as soon as it misses a heartbeat; mark it down after a fixed timeout.
+    Timeout,
+    /// Mark a node down once its silence is unlikely given how its heartbeats usually arrive.
+    Phi,
+}
+
+/// Everything needed to give a new node its own detector.
+#[derive(Debug, Clone, Copy)]
+pub struct DetectorConfig {
+    pub kind: DetectorKind,
+    /// How often heartbeats go out.
+    pub interval: Duration,
+    /// For [`Timeout`].
+    pub timeout: Duration,
+    /// For [`PhiAccrual`].
+    pub phi_threshold: f64,
+}
+
+impl DetectorConfig {
+    /// A detector for a node we last heard from at `now`.
+    pub fn build(&self, now: Instant) -> Box<dyn FailureDetector> {
+        match self.kind {
+            DetectorKind::Timeout => Box::new(Timeout::new(now, self.timeout)),
+            DetectorKind::Phi => Box::new(PhiAccrual::new(now, self.interval, self.phi_threshold)),
+        }
+    }
+}
+
+/// Suspect on the first miss, dead after `timeout` without an answer.
+pub struct Timeout {
+    last_heard: Instant,
+    timeout: Duration,
+}
+
+impl Timeout {
+    pub fn new(now: Instant, timeout: Duration) -> Self {
+        Self {
+            last_heard: now,
+            timeout,
+        }
+    }
+}
+
+impl FailureDetector for Timeout {
+    fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+    }
+
+    fn after_miss(&self, now: Instant) -> Health {
+        if now.duration_since(self.last_heard) >= self.timeout {
+            Health::Dead
+        } else {
+            Health::Suspect
+        }
+    }
+}
+
+/// How many of the latest gaps between heartbeats to learn from.
+const WINDOW: usize = 100;
+
+/// Below this φ a missed heartbeat is just a slow one. φ = 1 means there's a 10% chance the next
+/// heartbeat is still on its way.
+const SUSPECT_PHI: f64 = 1.0;
+
+/// Keeps the gaps between a node's heartbeats and works out φ, how confident we'd be that it's
+/// gone: φ = -log10 of the chance a heartbeat shows up after this much silence, with gaps assumed
+/// to be normally distributed.
+pub struct PhiAccrual {
+    last_heard: Instant,
+    /// Milliseconds between consecutive heartbeats, oldest first.
+    gaps: VecDeque<f64>,
+    /// φ at which the node is marked down.
+    threshold: f64,
+    /// Extra silence we always put up with, on top of what the node's history says. Without it a
+    /// perfectly regular node would be marked down the moment it was a little late.
+    acceptable_pause: f64,
+    /// Floor for the standard deviation, for the same reason.
+    min_std_dev: f64,
+}
+
+impl PhiAccrual {
+    /// `expected` is how often heartbeats are sent. We start off assuming they arrive about that
+    /// often and let the node's actual history take over from there.
+    pub fn new(now: Instant, expected: Duration, threshold: f64) -> Self {
+        let expected = expected.as_secs_f64() * 1000.0;
+        Self {
+            last_heard: now,
+            // Two made-up samples, a quarter off either side, so we start with a sensible spread.
+            gaps: VecDeque::from([expected * 0.75, expected * 1.25]),
+            threshold,
+            acceptable_pause: expected,
+            min_std_dev: expected / 4.0,
+        }
+    }
+
+    pub fn phi(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_heard).as_secs_f64() * 1000.0;
+        let n = self.gaps.len() as f64;
+        let mean = self.gaps.iter().sum::<f64>() / n;
+        let variance = self
+            .gaps
+            .iter()
+            .map(|gap| (gap - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(self.min_std_dev);
+
+        // Logistic approximation of the normal CDF, which is accurate enough and doesn't need erf.
+        let y = (elapsed - (mean + self.acceptable_pause)) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if y > 0.0 {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+impl FailureDetector for PhiAccrual {
+    fn heard(&mut self, now: Instant) {
+        let gap = now.duration_since(self.last_heard).as_secs_f64() * 1000.0;
+        self.last_heard = now;
+        if self.gaps.len() == WINDOW {
+            self.gaps.pop_front();
+        }
+        self.gaps.push_back(gap);
+    }
+
+    fn revived(&mut self, now: Instant) {
+        self.last_heard = now;
+    }
+
+    fn after_miss(&self, now: Instant) -> Health {
+        let phi = self.phi(now);
+        if phi >= self.threshold {
+            Health::Dead
+        } else if phi >= SUSPECT_PHI {
+            Health::Suspect
+        } else {
+            Health::Alive
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// A detector that's heard `count` heartbeats, `gap(i)` apart. Returns it and the time of the
+    /// last one.
+    fn trained(count: u64, gap: impl Fn(u64) -> u64) -> (PhiAccrual, Instant) {
+        let start = Instant::now();
+        let mut detector = PhiAccrual::new(start, ms(1000), 8.0);
+        let mut now = start;
+        for i in 0..count {
+            now += ms(gap(i));
+            detector.heard(now);
+        }
+        (detector, now)
+    }
+
+    #[test]
+    fn phi_grows_with_silence() {
+        let (detector, last) = trained(50, |_| 1000);
+        let phis: Vec<f64> = [0, 1000, 2000, 3000, 4000]
+            .into_iter()
+            .map(|after| detector.phi(last + ms(after)))
+            .collect();
+        assert!(phis.windows(2).all(|pair| pair[0] <= pair[1]), "{phis:?}");
+
+        // One missed beat is forgiven, two is worrying, three is fatal.
+        assert_eq!(detector.after_miss(last + ms(2000)), Health::Alive);
+        assert_eq!(detector.after_miss(last + ms(3000)), Health::Suspect);
+        assert_eq!(detector.after_miss(last + ms(4000)), Health::Dead);
+    }
+
+    #[test]
+    fn jittery_nodes_get_more_slack() {
+        let (steady, steady_last) = trained(50, |_| 1000);
+        // Same average rate, but swinging between half a second and a second and a half.
+        let (jittery, jittery_last) = trained(50, |i| if i % 2 == 0 { 500 } else { 1500 });
+
+        let silence = ms(3500);
+        assert!(jittery.phi(jittery_last + silence) < steady.phi(steady_last + silence));
+        assert_eq!(steady.after_miss(steady_last + silence), Health::Dead);
+        assert_ne!(jittery.after_miss(jittery_last + silence), Health::Dead);
+    }
+
+    #[test]
+    fn only_the_latest_gaps_count() {
+        // A node that used to be slow and has since sped up shouldn't get slack for its past.
+        let (detector, last) = trained(WINDOW as u64 + 50, |i| if i < 50 { 5000 } else { 1000 });
+        assert!(detector.gaps.iter().all(|&gap| gap == 1000.0));
+        assert_eq!(detector.after_miss(last + ms(4000)), Health::Dead);
+    }
+
+    #[test]
+    fn outages_dont_count_as_gaps() {
+        let (mut detector, last) = trained(50, |_| 1000);
+        let before = detector.gaps.clone();
+        detector.revived(last + ms(60_000));
+        assert_eq!(detector.gaps, before);
+        assert_eq!(detector.after_miss(last + ms(64_000)), Health::Dead);
+    }
+
+    #[test]
+    fn timeout_counts_from_the_last_answer() {
+        let start = Instant::now();
+        let mut detector = Timeout::new(start, ms(5000));
+        assert_eq!(detector.after_miss(start + ms(4999)), Health::Suspect);
+        assert_eq!(detector.after_miss(start + ms(5000)), Health::Dead);
+        detector.heard(start + ms(5000));
+        assert_eq!(detector.after_miss(start + ms(6000)), Health::Suspect);
+    }
+}
@@ -1,35 +1,1062 @@
 use clap::Parser;
-use ring_hash::RingHash;
-use std::{net::SocketAddr, sync::LazyLock};
-use tokio::net::TcpStream;
+use comm::{
+    handshake,
+    mux::{self, Connection},
+    placement::{HashFn, Placement, Strategy, StrategyKind, DEFAULT_TOKENS},
+    pool::Pool,
+    raft::Command,
+    range_map::RangeSize,
+    Consistency, Envelope, Epoch, ErrorCode, Health, Message, PartitionMap, Quorum, Rebalance,
+    Role,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::timeout,
+};
 
-mod ring_hash;
+use detector::{DetectorConfig, DetectorKind, FailureDetector};
+use raft::{HardState, Outgoing, Raft};
 
+mod detector;
+mod raft;
+
+/// How long another manager gets to answer a Raft message. Well under the election timeout, so
+/// one that's gone can't hold anything up.
+const RAFT_RPC_TIMEOUT: Duration = Duration::from_millis(200);
+/// How often the state loop checks Raft's timers.
+const RAFT_TICK: Duration = Duration::from_millis(20);
+/// How often the leader asks how a rebalance is going.
+const HANDOFF_POLL_PERIOD: Duration = Duration::from_secs(1);
+/// How often the leader of a range-partitioned cluster asks how big the ranges have gotten.
+const SPLIT_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Managers replicate the partition map between themselves with Raft (see [`raft`]), so any of
+/// them can take over if the leader goes down. Only the leader checks on storage nodes, publishes
+/// maps, and answers clients; the rest point clients its way.
 struct Manager {
+    /// What every manager has agreed the cluster looks like. Only ever changed by applying
+    /// committed log entries, and its epoch only goes up when it does.
+    map: PartitionMap,
+    /// Our connection to and opinion of each storage node. Only the leader's opinion makes it into
+    /// `map`, by way of the log.
     nodes: Vec<Node>,
-    ring_hash: RingHash,
-    reps: usize,
+    raft: Raft,
+    /// Whether we were leading last time we looked, to notice when that changes.
+    leading: bool,
+    /// Where to keep Raft's state across restarts. Unset means it only lives in memory.
+    raft_file: Option<PathBuf>,
+    /// Connections to the other managers.
+    peers: Arc<Pool>,
+    /// Where the other managers' answers get sent back to.
+    state_tx: mpsc::Sender<ManagerCmd>,
+    /// What to start the log with if we end up leading before anyone else has.
+    bootstrap: PartitionMap,
+    detector: DetectorConfig,
+    /// Health changes we've put in the log but haven't seen applied yet, so we don't keep
+    /// proposing them every round.
+    proposed: HashMap<SocketAddr, Health>,
+    /// We've put a ring change in the log that hasn't been applied yet. Only one at a time.
+    changing_ring: bool,
+    /// How far each node said it was with the pending rebalance last time we asked.
+    handoff: HashMap<SocketAddr, (u32, u32)>,
+    /// When any node last got further with the pending rebalance.
+    handoff_moved: Option<Instant>,
+    /// How long a rebalance can go without anyone getting further before it's called off.
+    rebalance_timeout: Duration,
+    /// Virtual nodes to give joining nodes that don't ask for a number.
+    tokens: usize,
+    /// Live keys a range can hold before it gets split.
+    max_range_keys: u64,
+}
+
+impl Manager {
+    /// Pushes the current partition map to every storage node we can reach. Doesn't wait for
+    /// them to acknowledge it. A node that misses the update still serves clients routing with
+    /// the newer map; it just can't turn away stale ones until it hears from us.
+    fn publish(&self) {
+        for node in &self.nodes {
+            if let Some(conn) = node.conn.clone().filter(|conn| !conn.is_closed()) {
+                send_map(conn, self.map.clone());
+            }
+        }
+    }
+
+    async fn handle(&mut self, cmd: ManagerCmd) {
+        match cmd {
+            ManagerCmd::Owners { key, reply } => {
+                let _ = reply.send(self.leading().map(|map| map.owners(&key)));
+            }
+            ManagerCmd::PartitionMap { reply } => {
+                let _ = reply.send(self.leading().cloned());
+            }
+            ManagerCmd::HeartbeatTargets { reply } => {
+                let targets = match self.raft.is_leader() {
+                    true => self
+                        .nodes
+                        .iter()
+                        .map(|node| Target {
+                            addr: node.addr,
+                            conn: node.conn.clone(),
+                        })
+                        .collect(),
+                    false => Vec::new(),
+                };
+                let _ = reply.send(targets);
+            }
+            ManagerCmd::Checkups { checkups } => {
+                self.record_checkups(checkups, Instant::now()).await;
+            }
+            ManagerCmd::Join {
+                node,
+                tokens,
+                reply,
+            } => {
+                let _ = reply.send(self.join(node, tokens).await);
+            }
+            ManagerCmd::Decommission { node, reply } => {
+                let _ = reply.send(self.decommission(node).await);
+            }
+            ManagerCmd::HandoffTargets { reply } => {
+                let targets = match (self.leading(), &self.map.pending) {
+                    (Ok(_), Some(pending)) => {
+                        let targets = self
+                            .nodes
+                            .iter()
+                            .filter(|node| pending.ring.contains(node.addr))
+                            .map(|node| Target {
+                                addr: node.addr,
+                                conn: node.conn.clone(),
+                            })
+                            .collect();
+                        Some((pending.since, targets))
+                    }
+                    _ => None,
+                };
+                let _ = reply.send(targets);
+            }
+            ManagerCmd::HandoffProgress { since, progress } => {
+                self.record_handoff(since, progress, Instant::now()).await;
+            }
+            ManagerCmd::SplitTargets { reply } => {
+                let targets = match (self.leading(), self.map.ring.as_ranges()) {
+                    (Ok(map), Some(_)) if map.pending.is_none() && !self.changing_ring => {
+                        let targets = self
+                            .nodes
+                            .iter()
+                            .filter(|node| node.is_alive())
+                            .map(|node| Target {
+                                addr: node.addr,
+                                conn: node.conn.clone(),
+                            })
+                            .collect();
+                        Some((self.max_range_keys, targets))
+                    }
+                    _ => None,
+                };
+                let _ = reply.send(targets);
+            }
+            ManagerCmd::RangeSizes { sizes } => {
+                self.record_range_sizes(sizes).await;
+            }
+            ManagerCmd::Raft { msg, reply } => {
+                let _ = reply.send(self.handle_raft(msg, Instant::now()).await);
+            }
+            ManagerCmd::RaftReply { from, msg } => {
+                self.handle_raft_reply(from, msg, Instant::now()).await;
+            }
+        }
+    }
+
+    /// The map, if we're the one who should be handing it out. Otherwise, who is. A leader that
+    /// hasn't caught up yet (or is still waiting on the first map to commit, which starts at
+    /// epoch 1) doesn't have a map it can vouch for, so it sends clients nowhere and they try
+    /// again shortly.
+    fn leading(&self) -> Result<&PartitionMap, Option<SocketAddr>> {
+        if !self.raft.is_leader() {
+            return Err(self.raft.leader());
+        }
+        match self.raft.is_caught_up() && self.map.epoch > 0 {
+            true => Ok(&self.map),
+            false => Err(None),
+        }
+    }
+
+    /// Folds in a round of heartbeats. Where our opinion of a node no longer matches the map, we
+    /// put the change in the log, and it goes out to the stores once it's committed. Nodes we just
+    /// reconnected to get the current map regardless, since a node that restarted has forgotten
+    /// it.
+    async fn record_checkups(&mut self, checkups: Vec<Checkup>, now: Instant) {
+        if !self.raft.is_leader() {
+            return;
+        }
+        for checkup in checkups {
+            let Some(node) = self.nodes.iter_mut().find(|node| node.addr == checkup.addr) else {
+                continue;
+            };
+            if let Some(conn) = checkup.reconnected {
+                send_map(conn.clone(), self.map.clone());
+                node.conn = Some(conn);
+            }
+            if let Some(health) = node.observe(checkup.heard, now) {
+                match health {
+                    Health::Alive => eprintln!("[INFO] {} is back", node.addr),
+                    Health::Suspect => eprintln!("[WARN] {} missed a heartbeat", node.addr),
+                    Health::Dead => {
+                        eprintln!("[WARN] {} stopped answering, marking it down", node.addr)
+                    }
+                }
+            }
+
+            let (addr, health) = (node.addr, node.health);
+            if self.map.health(addr) != health && self.proposed.get(&addr) != Some(&health) {
+                self.raft.propose(Command::SetHealth { node: addr, health });
+                self.proposed.insert(addr, health);
+            }
+        }
+        self.after_raft(Vec::new()).await;
+    }
+
+    /// Starts adding `node` to the ring. It only gets reads once everyone has handed it its keys,
+    /// see [`Manager::record_handoff`].
+    async fn join(&mut self, node: SocketAddr, tokens: Option<usize>) -> Message {
+        if let Err(leader) = self.leading() {
+            return Message::NotLeader { leader };
+        }
+        if self.map.pending.is_some() || self.changing_ring {
+            return Message::error(ErrorCode::Busy, "the ring is already changing");
+        }
+        if self.map.ring.contains(node) {
+            return Message::error(
+                ErrorCode::BadRequest,
+                format!("{node} is already in the ring"),
+            );
+        }
+
+        if tokens == Some(0) {
+            return Message::error(ErrorCode::BadRequest, "nodes need at least one token");
+        }
+
+        eprintln!("[INFO] {node} asked to join");
+        let tokens = tokens.unwrap_or(self.tokens);
+        self.raft.propose(Command::Join { node, tokens });
+        self.changing_ring = true;
+        self.after_raft(Vec::new()).await;
+        Message::Accepted
+    }
+
+    /// Starts taking `node` out of the ring. The nodes inheriting its keys pull them in like they
+    /// would for a join, and once they have, it's told to shut down.
+    async fn decommission(&mut self, node: SocketAddr) -> Message {
+        if let Err(leader) = self.leading() {
+            return Message::NotLeader { leader };
+        }
+        if self.map.pending.is_some() || self.changing_ring {
+            return Message::error(ErrorCode::Busy, "the ring is already changing");
+        }
+        if !self.map.ring.contains(node) {
+            return Message::error(ErrorCode::BadRequest, format!("{node} isn't in the ring"));
+        }
+        let members = self.map.members().len();
+        if members <= self.map.quorum.n {
+            return Message::error(
+                ErrorCode::BadRequest,
+                format!(
+                    "only {members} nodes left, and keys need {} replicas each",
+                    self.map.quorum.n
+                ),
+            );
+        }
+
+        eprintln!("[INFO] Decommissioning {node}");
+        self.raft.propose(Command::Leave { node });
+        self.changing_ring = true;
+        self.after_raft(Vec::new()).await;
+        Message::Accepted
+    }
+
+    /// Folds in how far each node in the new ring has gotten with the rebalance that started at
+    /// `since`. Once they all have every bucket, the new ring takes over. If nobody has gotten any
+    /// further in `rebalance_timeout`, say because a node in the new ring stopped answering, the
+    /// rebalance is called off instead.
+    async fn record_handoff(
+        &mut self,
+        since: Epoch,
+        progress: Vec<(SocketAddr, Option<(u32, u32)>)>,
+        now: Instant,
+    ) {
+        let current = self.map.pending.as_ref().map(|pending| pending.since);
+        if !self.raft.is_leader() || current != Some(since) {
+            return;
+        }
+        let mut moved = *self.handoff_moved.get_or_insert(now);
+        let mut caught_up = true;
+        for (addr, progress) in progress {
+            let Some((done, total)) = progress else {
+                caught_up = false;
+                continue;
+            };
+            caught_up &= done == total;
+            if self.handoff.insert(addr, (done, total)) != Some((done, total)) {
+                eprintln!("[INFO] Handoff for epoch {since}: {addr} has {done}/{total} buckets");
+                moved = now;
+            }
+        }
+        self.handoff_moved = Some(moved);
+        if self.changing_ring {
+            return;
+        }
+        if caught_up {
+            eprintln!("[INFO] Everyone has caught up on epoch {since}, switching rings");
+            self.raft.propose(Command::FinishRebalance { since });
+        } else if now.duration_since(moved) >= self.rebalance_timeout {
+            eprintln!(
+                "[WARN] Handoff for epoch {since} hasn't moved in {:?}, calling it off",
+                self.rebalance_timeout
+            );
+            self.raft.propose(Command::AbortRebalance { since });
+        } else {
+            return;
+        }
+        self.changing_ring = true;
+        self.after_raft(Vec::new()).await;
+    }
+
+    /// Splits the first range that's grown past `max_range_keys`. Splits move keys around like
+    /// any other ring change, so only one goes in at a time; the rest wait for the next check.
+    async fn record_range_sizes(&mut self, sizes: Vec<RangeSize>) {
+        if !self.raft.is_leader() || self.map.pending.is_some() || self.changing_ring {
+            return;
+        }
+        let Some(ranges) = self.map.ring.as_ranges() else {
+            return;
+        };
+        let oversized = sizes.into_iter().find(|size| {
+            // Sizes from before the last split can name a range that isn't there anymore.
+            let fits = |at: &str| ranges.bounds(at).0 == size.start;
+            size.keys > self.max_range_keys && size.middle.as_deref().is_some_and(fits)
+        });
+        let Some(RangeSize {
+            start,
+            keys,
+            middle: Some(at),
+        }) = oversized
+        else {
+            return;
+        };
+        eprintln!("[INFO] Range starting at '{start}' has {keys} keys, splitting it at '{at}'");
+        self.raft.propose(Command::Split { at });
+        self.changing_ring = true;
+        self.after_raft(Vec::new()).await;
+    }
+
+    async fn handle_raft(&mut self, msg: Message, now: Instant) -> Message {
+        let reply = match msg {
+            Message::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self
+                .raft
+                .handle_request_vote(term, candidate, last_log_index, last_log_term, now),
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.raft.handle_append_entries(
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                now,
+            ),
+            Message::InstallSnapshot {
+                term,
+                leader,
+                snapshot,
+            } => self
+                .raft
+                .handle_install_snapshot(term, leader, snapshot, now),
+            other => Message::error(
+                ErrorCode::BadRequest,
+                format!("not a Raft message: {other:?}"),
+            ),
+        };
+        // Whatever we just promised has to be on disk before we say so.
+        self.after_raft(Vec::new()).await;
+        reply
+    }
+
+    async fn handle_raft_reply(&mut self, from: SocketAddr, msg: Message, now: Instant) {
+        let outgoing = match msg {
+            Message::Vote { term, granted } => self.raft.handle_vote(from, term, granted, now),
+            Message::Appended {
+                term,
+                success,
+                match_index,
+            } => self.raft.handle_appended(from, term, success, match_index),
+            _ => Vec::new(),
+        };
+        self.after_raft(outgoing).await;
+    }
+
+    async fn tick(&mut self, now: Instant) {
+        let outgoing = self.raft.tick(now);
+        self.after_raft(outgoing).await;
+    }
+
+    /// Everything that has to happen after Raft's state moves: save it, send what it wants sent,
+    /// and apply whatever got committed.
+    async fn after_raft(&mut self, outgoing: Vec<Outgoing>) {
+        if self.raft.is_leader() != self.leading {
+            self.leading = self.raft.is_leader();
+            self.on_leadership_change();
+        }
+
+        if let Some(hard) = self.raft.take_dirty() {
+            if let Some(path) = self.raft_file.clone() {
+                // Encode here and leave the disk to a blocking thread. We still wait for it, since
+                // nothing Raft wants sent can go out before it's saved.
+                let saved = match hard.encode() {
+                    Ok(bytes) => {
+                        let path = path.clone();
+                        tokio::task::spawn_blocking(move || HardState::save(&path, &bytes))
+                            .await
+                            .expect("Saving Raft state panicked")
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = saved {
+                    eprintln!("[ERROR] Couldn't save Raft state to {path:?}: {e}");
+                }
+            }
+        }
+
+        for Outgoing { to, msg } in outgoing {
+            let peers = self.peers.clone();
+            let state_tx = self.state_tx.clone();
+            tokio::spawn(async move {
+                // No answer is an answer too: Raft just tries again next heartbeat.
+                if let Ok(Ok(msg)) = timeout(RAFT_RPC_TIMEOUT, peers.call(to, msg)).await {
+                    let _ = state_tx.send(ManagerCmd::RaftReply { from: to, msg }).await;
+                }
+            });
+        }
+
+        let mut changed = false;
+        for command in self.raft.take_committed() {
+            changed |= self.apply(command);
+        }
+        // The map is everything applied so far, so it stands in for those entries from here on.
+        // It goes to disk with the next save.
+        if self.raft.wants_compaction() {
+            self.raft.compact(self.map.clone());
+        }
+        if changed && self.raft.is_leader() {
+            self.publish();
+        }
+    }
+
+    fn on_leadership_change(&mut self) {
+        if !self.leading {
+            eprintln!("[INFO] No longer leading the managers");
+            return;
+        }
+        eprintln!(
+            "[INFO] Leading the managers as of term {}",
+            self.raft.term()
+        );
+        // Nobody was checking on the stores while we were following, so start our opinions over.
+        let now = Instant::now();
+        for node in &mut self.nodes {
+            node.detector = self.detector.build(now);
+        }
+        self.proposed.clear();
+        self.changing_ring = false;
+        self.handoff.clear();
+        self.handoff_moved = None;
+
+        let bootstrapped = self.raft.has_snapshot()
+            || self
+                .raft
+                .log()
+                .iter()
+                .any(|entry| matches!(entry.command, Command::Bootstrap { .. }));
+        if !bootstrapped {
+            let map = self.bootstrap.clone();
+            self.raft.propose(Command::Bootstrap { map });
+        }
+    }
+
+    /// Applies one committed entry. Returns whether the map changed.
+    fn apply(&mut self, command: Command) -> bool {
+        match &command {
+            Command::Bootstrap { map } => eprintln!(
+                "[INFO] Map is at epoch {} with {} storage nodes",
+                map.epoch,
+                map.members().len()
+            ),
+            Command::SetHealth { node, health } => {
+                if self.proposed.get(node) == Some(health) {
+                    self.proposed.remove(node);
+                }
+            }
+            Command::Join { .. }
+            | Command::Leave { .. }
+            | Command::FinishRebalance { .. }
+            | Command::AbortRebalance { .. }
+            | Command::Split { .. } => {
+                self.changing_ring = false;
+                self.handoff.clear();
+                self.handoff_moved = None;
+            }
+            Command::Noop => {}
+        }
+        let ring_changed = matches!(
+            command,
+            Command::Bootstrap { .. } | Command::FinishRebalance { .. } | Command::Split { .. }
+        );
+        // A called-off join leaves the joining node out of the map, but it never had any keys to
+        // itself, so it isn't one to shut down.
+        let aborted = matches!(command, Command::AbortRebalance { .. });
+        if !apply(&mut self.map, command) {
+            return false;
+        }
+        if ring_changed {
+            log_shares(&self.map);
+        }
+
+        // Nodes that just left have handed everything over, so they can go.
+        let members = self.map.members();
+        let leavers = self
+            .nodes
+            .iter()
+            .filter(|node| !aborted && !members.contains(&node.addr));
+        for node in leavers {
+            if let (true, Some(conn)) = (self.raft.is_leader(), node.conn.clone()) {
+                tokio::spawn(async move {
+                    match conn.call(Message::Shutdown).await {
+                        Ok(_) => eprintln!("[INFO] Told {} to shut down", conn.peer()),
+                        Err(e) => eprintln!("[WARN] Couldn't shut {} down: {e}", conn.peer()),
+                    }
+                });
+            }
+        }
+        self.sync_nodes();
+        true
+    }
+
+    /// Makes sure we have a `Node` for everything in the map, keeping the ones we already had.
+    fn sync_nodes(&mut self) {
+        let now = Instant::now();
+        let mut known: HashMap<_, _> = self.nodes.drain(..).map(|n| (n.addr, n)).collect();
+        self.nodes = self
+            .map
+            .members()
+            .into_iter()
+            .map(|addr| {
+                known.remove(&addr).unwrap_or_else(|| Node {
+                    conn: None,
+                    addr,
+                    health: self.map.health(addr),
+                    detector: self.detector.build(now),
+                })
+            })
+            .collect();
+    }
+}
+
+/// Applies one committed entry to `map`. Every manager does this with the same entries in the same
+/// order, so they all end up with the same map. Returns whether it changed.
+fn apply(map: &mut PartitionMap, command: Command) -> bool {
+    match command {
+        Command::Noop => return false,
+        Command::Bootstrap { map: bootstrap } => *map = bootstrap,
+        Command::SetHealth { node, health } => {
+            map.down.retain(|&addr| addr != node);
+            map.suspect.retain(|&addr| addr != node);
+            match health {
+                Health::Alive => {}
+                Health::Suspect => map.suspect.push(node),
+                Health::Dead => map.down.push(node),
+            }
+            map.epoch += 1;
+        }
+        Command::Join { node, tokens } => {
+            if map.pending.is_some() {
+                eprintln!("[WARN] {node} can't join in the middle of another rebalance");
+                return false;
+            }
+            let mut ring = map.ring.clone();
+            ring.add_node(node, tokens);
+            map.epoch += 1;
+            map.pending = Some(Rebalance {
+                ring,
+                since: map.epoch,
+            });
+            eprintln!("[INFO] {node} is joining as of epoch {}", map.epoch);
+        }
+        Command::Leave { node } => {
+            if map.pending.is_some() {
+                eprintln!("[WARN] {node} can't leave in the middle of another rebalance");
+                return false;
+            }
+            if !map.ring.contains(node) {
+                eprintln!("[WARN] {node} can't leave a ring it isn't in");
+                return false;
+            }
+            let mut ring = map.ring.clone();
+            ring.remove_node(node);
+            map.epoch += 1;
+            map.pending = Some(Rebalance {
+                ring,
+                since: map.epoch,
+            });
+            eprintln!("[INFO] {node} is leaving as of epoch {}", map.epoch);
+        }
+        Command::FinishRebalance { since } => {
+            match map.pending.take_if(|pending| pending.since == since) {
+                Some(pending) => map.ring = pending.ring,
+                None => return false,
+            }
+            map.epoch += 1;
+            eprintln!(
+                "[INFO] Rebalance from epoch {since} done as of epoch {}",
+                map.epoch
+            );
+        }
+        Command::AbortRebalance { since } => {
+            if map
+                .pending
+                .take_if(|pending| pending.since == since)
+                .is_none()
+            {
+                return false;
+            }
+            map.epoch += 1;
+            eprintln!(
+                "[WARN] Rebalance from epoch {since} called off as of epoch {}",
+                map.epoch
+            );
+        }
+        Command::Split { at } => {
+            if map.pending.is_some() {
+                eprintln!("[WARN] Can't split at '{at}' in the middle of a rebalance");
+                return false;
+            }
+            let Some(ranges) = map.ring.as_ranges_mut() else {
+                eprintln!("[WARN] Can't split at '{at}', the cluster isn't range-partitioned");
+                return false;
+            };
+            if !ranges.split(&at) {
+                return false;
+            }
+            // Both halves start out where the whole was, so nothing has to move for the split
+            // itself. Handing one half to someone else is a rebalance like any other.
+            let mut next = ranges.clone();
+            next.spread(&at);
+            map.epoch += 1;
+            if map.ring.as_ranges() != Some(&next) {
+                map.pending = Some(Rebalance {
+                    ring: Strategy::Range(next),
+                    since: map.epoch,
+                });
+            }
+            eprintln!("[INFO] Split at '{at}' as of epoch {}", map.epoch);
+        }
+    }
+    true
+}
+
+/// Logs how the keyspace is split up, so lopsided weights are easy to spot.
+fn log_shares(map: &PartitionMap) {
+    for (node, share) in map.ring.shares() {
+        eprintln!(
+            "[INFO] {node} has {} tokens: first in line for {:.1}% of keys, a replica for {:.1}%",
+            map.ring.tokens(node),
+            share.primary * 100.0,
+            share.replica * 100.0,
+        );
+    }
+}
+
+fn send_map(conn: Arc<Connection>, map: PartitionMap) {
+    tokio::spawn(async move {
+        if let Err(e) = conn.call(Message::UpdatePartitionMap { map }).await {
+            eprintln!("[WARN] Couldn't send partition map to {}: {e}", conn.peer());
+        }
+    });
+}
+
+/// Requests for the task that owns the `Manager`. Connection handlers never touch the state
+/// directly; they send one of these and wait on the reply.
+enum ManagerCmd {
+    /// `Err` if we aren't leading, with who is if we know.
+    Owners {
+        key: String,
+        reply: oneshot::Sender<Result<Vec<SocketAddr>, Option<SocketAddr>>>,
+    },
+    PartitionMap {
+        reply: oneshot::Sender<Result<PartitionMap, Option<SocketAddr>>>,
+    },
+    /// Every node, for the heartbeat loop. Nobody unless we're leading.
+    HeartbeatTargets {
+        reply: oneshot::Sender<Vec<Target>>,
+    },
+    Checkups {
+        checkups: Vec<Checkup>,
+    },
+    Join {
+        node: SocketAddr,
+        tokens: Option<usize>,
+        reply: oneshot::Sender<Message>,
+    },
+    Decommission {
+        node: SocketAddr,
+        reply: oneshot::Sender<Message>,
+    },
+    /// The rebalance in progress and every node that has to catch up on it, for the handoff loop.
+    /// Nothing unless we're leading one.
+    HandoffTargets {
+        reply: oneshot::Sender<Option<(Epoch, Vec<Target>)>>,
+    },
+    /// How far each node has gotten with the rebalance that started at `since`. `None` for nodes
+    /// that didn't say.
+    HandoffProgress {
+        since: Epoch,
+        progress: Vec<(SocketAddr, Option<(u32, u32)>)>,
+    },
+    /// How many keys a range can hold and every node to ask about theirs, for the split loop.
+    /// Nothing unless we're leading a range-partitioned cluster that isn't already changing.
+    SplitTargets {
+        reply: oneshot::Sender<Option<(u64, Vec<Target>)>>,
+    },
+    /// How big the ranges were, from whichever of their owners answered.
+    RangeSizes {
+        sizes: Vec<RangeSize>,
+    },
+    /// A Raft message from another manager, and where to send our answer.
+    Raft {
+        msg: Message,
+        reply: oneshot::Sender<Message>,
+    },
+    /// Another manager's answer to a Raft message we sent.
+    RaftReply {
+        from: SocketAddr,
+        msg: Message,
+    },
+}
+
+/// A node the heartbeat loop should check on.
+struct Target {
+    addr: SocketAddr,
+    conn: Option<Arc<Connection>>,
+}
+
+/// How one node did in a round of heartbeats.
+struct Checkup {
+    addr: SocketAddr,
+    heard: bool,
+    /// Fresh connection, if the old one was gone and we managed to get a new one.
+    reconnected: Option<Arc<Connection>>,
+}
+
+/// Hands `cmd` to the state loop and waits for its answer.
+async fn ask<T>(
+    state_tx: &mpsc::Sender<ManagerCmd>,
+    cmd: impl FnOnce(oneshot::Sender<T>) -> ManagerCmd,
+) -> Option<T> {
+    let (reply, reply_rx) = oneshot::channel();
+    state_tx.send(cmd(reply)).await.ok()?;
+    reply_rx.await.ok()
 }
 
 struct Node {
-    conn: Option<TcpStream>,
-    port: u16,
+    conn: Option<Arc<Connection>>,
+    addr: SocketAddr,
+    health: Health,
+    detector: Box<dyn FailureDetector>,
 }
 
 impl Node {
-    pub async fn connect_on_port(port: u16) -> Self {
-        let addr = SocketAddr::from(([127, 0, 0, 1], port));
-        let conn = TcpStream::connect(addr).await;
-        if let Err(e) = &conn {
-            eprintln!("Failed to connect to '{addr:?}': {e}");
-        }
-        let conn = conn.ok();
+    pub async fn connect(addr: SocketAddr, mgr_id: &str, detector: DetectorConfig) -> Self {
+        let (conn, health) = match Connection::connect(addr, Role::Manager, mgr_id).await {
+            Ok(conn) => (Some(Arc::new(conn)), Health::Alive),
+            Err(e) => {
+                eprintln!("Failed to connect to '{addr:?}': {e}");
+                (None, Health::Dead)
+            }
+        };
 
-        Self { conn, port }
+        Self {
+            conn,
+            addr,
+            health,
+            detector: detector.build(Instant::now()),
+        }
     }
 
     pub fn is_alive(&self) -> bool {
-        self.conn.is_some()
+        self.health != Health::Dead
+    }
+
+    /// Updates our opinion of the node after a heartbeat. The detector decides what a miss
+    /// means, but once a node is dead it takes an answer to bring it back. Returns the new health
+    /// if it changed.
+    fn observe(&mut self, heard: bool, now: Instant) -> Option<Health> {
+        let health = if heard {
+            if self.health == Health::Dead {
+                self.detector.revived(now);
+            } else {
+                self.detector.heard(now);
+            }
+            Health::Alive
+        } else if self.health == Health::Dead {
+            Health::Dead
+        } else {
+            self.detector.after_miss(now)
+        };
+
+        if health == Health::Dead {
+            // Whatever connection we had is no good. The heartbeat loop will make a new one.
+            self.conn = None;
+        }
+        if health == self.health {
+            return None;
+        }
+        self.health = health;
+        Some(health)
+    }
+}
+
+/// Sends every node a heartbeat each `interval`, and tries to reconnect to the ones we've lost
+/// our connection to. Each round reports back to the state loop, which decides who's healthy.
+async fn heartbeat_loop(state_tx: mpsc::Sender<ManagerCmd>, mgr_id: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let Some(targets) = ask(&state_tx, |reply| ManagerCmd::HeartbeatTargets { reply }).await
+        else {
+            return;
+        };
+
+        // Nobody gets longer than a round to answer, so one slow node can't hold up the rest.
+        let mut checks = JoinSet::new();
+        for Target { addr, conn } in targets {
+            let mgr_id = mgr_id.clone();
+            checks.spawn(async move {
+                match conn.filter(|conn| !conn.is_closed()) {
+                    Some(conn) => {
+                        let answer = timeout(interval, conn.call(Message::Heartbeat)).await;
+                        Checkup {
+                            addr,
+                            heard: matches!(answer, Ok(Ok(Message::Heartbeat))),
+                            reconnected: None,
+                        }
+                    }
+                    None => {
+                        let attempt = Connection::connect(addr, Role::Manager, &mgr_id);
+                        let reconnected = match timeout(interval, attempt).await {
+                            Ok(Ok(conn)) => Some(Arc::new(conn)),
+                            _ => None,
+                        };
+                        Checkup {
+                            addr,
+                            heard: reconnected.is_some(),
+                            reconnected,
+                        }
+                    }
+                }
+            });
+        }
+        let checkups = checks.join_all().await;
+
+        if state_tx
+            .send(ManagerCmd::Checkups { checkups })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Clients come back for every key they touch, so keep their connections around for a while.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn handoff_loop(state_tx: mpsc::Sender<ManagerCmd>) {
+    let mut ticker = tokio::time::interval(HANDOFF_POLL_PERIOD);
+    loop {
+        ticker.tick().await;
+        let Some(targets) = ask(&state_tx, |reply| ManagerCmd::HandoffTargets { reply }).await
+        else {
+            return;
+        };
+        let Some((since, targets)) = targets else {
+            continue;
+        };
+
+        let mut checks = JoinSet::new();
+        for Target { addr, conn } in targets {
+            checks.spawn(async move {
+                // Nodes we can't reach right now get asked again next round.
+                let Some(conn) = conn.filter(|conn| !conn.is_closed()) else {
+                    return (addr, None);
+                };
+                let answer = timeout(HANDOFF_POLL_PERIOD, conn.call(Message::GetHandoff)).await;
+                let progress = match answer {
+                    Ok(Ok(Message::Handoff {
+                        since: theirs,
+                        done,
+                        total,
+                    })) if theirs == since => Some((done, total)),
+                    _ => None,
+                };
+                (addr, progress)
+            });
+        }
+        let progress = checks.join_all().await;
+
+        if state_tx
+            .send(ManagerCmd::HandoffProgress { since, progress })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Asks the first owner of every range how many keys it has, so the state loop can split the ones
+/// that have gotten too big.
+async fn split_loop(state_tx: mpsc::Sender<ManagerCmd>) {
+    let mut ticker = tokio::time::interval(SPLIT_CHECK_PERIOD);
+    loop {
+        ticker.tick().await;
+        let Some(targets) = ask(&state_tx, |reply| ManagerCmd::SplitTargets { reply }).await else {
+            return;
+        };
+        let Some((max_keys, targets)) = targets else {
+            continue;
+        };
+
+        let mut checks = JoinSet::new();
+        for Target { conn, .. } in targets {
+            checks.spawn(async move {
+                let conn = conn.filter(|conn| !conn.is_closed())?;
+                match timeout(SPLIT_CHECK_PERIOD, conn.call(Message::GetRangeSizes)).await {
+                    Ok(Ok(Message::RangeSizes { sizes })) => Some(sizes),
+                    _ => None,
+                }
+            });
+        }
+        let sizes: Vec<_> = checks
+            .join_all()
+            .await
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|size| size.keys > max_keys)
+            .collect();
+        if sizes.is_empty() {
+            continue;
+        }
+
+        if state_tx
+            .send(ManagerCmd::RangeSizes { sizes })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+async fn handle_client(mut conn: TcpStream, mgr_id: String, state_tx: mpsc::Sender<ManagerCmd>) {
+    if let Err(e) = handshake::accept_within(
+        &mut conn,
+        Role::Manager,
+        &mgr_id,
+        handshake::HANDSHAKE_TIMEOUT,
+    )
+    .await
+    {
+        eprintln!("[ERROR] Handshake with {conn:?} failed: {e}");
+        return;
+    }
+
+    let peer = conn.peer_addr();
+    let handler = move |request| handle_request(request, state_tx.clone());
+    if let Err(e) = mux::serve(conn, IDLE_TIMEOUT, handler).await {
+        eprintln!("[ERROR] Connection with {peer:?} failed: {e}");
+    }
+}
+
+async fn handle_request(request: Envelope, state_tx: mpsc::Sender<ManagerCmd>) -> Message {
+    const GONE: &str = "manager state loop is gone";
+    match request.msg {
+        Message::GetOwners { key } => {
+            match ask(&state_tx, |reply| ManagerCmd::Owners { key, reply }).await {
+                Some(Ok(nodes)) if nodes.is_empty() => {
+                    Message::error(ErrorCode::NotOwner, "no storage nodes in the ring")
+                }
+                Some(Ok(nodes)) => Message::Owners { nodes },
+                Some(Err(leader)) => Message::NotLeader { leader },
+                None => Message::error(ErrorCode::Internal, GONE),
+            }
+        }
+        Message::GetPartitionMap => {
+            match ask(&state_tx, |reply| ManagerCmd::PartitionMap { reply }).await {
+                Some(Ok(map)) => Message::PartitionMap { map },
+                Some(Err(leader)) => Message::NotLeader { leader },
+                None => Message::error(ErrorCode::Internal, GONE),
+            }
+        }
+        Message::Join { node, tokens } => {
+            let join = |reply| ManagerCmd::Join {
+                node,
+                tokens,
+                reply,
+            };
+            match ask(&state_tx, join).await {
+                Some(reply) => reply,
+                None => Message::error(ErrorCode::Internal, GONE),
+            }
+        }
+        Message::Decommission { node } => {
+            match ask(&state_tx, |reply| ManagerCmd::Decommission { node, reply }).await {
+                Some(reply) => reply,
+                None => Message::error(ErrorCode::Internal, GONE),
+            }
+        }
+        msg @ (Message::RequestVote { .. }
+        | Message::AppendEntries { .. }
+        | Message::InstallSnapshot { .. }) => {
+            match ask(&state_tx, |reply| ManagerCmd::Raft { msg, reply }).await {
+                Some(reply) => reply,
+                None => Message::error(ErrorCode::Internal, GONE),
+            }
+        }
+        other => Message::error(
+            ErrorCode::BadRequest,
+            format!("managers don't handle '{other:?}'"),
+        ),
     }
 }
 
@@ -39,10 +1066,254 @@ struct ManagerArgs {
     /// Port of manager node.
     #[arg(short, long, default_value_t = 50051)]
     port: u16,
-    /// List of storage node ports to try to connect to.
-    store_ports: Option<Vec<u16>>,
+    /// List of storage node ports to try to connect to. Follow a port with `:TOKENS` to give that
+    /// node a different number of virtual nodes than `--tokens`.
+    store_ports: Option<Vec<StoreSpec>>,
+    /// Tokens per storage node, unless it says otherwise. A node's share of the keys grows with
+    /// its count, so give bigger machines more. Jump hashing ignores them.
+    #[arg(long, default_value_t = DEFAULT_TOKENS)]
+    tokens: usize,
+    /// How keys are split between storage nodes: `ring` (consistent hashing with virtual nodes),
+    /// `rendezvous` (highest random weight), `jump` (jump consistent hashing), or `range`
+    /// (contiguous key ranges, which clients can scan in order). Only used when the cluster is
+    /// first started.
+    #[arg(long, default_value_t = StrategyKind::Ring)]
+    placement: StrategyKind,
+    /// Hash that places keys and nodes: `sha1`, or `xxh3` to spend less time hashing. Only used
+    /// when the cluster is first started.
+    #[arg(long, default_value_t = HashFn::Sha1)]
+    ring_hash: HashFn,
+    /// Live keys a range can hold before it's split in two. Only used with `--placement range`.
+    #[arg(long, default_value_t = 100_000)]
+    max_range_keys: u64,
     /// Replication factor.
+    #[arg(short, long, default_value_t = 3)]
+    reps: usize,
+    /// Replicas that have to answer a read. Defaults to a majority of `reps`.
+    #[arg(short = 'R', long)]
+    read_quorum: Option<usize>,
+    /// Replicas that have to acknowledge a write. Defaults to a majority of `reps`.
+    #[arg(short = 'W', long)]
+    write_quorum: Option<usize>,
+    /// How often (in milliseconds) to check on each storage node.
+    #[arg(long, default_value_t = 1000)]
+    heartbeat_interval: u64,
+    /// How long (in milliseconds) a node can go without answering before it's marked down.
+    /// Until then it's only suspect. Only used by the timeout detector.
+    #[arg(long, default_value_t = 5000)]
+    heartbeat_timeout: u64,
+    /// How to decide a storage node is down.
+    #[arg(long, value_enum, default_value_t = DetectorKind::Timeout)]
+    detector: DetectorKind,
+    /// How sure the phi detector has to be before marking a node down, as -log10 of the chance
+    /// it's wrong.
+    #[arg(long, default_value_t = 8.0)]
+    phi_threshold: f64,
+    /// Port of another manager to replicate state with. Can be given more than once. Every
+    /// manager should be given all the others.
+    #[arg(long = "peer")]
+    peers: Vec<u16>,
+    /// How long (in seconds) a rebalance can go without any node pulling in more keys before it's
+    /// called off and the ring stays as it was.
+    #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u64).range(1..))]
+    rebalance_timeout: u64,
+    /// File to keep this manager's Raft state in. If none is provided, a restarted manager
+    /// rejoins with nothing and catches up from the others.
+    #[arg(long)]
+    raft_state: Option<PathBuf>,
+}
+
+/// A storage node from the command line: `PORT` or `PORT:TOKENS`.
+#[derive(Debug, Clone, Copy)]
+struct StoreSpec {
+    port: u16,
+    tokens: Option<usize>,
+}
+
+impl FromStr for StoreSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, tokens) = match s.split_once(':') {
+            Some((port, tokens)) => (port, Some(tokens)),
+            None => (s, None),
+        };
+        let port = port
+            .parse()
+            .map_err(|e| format!("bad port '{port}': {e}"))?;
+        let tokens = match tokens.map(str::parse) {
+            Some(Ok(0)) => return Err("nodes need at least one token".into()),
+            Some(Ok(tokens)) => Some(tokens),
+            Some(Err(e)) => return Err(format!("bad token count in '{s}': {e}")),
+            None => None,
+        };
+        Ok(Self { port, tokens })
+    }
+}
+
+/// Everything `ManagerArgs` boils down to, so managers can be started without a command line.
+struct Config {
+    port: u16,
+    stores: Vec<StoreSpec>,
+    /// Tokens for stores that don't say.
+    tokens: usize,
+    placement: StrategyKind,
+    ring_hash: HashFn,
+    max_range_keys: u64,
+    peers: Vec<u16>,
     reps: usize,
+    quorum: Quorum,
+    detector: DetectorConfig,
+    rebalance_timeout: Duration,
+    raft_state: Option<PathBuf>,
+}
+
+/// Starts a manager in the background. Dropping the returned tasks stops it.
+async fn start(config: Config) -> io::Result<JoinSet<()>> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], config.port));
+    let listener = TcpListener::bind(addr).await?;
+
+    // I had this nice FP-style solution only for async semantics to ruin it.
+    // Even though this function is in an async block, the lambdas I passed in also had to be
+    // marked as async to call async code, which would cause nodes' type to be `Vec<async block>`
+    // even though said block only did effects. Maybe it's because iterators are lazy?
+    let mgr_id = format!("manager@{addr}");
+    let nodes = {
+        let mut nodes = Vec::with_capacity(config.stores.len());
+        for spec in &config.stores {
+            // We don't return an error if we fail to connect because we figure the user may want to
+            // remember this node or try to connect to it as soon as possible.
+            let store = SocketAddr::from(([127, 0, 0, 1], spec.port));
+            nodes.push(Node::connect(store, &mgr_id, config.detector).await);
+        }
+        nodes
+    };
+
+    let mut ring = Strategy::new(config.placement, config.reps, config.ring_hash);
+    for (node, spec) in nodes.iter().zip(&config.stores) {
+        ring.add_node(node.addr, spec.tokens.unwrap_or(config.tokens));
+    }
+
+    // The stores keep going without us, and might have seen maps from a previous run. Pick up
+    // past whatever they're on, or they'd ignore everything we send.
+    let mut epoch = 1;
+    for node in &nodes {
+        let Some(conn) = &node.conn else {
+            continue;
+        };
+        // Same patience as a heartbeat, so one stuck store can't hold up startup.
+        let asked = timeout(
+            config.detector.interval,
+            conn.call(Message::GetPartitionMap),
+        )
+        .await;
+        match asked {
+            Ok(Ok(Message::PartitionMap { map })) => epoch = epoch.max(map.epoch + 1),
+            Err(_) => eprintln!("[WARN] {} didn't say which map it has in time", node.addr),
+            _ => {}
+        }
+    }
+    let with_health = |health| {
+        nodes
+            .iter()
+            .filter(|node| node.health == health)
+            .map(|node| node.addr)
+            .collect()
+    };
+    let bootstrap = PartitionMap {
+        epoch,
+        ring,
+        quorum: config.quorum,
+        down: with_health(Health::Dead),
+        suspect: with_health(Health::Suspect),
+        pending: None,
+    };
+    eprintln!(
+        "[INFO] Managing {} storage nodes ({} alive), {} replicas per key (R={}, W={})",
+        nodes.len(),
+        nodes.iter().filter(|n| n.is_alive()).count(),
+        config.quorum.n,
+        config.quorum.r,
+        config.quorum.w,
+    );
+
+    let hard = match &config.raft_state {
+        Some(path) => HardState::load(path)?,
+        None => HardState::default(),
+    };
+    let peers: Vec<_> = config
+        .peers
+        .iter()
+        .map(|&p| SocketAddr::from(([127, 0, 0, 1], p)))
+        .collect();
+    eprintln!(
+        "[INFO] Replicating with {} other managers, starting from term {}",
+        peers.len(),
+        hard.term
+    );
+
+    // Yknow, just for the hell of it, let's use channels this time.
+    let (state_tx, mut state_rx) = mpsc::channel(1024);
+    let mut mgr_state = Manager {
+        map: PartitionMap::default(),
+        nodes,
+        raft: Raft::new(addr, peers, hard, Instant::now()),
+        leading: false,
+        raft_file: config.raft_state,
+        peers: Arc::new(Pool::new(
+            Role::Manager,
+            mgr_id.clone(),
+            1,
+            RAFT_RPC_TIMEOUT,
+        )),
+        state_tx: state_tx.clone(),
+        bootstrap,
+        detector: config.detector,
+        proposed: HashMap::new(),
+        changing_ring: false,
+        handoff: HashMap::new(),
+        handoff_moved: None,
+        rebalance_timeout: config.rebalance_timeout,
+        tokens: config.tokens,
+        max_range_keys: config.max_range_keys,
+    };
+
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move {
+        let mut ticker = tokio::time::interval(RAFT_TICK);
+        loop {
+            tokio::select! {
+                cmd = state_rx.recv() => match cmd {
+                    Some(cmd) => mgr_state.handle(cmd).await,
+                    None => break,
+                },
+                _ = ticker.tick() => mgr_state.tick(Instant::now()).await,
+            }
+        }
+    });
+    tasks.spawn(handoff_loop(state_tx.clone()));
+    tasks.spawn(split_loop(state_tx.clone()));
+    tasks.spawn(heartbeat_loop(
+        state_tx.clone(),
+        mgr_id.clone(),
+        config.detector.interval,
+    ));
+    tasks.spawn(async move {
+        // Kept here rather than spawned loose so they go away with the manager.
+        let mut clients = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (conn, _) = accepted.expect("Failed to accept connection!");
+                    eprintln!("[INFO] Got connection!");
+                    clients.spawn(handle_client(conn, mgr_id.clone(), state_tx.clone()));
+                }
+                Some(_) = clients.join_next() => {}
+            }
+        }
+    });
+    eprintln!("[INFO] Listening on {}", config.port);
+    Ok(tasks)
 }
 
 #[tokio::main]
@@ -67,42 +1338,305 @@ async fn main() {
     // What if a node goes down mid-write? Perhaps we could try logging.
 
     let args = ManagerArgs::parse();
+    let config = Config {
+        port: args.port,
+        stores: args.store_ports.unwrap_or(Vec::new()),
+        tokens: args.tokens,
+        placement: args.placement,
+        ring_hash: args.ring_hash,
+        max_range_keys: args.max_range_keys,
+        peers: args.peers,
+        reps: args.reps,
+        quorum: Quorum::majority(args.reps).with(Consistency {
+            n: None,
+            r: args.read_quorum,
+            w: args.write_quorum,
+        }),
+        detector: DetectorConfig {
+            kind: args.detector,
+            interval: Duration::from_millis(args.heartbeat_interval),
+            timeout: Duration::from_millis(args.heartbeat_timeout),
+            phi_threshold: args.phi_threshold,
+        },
+        rebalance_timeout: Duration::from_secs(args.rebalance_timeout),
+        raft_state: args.raft_state,
+    };
 
-    // I had this nice FP-style solution only for async semantics to ruin it.
-    // Even though this function is in an async block, the lambdas I passed in also had to be
-    // marked as async to call async code, which would cause nodes' type to be `Vec<async block>`
-    // even though said block only did effects. Maybe it's because iterators are lazy?
-    let nodes = {
-        let ports = args.store_ports.unwrap_or(Vec::new());
-        let mut nodes = Vec::with_capacity(ports.len());
-        for p in ports {
-            // We don't return an error if we fail to connect because we figure the user may want to
-            // remember this node or try to connect to it as soon as possible.
-            nodes.push(Node::connect_on_port(p).await);
+    let mut manager = start(config).await.expect("Couldn't start manager");
+    // Everything in there runs forever, so this only returns if something fell over.
+    if let Some(Err(e)) = manager.join_next().await {
+        eprintln!("[ERROR] Manager task died: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::ring_hash::RingHash;
+
+    #[test]
+    fn nodes_are_suspect_before_they_are_dead() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let mut node = Node {
+            conn: None,
+            addr: SocketAddr::from(([127, 0, 0, 1], 50052)),
+            health: Health::Alive,
+            detector: Box::new(detector::Timeout::new(start, timeout)),
+        };
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(node.observe(true, at(1)), None);
+        assert_eq!(node.observe(false, at(2)), Some(Health::Suspect));
+        assert_eq!(node.observe(false, at(5)), None);
+        // The clock runs from the last answer, not the first miss.
+        assert_eq!(node.observe(false, at(6)), Some(Health::Dead));
+        assert!(!node.is_alive());
+
+        // Dead stays dead until it answers, however recently that clock was reset.
+        assert_eq!(node.observe(false, at(7)), None);
+        assert_eq!(node.observe(true, at(8)), Some(Health::Alive));
+        assert_eq!(node.observe(false, at(9)), Some(Health::Suspect));
+        assert_eq!(node.observe(true, at(10)), Some(Health::Alive));
+    }
+
+    #[test]
+    fn ring_changes_take_effect_when_the_rebalance_finishes() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut ring = Strategy::from(RingHash::new(2));
+        ring.add_node(addr(50052), DEFAULT_TOKENS);
+        ring.add_node(addr(50053), DEFAULT_TOKENS);
+        let mut map = PartitionMap {
+            epoch: 1,
+            ring: ring.clone(),
+            ..PartitionMap::default()
+        };
+
+        let join = |port| Command::Join {
+            node: addr(port),
+            tokens: DEFAULT_TOKENS,
+        };
+        assert!(apply(&mut map, join(50054)));
+        assert_eq!(map.epoch, 2);
+        assert_eq!(map.members().len(), 3);
+        // Reads stay where they were until the new node has its keys.
+        assert_eq!(map.ring, ring);
+        let since = map.pending.as_ref().unwrap().since;
+        assert!(map.pending.as_ref().unwrap().ring.contains(addr(50054)));
+
+        // One change at a time, and only the one in progress can finish.
+        assert!(!apply(&mut map, join(50055)));
+        assert!(!apply(
+            &mut map,
+            Command::FinishRebalance { since: since - 1 }
+        ));
+        assert!(apply(&mut map, Command::FinishRebalance { since }));
+        assert_eq!(map.epoch, 3);
+        assert!(map.pending.is_none());
+        assert!(map.ring.contains(addr(50054)));
+
+        // Only the keys the leaving node had move.
+        let before = map.ring.clone();
+        assert!(apply(&mut map, Command::Leave { node: addr(50052) }));
+        assert!(map.ring.contains(addr(50052)));
+        assert!(!map.is_settled(addr(50052)));
+        let since = map.pending.as_ref().unwrap().since;
+        assert!(apply(&mut map, Command::FinishRebalance { since }));
+        assert!(!map.ring.contains(addr(50052)));
+        for i in 0..64 {
+            let key = format!("key_{i}");
+            let owners = before.write_group(&key);
+            if !owners.contains(&addr(50052)) {
+                assert_eq!(map.owners(&key), owners.into_vec());
+            }
         }
-        nodes
-    };
+        assert_eq!(map.members(), [addr(50053), addr(50054)]);
+        assert!(!apply(&mut map, Command::Leave { node: addr(50052) }));
 
-    let mut ring_hash = RingHash::new(args.reps);
-    for i in 0..nodes.len() {
-        ring_hash.add_node(i);
+        // A called-off join leaves the ring as it was.
+        let before = map.clone();
+        assert!(apply(&mut map, join(50055)));
+        let since = map.pending.as_ref().unwrap().since;
+        assert!(!apply(
+            &mut map,
+            Command::AbortRebalance { since: since - 1 }
+        ));
+        assert!(apply(&mut map, Command::AbortRebalance { since }));
+        assert_eq!(map.epoch, before.epoch + 2);
+        assert_eq!(map.ring, before.ring);
+        assert_eq!(map.members(), before.members());
+        assert!(!apply(&mut map, Command::FinishRebalance { since }));
     }
 
-    // Yknow, just for the hell of it, let's use channels this time.
-    let mut mgr_state = Manager {
-        nodes,
-        ring_hash,
-        reps: args.reps,
-    };
+    #[test]
+    fn splits_only_move_the_half_that_gets_a_new_home() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut ring = Strategy::new(StrategyKind::Range, 1, HashFn::default());
+        ring.add_node(addr(50052), DEFAULT_TOKENS);
+        ring.add_node(addr(50053), DEFAULT_TOKENS);
+        let mut map = PartitionMap {
+            epoch: 1,
+            ring,
+            ..PartitionMap::default()
+        };
+        let split = |at: &str| Command::Split { at: at.into() };
+
+        assert!(apply(&mut map, split("m")));
+        assert_eq!(map.epoch, 2);
+        // The split takes effect right away, but the keys from "m" on stay put until they've been
+        // handed over.
+        assert_eq!(map.ring.as_ranges().unwrap().ranges().len(), 2);
+        assert_eq!(map.owners("apple"), [addr(50052)]);
+        assert_eq!(map.owners("zebra"), [addr(50052)]);
+        let pending = map.pending.as_ref().unwrap();
+        assert_eq!(pending.ring.write_group("apple")[..], [addr(50052)]);
+        assert_eq!(pending.ring.write_group("zebra")[..], [addr(50053)]);
+        let since = pending.since;
+
+        // One change at a time.
+        assert!(!apply(&mut map, split("t")));
+        assert!(apply(&mut map, Command::FinishRebalance { since }));
+        assert_eq!(map.owners("zebra"), [addr(50053)]);
 
-    let (state_tx, state_rx) = std::sync::mpsc::channel();
+        // Nowhere better for the new half to go, so there's nothing to wait for.
+        assert!(apply(&mut map, split("t")));
+        assert!(map.pending.is_none());
+        assert!(!apply(&mut map, split("t")));
 
-    tokio::spawn({
-        let tx = state_tx.clone();
-        async move {
-            tx.send(());
+        // Only range-partitioned clusters can be split.
+        let mut map = PartitionMap::default();
+        assert!(!apply(&mut map, split("m")));
+    }
+
+    #[test]
+    fn store_specs_can_carry_token_counts() {
+        let spec: StoreSpec = "50052".parse().unwrap();
+        assert_eq!((spec.port, spec.tokens), (50052, None));
+        let spec: StoreSpec = "50053:256".parse().unwrap();
+        assert_eq!((spec.port, spec.tokens), (50053, Some(256)));
+        assert!("50053:0".parse::<StoreSpec>().is_err());
+        assert!("50053:lots".parse::<StoreSpec>().is_err());
+    }
+
+    fn local(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// Just enough of a storage node to answer the manager.
+    async fn fake_store(port: u16) {
+        let listener = TcpListener::bind(local(port)).await.unwrap();
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                handshake::accept(&mut conn, Role::Store, "fake-store")
+                    .await
+                    .unwrap();
+                let _ = mux::serve(conn, IDLE_TIMEOUT, |request: Envelope| async move {
+                    match request.msg {
+                        Message::Heartbeat => Message::Heartbeat,
+                        Message::UpdatePartitionMap { map } => Message::PartitionMap { map },
+                        _ => Message::PartitionMap {
+                            map: PartitionMap::default(),
+                        },
+                    }
+                })
+                .await;
+            });
         }
-    });
+    }
+
+    /// Asks every manager for the map until one that's leading answers.
+    async fn leader_map(ports: &[u16]) -> (u16, PartitionMap) {
+        for _ in 0..100 {
+            for &port in ports {
+                let Ok(conn) = Connection::connect(local(port), Role::Client, "test").await else {
+                    continue;
+                };
+                if let Ok(Message::PartitionMap { map }) = conn.call(Message::GetPartitionMap).await
+                {
+                    return (port, map);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("no manager took the lead");
+    }
+
+    #[tokio::test]
+    async fn managers_agree_on_the_map_and_outlive_their_leader() {
+        const MANAGERS: [u16; 3] = [50751, 50752, 50753];
+        const STORE: u16 = 50761;
+        let mut managers = HashMap::new();
+        for port in MANAGERS {
+            let config = Config {
+                port,
+                stores: vec![StoreSpec {
+                    port: STORE,
+                    tokens: None,
+                }],
+                tokens: DEFAULT_TOKENS,
+                placement: StrategyKind::Ring,
+                ring_hash: HashFn::default(),
+                max_range_keys: 100_000,
+                peers: MANAGERS.into_iter().filter(|&p| p != port).collect(),
+                reps: 1,
+                quorum: Quorum::majority(1),
+                detector: DetectorConfig {
+                    kind: DetectorKind::Timeout,
+                    interval: Duration::from_millis(100),
+                    timeout: Duration::from_millis(300),
+                    phi_threshold: 8.0,
+                },
+                rebalance_timeout: Duration::from_secs(600),
+                raft_state: None,
+            };
+            managers.insert(port, start(config).await.unwrap());
+        }
+
+        // The store wasn't up when the managers started, so it begins the log down.
+        let (_, map) = leader_map(&MANAGERS).await;
+        assert_eq!(map.health(local(STORE)), Health::Dead);
+        tokio::spawn(fake_store(STORE));
+        let (leader, map) = loop {
+            let (leader, map) = leader_map(&MANAGERS).await;
+            if map.health(local(STORE)) == Health::Alive {
+                break (leader, map);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
 
-    println!("Hello, world!");
+        // The others send clients to the leader.
+        let follower = MANAGERS.into_iter().find(|&p| p != leader).unwrap();
+        let conn = Connection::connect(local(follower), Role::Client, "test")
+            .await
+            .unwrap();
+        let reply = conn.call(Message::GetPartitionMap).await.unwrap();
+        assert_eq!(
+            reply,
+            Message::NotLeader {
+                leader: Some(local(leader))
+            }
+        );
+        let config = client::ClientConfig {
+            managers: MANAGERS.into_iter().map(local).collect(),
+            request_timeout: Duration::from_millis(500),
+            ..client::ClientConfig::default()
+        };
+        let client = client::Client::with_config(local(follower), config);
+        assert_eq!(*client.refresh_partition_map().await.unwrap(), map);
+        assert_eq!(client.mgr_addr(), local(leader));
+
+        // Whoever takes over already has everything the old leader committed.
+        managers.remove(&leader);
+        let rest: Vec<_> = MANAGERS.into_iter().filter(|&p| p != leader).collect();
+        let (new_leader, new_map) = leader_map(&rest).await;
+        assert_ne!(new_leader, leader);
+        assert_eq!(new_map.health(local(STORE)), Health::Alive);
+        assert!(new_map.epoch >= map.epoch);
+
+        // The client that was following the old leader finds the new one on its own.
+        let reached = client.refresh_partition_map().await.unwrap();
+        assert_eq!(client.mgr_addr(), local(new_leader));
+        assert!(reached.epoch >= map.epoch);
+    }
 }
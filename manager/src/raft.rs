@@ -0,0 +1,937 @@
+//! Just enough Raft (Ongaro and Ousterhout, "In Search of an Understandable Consensus Algorithm")
+//! to keep several managers agreeing on the partition map. The set of managers is fixed at startup,
+//! so there's no membership change. Once enough of the log has been applied, it gets squashed down
+//! to the map it adds up to (a [`Snapshot`]), and followers too far behind are sent that instead.
+//!
+//! Nothing in here does any IO. Every method takes the current time and hands back whatever
+//! messages need sending, and the manager's state loop does the rest. That keeps the rules easy to
+//! test without any real clocks or sockets.
+
+use comm::{
+    raft::{Command, LogEntry, LogIndex, Snapshot, Term},
+    Message, PartitionMap,
+};
+use rmp_serde::{from_read, Serializer};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fs::{self, File},
+    hash::BuildHasher,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How often the leader reminds everyone it's still there.
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+
+/// How long a follower waits to hear from a leader before trying to become one. Each wait is
+/// picked at random from this range so managers don't all stand at once and split the vote.
+const ELECTION_TIMEOUT_MS: (u64, u64) = (500, 1000);
+
+/// Most entries to send in one `AppendEntries`.
+const MAX_BATCH: usize = 64;
+
+/// Applied entries to let pile up in the log before squashing them into a snapshot.
+const COMPACT_AFTER: LogIndex = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What has to survive a restart, or we could vote twice in one term or forget entries we told
+/// the leader we had.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: Term,
+    pub voted_for: Option<SocketAddr>,
+    /// Entries after the snapshot, if there is one.
+    pub log: Vec<LogEntry>,
+    /// Everything before `log`, squashed.
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+}
+
+impl HardState {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        if !fs::exists(path)? {
+            return Ok(Self::default());
+        }
+        from_read(File::open(path)?).map_err(io::Error::other)
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.serialize(&mut Serializer::new(&mut buffer))
+            .map_err(io::Error::other)?;
+        Ok(buffer)
+    }
+
+    /// Puts state from [`HardState::encode`] in place at `path`. Goes through a temporary file so
+    /// a crash halfway through leaves the old state intact.
+    pub fn save(path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let mut tmp_path = path.to_owned().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // The rename only sticks once the directory it happened in is on disk too.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// A message for another manager.
+#[derive(Debug)]
+pub struct Outgoing {
+    pub to: SocketAddr,
+    pub msg: Message,
+}
+
+pub struct Raft {
+    id: SocketAddr,
+    /// The other managers.
+    peers: Vec<SocketAddr>,
+    hard: HardState,
+    /// Set whenever `hard` changes. The caller has to save it before sending anything.
+    dirty: bool,
+    state: State,
+    leader: Option<SocketAddr>,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    /// Who voted for us, while we're a candidate.
+    votes: HashSet<SocketAddr>,
+    /// While leading: the next entry to send each peer, and how much of our log we know they have.
+    next_index: HashMap<SocketAddr, LogIndex>,
+    match_index: HashMap<SocketAddr, LogIndex>,
+    /// A snapshot we've started from or been sent that hasn't been handed to the caller yet.
+    installed: Option<PartitionMap>,
+    /// xorshift state, for election timeouts.
+    rng: u64,
+}
+
+impl Raft {
+    pub fn new(id: SocketAddr, peers: Vec<SocketAddr>, hard: HardState, now: Instant) -> Self {
+        let seed = RandomState::new().hash_one(id);
+        Self::with_seed(id, peers, hard, now, seed)
+    }
+
+    /// Like [`Raft::new`], but with the election timeouts picked deterministically from `seed`.
+    pub fn with_seed(
+        id: SocketAddr,
+        peers: Vec<SocketAddr>,
+        hard: HardState,
+        now: Instant,
+        seed: u64,
+    ) -> Self {
+        // Only committed entries ever make it into a snapshot.
+        let committed = hard
+            .snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.last_index);
+        let installed = hard.snapshot.as_ref().map(|snapshot| snapshot.map.clone());
+        let mut raft = Self {
+            id,
+            peers,
+            hard,
+            dirty: false,
+            state: State::Follower,
+            leader: None,
+            commit_index: committed,
+            last_applied: committed,
+            election_deadline: now,
+            next_heartbeat: now,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            installed,
+            rng: seed | 1,
+        };
+        // On our own there's nobody to wait for.
+        if !raft.peers.is_empty() {
+            raft.reset_election_deadline(now);
+        }
+        raft
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state == State::Leader
+    }
+
+    /// Whether we're leading and have applied everything earlier leaders committed. Until then,
+    /// whatever we've built from the log could be missing entries.
+    pub fn is_caught_up(&self) -> bool {
+        self.is_leader()
+            && self.last_applied > 0
+            && self.term_at(self.last_applied) == self.hard.term
+    }
+
+    /// Who we think is leading, if anyone.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.leader
+    }
+
+    pub fn term(&self) -> Term {
+        self.hard.term
+    }
+
+    /// The entries since the snapshot, if there is one.
+    pub fn log(&self) -> &[LogEntry] {
+        &self.hard.log
+    }
+
+    pub fn has_snapshot(&self) -> bool {
+        self.hard.snapshot.is_some()
+    }
+
+    /// Our persistent state, if it's changed since last time we asked.
+    pub fn take_dirty(&mut self) -> Option<&HardState> {
+        std::mem::take(&mut self.dirty).then_some(&self.hard)
+    }
+
+    /// Entries that have been committed since last time we asked, in order. If we've taken on a
+    /// snapshot since, it comes first, as a `Bootstrap` with the map it adds up to.
+    pub fn take_committed(&mut self) -> Vec<Command> {
+        let start = self.offset(self.last_applied + 1);
+        let end = self.offset(self.commit_index + 1);
+        self.last_applied = self.commit_index;
+        let installed = self.installed.take();
+        let installed = installed.map(|map| Command::Bootstrap { map });
+        let committed = self.hard.log[start..end]
+            .iter()
+            .map(|entry| entry.command.clone());
+        installed.into_iter().chain(committed).collect()
+    }
+
+    /// Whether enough of the log has been applied that it's worth squashing.
+    pub fn wants_compaction(&self) -> bool {
+        self.last_applied - self.snapshot_index() >= COMPACT_AFTER
+    }
+
+    /// Squashes every entry applied so far into a snapshot of `map`, which has to be what they add
+    /// up to, and drops them from the log.
+    pub fn compact(&mut self, map: PartitionMap) {
+        let last_index = self.last_applied;
+        if last_index <= self.snapshot_index() {
+            return;
+        }
+        let last_term = self.term_at(last_index);
+        let end = self.offset(last_index) + 1;
+        self.hard.log.drain(..end);
+        self.hard.snapshot = Some(Snapshot {
+            last_index,
+            last_term,
+            map,
+        });
+        self.dirty = true;
+    }
+
+    /// Appends `command` to the log if we're leading. It goes out with the next heartbeat.
+    pub fn propose(&mut self, command: Command) -> bool {
+        if !self.is_leader() {
+            return false;
+        }
+        self.hard.log.push(LogEntry {
+            term: self.hard.term,
+            command,
+        });
+        self.dirty = true;
+        self.advance_commit();
+        true
+    }
+
+    /// Call every so often. Starts elections when the leader's gone quiet, and sends heartbeats
+    /// while we're leading.
+    pub fn tick(&mut self, now: Instant) -> Vec<Outgoing> {
+        match self.state {
+            State::Leader if now >= self.next_heartbeat => {
+                self.next_heartbeat = now + HEARTBEAT_PERIOD;
+                self.peers
+                    .iter()
+                    .map(|&peer| self.append_for(peer))
+                    .collect()
+            }
+            State::Leader => Vec::new(),
+            State::Follower | State::Candidate if now >= self.election_deadline => {
+                self.start_election(now)
+            }
+            State::Follower | State::Candidate => Vec::new(),
+        }
+    }
+
+    pub fn handle_request_vote(
+        &mut self,
+        term: Term,
+        candidate: SocketAddr,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+        now: Instant,
+    ) -> Message {
+        if term > self.hard.term {
+            self.step_down(term);
+        }
+        // Only vote for candidates whose log has everything ours does, or committed entries could
+        // get lost.
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.last_log_term(), self.last_index());
+        let granted = term == self.hard.term
+            && up_to_date
+            && self.hard.voted_for.is_none_or(|voted| voted == candidate);
+        if granted {
+            self.hard.voted_for = Some(candidate);
+            self.dirty = true;
+            self.reset_election_deadline(now);
+        }
+        Message::Vote {
+            term: self.hard.term,
+            granted,
+        }
+    }
+
+    pub fn handle_vote(
+        &mut self,
+        from: SocketAddr,
+        term: Term,
+        granted: bool,
+        now: Instant,
+    ) -> Vec<Outgoing> {
+        if term > self.hard.term {
+            self.step_down(term);
+            return Vec::new();
+        }
+        if self.state != State::Candidate || term != self.hard.term || !granted {
+            return Vec::new();
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.majority() {
+            self.become_leader(now)
+        } else {
+            Vec::new()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_append_entries(
+        &mut self,
+        term: Term,
+        leader: SocketAddr,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<LogEntry>,
+        leader_commit: LogIndex,
+        now: Instant,
+    ) -> Message {
+        if term < self.hard.term {
+            return Message::Appended {
+                term: self.hard.term,
+                success: false,
+                match_index: 0,
+            };
+        }
+        if term > self.hard.term || self.state != State::Follower {
+            self.step_down(term);
+        }
+        self.leader = Some(leader);
+        self.reset_election_deadline(now);
+
+        // Everything up to our snapshot was committed, so it's the same as what the leader has.
+        // Skip ahead to where our log picks up.
+        let (mut prev_log_index, mut prev_log_term, mut entries) =
+            (prev_log_index, prev_log_term, entries);
+        if let Some(snapshot) = &self.hard.snapshot {
+            if prev_log_index < snapshot.last_index {
+                let skip = (snapshot.last_index - prev_log_index) as usize;
+                if entries.len() < skip {
+                    return Message::Appended {
+                        term: self.hard.term,
+                        success: true,
+                        match_index: prev_log_index + entries.len() as LogIndex,
+                    };
+                }
+                entries.drain(..skip);
+                prev_log_index = snapshot.last_index;
+                prev_log_term = snapshot.last_term;
+            }
+        }
+
+        if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+            return Message::Appended {
+                term: self.hard.term,
+                success: false,
+                match_index: 0,
+            };
+        }
+
+        let mut index = prev_log_index;
+        for entry in entries {
+            index += 1;
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                // Conflicts with what the leader has, so it and everything after it has to go.
+                self.hard.log.truncate(self.offset(index));
+            }
+            self.hard.log.push(entry);
+            self.dirty = true;
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(index);
+        }
+        Message::Appended {
+            term: self.hard.term,
+            success: true,
+            match_index: index,
+        }
+    }
+
+    /// Takes on the leader's snapshot if it has more than we've committed. Entries we have past it
+    /// stay if they line up with it.
+    pub fn handle_install_snapshot(
+        &mut self,
+        term: Term,
+        leader: SocketAddr,
+        snapshot: Snapshot,
+        now: Instant,
+    ) -> Message {
+        if term < self.hard.term {
+            return Message::Appended {
+                term: self.hard.term,
+                success: false,
+                match_index: 0,
+            };
+        }
+        if term > self.hard.term || self.state != State::Follower {
+            self.step_down(term);
+        }
+        self.leader = Some(leader);
+        self.reset_election_deadline(now);
+
+        let last_index = snapshot.last_index;
+        if last_index > self.commit_index {
+            let lines_up =
+                last_index <= self.last_index() && self.term_at(last_index) == snapshot.last_term;
+            match lines_up {
+                true => drop(self.hard.log.drain(..=self.offset(last_index))),
+                false => self.hard.log.clear(),
+            }
+            self.commit_index = last_index;
+            self.last_applied = last_index;
+            self.installed = Some(snapshot.map.clone());
+            self.hard.snapshot = Some(snapshot);
+            self.dirty = true;
+        }
+        Message::Appended {
+            term: self.hard.term,
+            success: true,
+            match_index: last_index,
+        }
+    }
+
+    pub fn handle_appended(
+        &mut self,
+        from: SocketAddr,
+        term: Term,
+        success: bool,
+        match_index: LogIndex,
+    ) -> Vec<Outgoing> {
+        if term > self.hard.term {
+            self.step_down(term);
+            return Vec::new();
+        }
+        if !self.is_leader() || term != self.hard.term {
+            return Vec::new();
+        }
+
+        if success {
+            let matched = self.match_index.entry(from).or_default();
+            *matched = (*matched).max(match_index);
+            self.next_index.insert(from, *matched + 1);
+            self.advance_commit();
+            Vec::new()
+        } else {
+            // Their log doesn't line up with ours that far back. Back up and try again.
+            let next = self.next_index.entry(from).or_insert(1);
+            *next = next.saturating_sub(1).max(1);
+            vec![self.append_for(from)]
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let managers = self.peers.len() + 1;
+        managers / 2 + 1
+    }
+
+    fn snapshot_index(&self) -> LogIndex {
+        self.hard
+            .snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.last_index)
+    }
+
+    fn last_index(&self) -> LogIndex {
+        self.snapshot_index() + self.hard.log.len() as LogIndex
+    }
+
+    /// Where entry `index` is in `hard.log`. It can't be one the snapshot covers.
+    fn offset(&self, index: LogIndex) -> usize {
+        (index - self.snapshot_index() - 1) as usize
+    }
+
+    /// The term of entry `index`. It can't be one from before the snapshot's last.
+    fn term_at(&self, index: LogIndex) -> Term {
+        match &self.hard.snapshot {
+            Some(snapshot) if index == snapshot.last_index => snapshot.last_term,
+            _ if index == 0 => 0,
+            _ => self.hard.log[self.offset(index)].term,
+        }
+    }
+
+    fn last_log_term(&self) -> Term {
+        self.term_at(self.last_index())
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let (min, max) = ELECTION_TIMEOUT_MS;
+        let wait = min + self.rng % (max - min + 1);
+        self.election_deadline = now + Duration::from_millis(wait);
+    }
+
+    /// Someone has a newer term than us, or won the election we were in.
+    fn step_down(&mut self, term: Term) {
+        if term > self.hard.term {
+            self.hard.term = term;
+            self.hard.voted_for = None;
+            self.dirty = true;
+            self.leader = None;
+        }
+        self.state = State::Follower;
+        self.votes.clear();
+    }
+
+    fn start_election(&mut self, now: Instant) -> Vec<Outgoing> {
+        self.hard.term += 1;
+        self.hard.voted_for = Some(self.id);
+        self.dirty = true;
+        self.state = State::Candidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        self.reset_election_deadline(now);
+        if self.votes.len() >= self.majority() {
+            return self.become_leader(now);
+        }
+
+        let msg = Message::RequestVote {
+            term: self.hard.term,
+            candidate: self.id,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_log_term(),
+        };
+        self.peers
+            .iter()
+            .map(|&to| Outgoing {
+                to,
+                msg: msg.clone(),
+            })
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Vec<Outgoing> {
+        self.state = State::Leader;
+        self.leader = Some(self.id);
+        self.votes.clear();
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&peer| (peer, next)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        // We can only count replicas for entries from our own term, so get one in right away to
+        // commit anything earlier leaders didn't get to.
+        self.propose(Command::Noop);
+        self.next_heartbeat = now;
+        self.tick(now)
+    }
+
+    fn append_for(&self, peer: SocketAddr) -> Outgoing {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1);
+        if let Some(snapshot) = &self.hard.snapshot {
+            // What they need next is gone from our log, so they get the map it added up to.
+            if next <= snapshot.last_index {
+                return Outgoing {
+                    to: peer,
+                    msg: Message::InstallSnapshot {
+                        term: self.hard.term,
+                        leader: self.id,
+                        snapshot: snapshot.clone(),
+                    },
+                };
+            }
+        }
+        let prev_log_index = next - 1;
+        let entries = self.hard.log[self.offset(next)..]
+            .iter()
+            .take(MAX_BATCH)
+            .cloned()
+            .collect();
+        Outgoing {
+            to: peer,
+            msg: Message::AppendEntries {
+                term: self.hard.term,
+                leader: self.id,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index),
+                entries,
+                leader_commit: self.commit_index,
+            },
+        }
+    }
+
+    /// Commits the newest entry from our term that a majority has.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.hard.term {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|&&m| m >= index).count();
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::Health;
+
+    fn addr(i: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 50051 + i))
+    }
+
+    /// Managers that pass messages straight to each other, except to the ones in `cut_off`.
+    struct Cluster {
+        nodes: HashMap<SocketAddr, Raft>,
+        cut_off: HashSet<SocketAddr>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(size: u16) -> Self {
+            let now = Instant::now();
+            let ids: Vec<_> = (0..size).map(addr).collect();
+            let nodes = ids
+                .iter()
+                .map(|&id| {
+                    let peers = ids.iter().copied().filter(|&p| p != id).collect();
+                    let seed = u64::from(id.port());
+                    (
+                        id,
+                        Raft::with_seed(id, peers, HardState::default(), now, seed),
+                    )
+                })
+                .collect();
+            Self {
+                nodes,
+                cut_off: HashSet::new(),
+                now,
+            }
+        }
+
+        fn deliver(&mut self, from: SocketAddr, mut outbox: Vec<Outgoing>) {
+            while let Some(Outgoing { to, msg }) = outbox.pop() {
+                if self.cut_off.contains(&from) || self.cut_off.contains(&to) {
+                    continue;
+                }
+                let now = self.now;
+                let node = self.nodes.get_mut(&to).unwrap();
+                let reply = match msg {
+                    Message::RequestVote {
+                        term,
+                        candidate,
+                        last_log_index,
+                        last_log_term,
+                    } => node.handle_request_vote(
+                        term,
+                        candidate,
+                        last_log_index,
+                        last_log_term,
+                        now,
+                    ),
+                    Message::AppendEntries {
+                        term,
+                        leader,
+                        prev_log_index,
+                        prev_log_term,
+                        entries,
+                        leader_commit,
+                    } => node.handle_append_entries(
+                        term,
+                        leader,
+                        prev_log_index,
+                        prev_log_term,
+                        entries,
+                        leader_commit,
+                        now,
+                    ),
+                    Message::InstallSnapshot {
+                        term,
+                        leader,
+                        snapshot,
+                    } => node.handle_install_snapshot(term, leader, snapshot, now),
+                    other => panic!("unexpected request {other:?}"),
+                };
+                let sender = self.nodes.get_mut(&from).unwrap();
+                let more = match reply {
+                    Message::Vote { term, granted } => sender.handle_vote(to, term, granted, now),
+                    Message::Appended {
+                        term,
+                        success,
+                        match_index,
+                    } => sender.handle_appended(to, term, success, match_index),
+                    other => panic!("unexpected reply {other:?}"),
+                };
+                // Replies go out from the original sender.
+                for out in more {
+                    self.deliver(from, vec![out]);
+                }
+            }
+        }
+
+        /// Lets `millis` pass in 10ms steps, ticking everyone.
+        fn run(&mut self, millis: u64) {
+            for _ in 0..millis / 10 {
+                self.now += Duration::from_millis(10);
+                let ids: Vec<_> = self.nodes.keys().copied().collect();
+                for id in ids {
+                    let now = self.now;
+                    let outbox = self.nodes.get_mut(&id).unwrap().tick(now);
+                    self.deliver(id, outbox);
+                }
+            }
+        }
+
+        fn leaders(&self) -> Vec<SocketAddr> {
+            let mut leaders: Vec<_> = self
+                .nodes
+                .iter()
+                .filter(|(id, node)| node.is_leader() && !self.cut_off.contains(id))
+                .map(|(id, _)| *id)
+                .collect();
+            leaders.sort();
+            leaders
+        }
+    }
+
+    fn set_health(port: u16, health: Health) -> Command {
+        Command::SetHealth {
+            node: SocketAddr::from(([127, 0, 0, 1], port)),
+            health,
+        }
+    }
+
+    #[test]
+    fn elects_one_leader_and_replicates_to_everyone() {
+        let mut cluster = Cluster::new(3);
+        cluster.run(2000);
+        let leaders = cluster.leaders();
+        assert_eq!(leaders.len(), 1);
+        let leader = leaders[0];
+        for node in cluster.nodes.values() {
+            assert_eq!(node.leader(), Some(leader));
+        }
+
+        assert!(cluster
+            .nodes
+            .get_mut(&leader)
+            .unwrap()
+            .propose(set_health(50052, Health::Dead)));
+        cluster.run(300);
+        for node in cluster.nodes.values_mut() {
+            assert_eq!(
+                node.take_committed(),
+                [Command::Noop, set_health(50052, Health::Dead)]
+            );
+        }
+
+        // Only the leader takes proposals.
+        let follower = *cluster.nodes.keys().find(|&&id| id != leader).unwrap();
+        assert!(!cluster
+            .nodes
+            .get_mut(&follower)
+            .unwrap()
+            .propose(Command::Noop));
+    }
+
+    #[test]
+    fn a_lone_manager_leads_itself() {
+        let mut raft = Raft::new(addr(0), Vec::new(), HardState::default(), Instant::now());
+        assert!(raft.tick(Instant::now()).is_empty());
+        assert!(raft.is_leader());
+        assert!(raft.propose(set_health(50052, Health::Alive)));
+        assert_eq!(
+            raft.take_committed(),
+            [Command::Noop, set_health(50052, Health::Alive)]
+        );
+        assert_eq!(raft.take_dirty().unwrap().log.len(), 2);
+        assert!(raft.take_dirty().is_none());
+    }
+
+    #[test]
+    fn a_new_leader_keeps_what_was_committed_and_drops_what_wasnt() {
+        let mut cluster = Cluster::new(3);
+        cluster.run(2000);
+        let old_leader = cluster.leaders()[0];
+        cluster
+            .nodes
+            .get_mut(&old_leader)
+            .unwrap()
+            .propose(set_health(50052, Health::Dead));
+        cluster.run(300);
+
+        // Cut off from everyone, the old leader takes a write it can never commit.
+        cluster.cut_off.insert(old_leader);
+        cluster
+            .nodes
+            .get_mut(&old_leader)
+            .unwrap()
+            .propose(set_health(50053, Health::Dead));
+        cluster.run(2000);
+        let new_leader = cluster.leaders()[0];
+        assert_ne!(new_leader, old_leader);
+        cluster
+            .nodes
+            .get_mut(&new_leader)
+            .unwrap()
+            .propose(set_health(50052, Health::Alive));
+
+        // Back together, the old leader steps down and comes around to the new leader's log.
+        cluster.cut_off.clear();
+        cluster.run(500);
+        assert_eq!(cluster.leaders(), [new_leader]);
+        let logs: Vec<Vec<Command>> = cluster
+            .nodes
+            .values_mut()
+            .map(|node| node.take_committed())
+            .collect();
+        for log in &logs {
+            assert_eq!(log, &logs[0]);
+            assert!(log.contains(&set_health(50052, Health::Dead)));
+            assert!(log.contains(&set_health(50052, Health::Alive)));
+            assert!(!log.contains(&set_health(50053, Health::Dead)));
+        }
+    }
+
+    #[test]
+    fn followers_behind_the_snapshot_catch_up_from_it() {
+        let mut cluster = Cluster::new(3);
+        cluster.run(2000);
+        let leader = cluster.leaders()[0];
+        let behind = *cluster.nodes.keys().find(|&&id| id != leader).unwrap();
+        cluster.cut_off.insert(behind);
+        for port in 50052..50062 {
+            cluster
+                .nodes
+                .get_mut(&leader)
+                .unwrap()
+                .propose(set_health(port, Health::Dead));
+        }
+        cluster.run(300);
+
+        // Everyone who kept up squashes what they've applied.
+        let map = PartitionMap {
+            epoch: 11,
+            ..PartitionMap::default()
+        };
+        for (id, node) in &mut cluster.nodes {
+            if *id != behind {
+                assert_eq!(node.take_committed().len(), 11);
+                node.compact(map.clone());
+                assert!(node.log().is_empty());
+            }
+        }
+
+        // What `behind` is missing is gone from the leader's log, so it gets the map in place of
+        // everything up to it, and then the entries after it.
+        cluster.cut_off.clear();
+        cluster.run(300);
+        let node = cluster.nodes.get_mut(&leader).unwrap();
+        node.propose(set_health(50052, Health::Alive));
+        cluster.run(300);
+        let node = cluster.nodes.get_mut(&behind).unwrap();
+        assert_eq!(
+            node.take_committed(),
+            [
+                Command::Bootstrap { map: map.clone() },
+                set_health(50052, Health::Alive)
+            ]
+        );
+
+        // After a restart, it starts from the snapshot. The entry after it stays in the log until
+        // a leader says it's committed.
+        let hard = node.take_dirty().unwrap().clone();
+        assert_eq!(hard.log.len(), 1);
+        let mut node = Raft::new(behind, Vec::new(), hard, cluster.now);
+        assert_eq!(node.take_committed(), [Command::Bootstrap { map }]);
+        assert_eq!(node.log().len(), 1);
+    }
+
+    #[test]
+    fn votes_go_to_candidates_that_are_caught_up() {
+        let now = Instant::now();
+        let mut raft = Raft::new(addr(0), vec![addr(1), addr(2)], HardState::default(), now);
+        raft.handle_append_entries(
+            1,
+            addr(1),
+            0,
+            0,
+            vec![LogEntry {
+                term: 1,
+                command: Command::Noop,
+            }],
+            1,
+            now,
+        );
+
+        // addr(2) missed the entry, so doesn't get our vote.
+        let vote = raft.handle_request_vote(2, addr(2), 0, 0, now);
+        assert_eq!(
+            vote,
+            Message::Vote {
+                term: 2,
+                granted: false
+            }
+        );
+        let vote = raft.handle_request_vote(2, addr(1), 1, 1, now);
+        assert_eq!(
+            vote,
+            Message::Vote {
+                term: 2,
+                granted: true
+            }
+        );
+        // One vote per term.
+        let vote = raft.handle_request_vote(2, addr(2), 1, 1, now);
+        assert_eq!(
+            vote,
+            Message::Vote {
+                term: 2,
+                granted: false
+            }
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Background repair for keys nobody reads. Read repair only fixes what clients touch, so every so
+//! often we pick a replica we share keys with, compare Merkle trees over those keys, and swap just
+//! the keys in buckets where the trees disagree.
+
+use comm::{Entry, Epoch, ErrorCode, Message, PartitionMap};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    coordinator, gossip,
+    merkle::{self, MerkleTree},
+    stats,
+    table::Table,
+    wal, PARTITION_MAP, TABLE_SERVICE,
+};
+
+/// How often we sync with one of our peers. Each round picks the next peer in line.
+const ANTI_ENTROPY_PERIOD: Duration = Duration::from_secs(30);
+
+/// Peer to the tree its current round is being answered from, and the epoch it's for.
+type Trees = HashMap<SocketAddr, (Epoch, Arc<MerkleTree>)>;
+
+/// A round asks for the root first and then works its way down, so the tree gets built when the
+/// root is asked for and the rest of the levels come out of the same one.
+static TREES: LazyLock<Mutex<Trees>> = LazyLock::new(|| Mutex::new(Trees::new()));
+
+/// Trees only line up if both sides split keys up the same way, so we only compare with peers
+/// using the same partition map. Returns the response to send instead if they aren't.
+fn check_epoch(epoch: Epoch) -> Option<Message> {
+    let current = PARTITION_MAP.read().expect("Lock poisoned :(").epoch;
+    if epoch < current {
+        Some(Message::StaleEpoch { current })
+    } else if epoch > current {
+        Some(Message::error(
+            ErrorCode::Busy,
+            format!("haven't caught up to epoch {epoch} yet"),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Keys in `table` that both `us` and `peer` are supposed to have.
+fn shared<'a>(
+    table: &'a Table,
+    map: &'a PartitionMap,
+    us: SocketAddr,
+    peer: SocketAddr,
+) -> impl Iterator<Item = (&'a String, &'a Entry)> {
+    table.iter().filter(move |(key, _)| {
+        let owners = map.owners(key);
+        owners.contains(&us) && owners.contains(&peer)
+    })
+}
+
+fn tree<'a>(shared: impl Iterator<Item = (&'a String, &'a Entry)>) -> MerkleTree {
+    MerkleTree::build(shared.map(|(key, entry)| (key.as_str(), entry)))
+}
+
+fn in_buckets<'a>(
+    shared: impl Iterator<Item = (&'a String, &'a Entry)>,
+    buckets: &[u32],
+) -> Vec<(String, Entry)> {
+    shared
+        .filter(|(key, _)| buckets.contains(&merkle::bucket(key)))
+        .map(|(key, entry)| (key.clone(), entry.clone()))
+        .collect()
+}
+
+/// Which of `ours` the peer that sent `theirs` for the same buckets is missing something of.
+fn behind(ours: Vec<(String, Entry)>, theirs: &HashMap<String, Entry>) -> Vec<(String, Entry)> {
+    ours.into_iter()
+        .filter(|(key, entry)| match theirs.get(key) {
+            Some(their_entry) => their_entry.clone().merge(entry.clone()),
+            None => true,
+        })
+        .collect()
+}
+
+/// Tree over every key we have that `peer` is also supposed to have. Hashing a big table takes a
+/// while, so it happens off the runtime's worker threads.
+async fn shared_tree(peer: SocketAddr) -> MerkleTree {
+    // Don't hold up map updates for it either.
+    let map = PARTITION_MAP.read().expect("Lock poisoned :(").clone();
+    tokio::task::spawn_blocking(move || {
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        tree(shared(&table, &map, coordinator::node_addr(), peer))
+    })
+    .await
+    .expect("Building the Merkle tree panicked")
+}
+
+/// Every key we have in `buckets` that `peer` is also supposed to have.
+async fn shared_entries(peer: SocketAddr, buckets: Vec<u32>) -> Vec<(String, Entry)> {
+    let map = PARTITION_MAP.read().expect("Lock poisoned :(").clone();
+    tokio::task::spawn_blocking(move || {
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        in_buckets(
+            shared(&table, &map, coordinator::node_addr(), peer),
+            &buckets,
+        )
+    })
+    .await
+    .expect("Collecting buckets panicked")
+}
+
+pub async fn handle_get_merkle(
+    peer: SocketAddr,
+    epoch: Epoch,
+    level: u32,
+    indices: Vec<u32>,
+) -> Message {
+    if let Some(response) = check_epoch(epoch) {
+        return response;
+    }
+    let cached = match TREES.lock().expect("Lock poisoned :(").get(&peer) {
+        Some((built_for, tree)) if level > 0 && *built_for == epoch => Some(tree.clone()),
+        _ => None,
+    };
+    let tree = match cached {
+        Some(tree) => tree,
+        None => {
+            let tree = Arc::new(shared_tree(peer).await);
+            TREES
+                .lock()
+                .expect("Lock poisoned :(")
+                .insert(peer, (epoch, tree.clone()));
+            tree
+        }
+    };
+    match tree.hashes(level, &indices) {
+        Some(hashes) => Message::Merkle { hashes },
+        None => Message::error(ErrorCode::BadRequest, "no such node in the tree"),
+    }
+}
+
+pub async fn handle_get_buckets(peer: SocketAddr, epoch: Epoch, buckets: Vec<u32>) -> Message {
+    if let Some(response) = check_epoch(epoch) {
+        return response;
+    }
+    Message::Entries {
+        entries: shared_entries(peer, buckets).await,
+    }
+}
+
+/// Brings us and `peer` in line for every key we both own.
+async fn sync_with(peer: SocketAddr, epoch: Epoch) -> comm::Result<()> {
+    let us = coordinator::node_addr();
+    let tree = shared_tree(peer).await;
+
+    let buckets = merkle::diff(&tree, |level, indices| async move {
+        let msg = Message::GetMerkle {
+            peer: us,
+            epoch,
+            level,
+            indices,
+        };
+        match coordinator::call_peer(peer, msg).await? {
+            Message::Merkle { hashes } => Ok(hashes),
+            other => Err(comm::unexpected(other)),
+        }
+    })
+    .await?;
+    if buckets.is_empty() {
+        return Ok(());
+    }
+
+    let msg = Message::GetBuckets {
+        peer: us,
+        epoch,
+        buckets: buckets.clone(),
+    };
+    let theirs: HashMap<String, Entry> = match coordinator::call_peer(peer, msg).await? {
+        Message::Entries { entries } => entries.into_iter().collect(),
+        other => return Err(comm::unexpected(other)),
+    };
+
+    // What we had before taking theirs, so we only send back what they're missing.
+    let ours = shared_entries(peer, buckets.clone()).await;
+    let pulled = wal::merge_all(theirs.clone()).await?;
+
+    let mut pushed = 0;
+    for (key, entry) in behind(ours, &theirs) {
+        coordinator::push(peer, key, entry).await?;
+        pushed += 1;
+    }
+
+    stats::ANTI_ENTROPY_KEYS_PULLED.add(pulled as u64);
+    stats::ANTI_ENTROPY_KEYS_PUSHED.add(pushed);
+    eprintln!(
+        "[INFO] Anti-entropy with {peer}: {} buckets differed, pulled {pulled} keys, pushed {pushed}",
+        buckets.len()
+    );
+    Ok(())
+}
+
+pub async fn anti_entropy_loop() {
+    let mut interval = tokio::time::interval(ANTI_ENTROPY_PERIOD);
+    let mut turn = 0;
+    loop {
+        interval.tick().await;
+        let (epoch, peers) = {
+            let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+            let us = coordinator::node_addr();
+            let peers: Vec<_> = map
+                .members()
+                .into_iter()
+                .filter(|&node| node != us && !gossip::is_down(&map, node))
+                .collect();
+            (map.epoch, peers)
+        };
+        if peers.is_empty() {
+            continue;
+        }
+
+        let peer = peers[turn % peers.len()];
+        turn += 1;
+        stats::ANTI_ENTROPY_ROUNDS.incr();
+        if let Err(e) = sync_with(peer, epoch).await {
+            eprintln!("[WARN] Anti-entropy with {peer} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table;
+    use comm::{
+        placement::{Placement, Strategy, DEFAULT_TOKENS},
+        ring_hash::RingHash,
+        version::Dot,
+    };
+
+    #[tokio::test]
+    async fn tables_a_few_keys_apart_converge_in_one_round() {
+        let (a, b) = (
+            SocketAddr::from(([127, 0, 0, 1], 50052)),
+            SocketAddr::from(([127, 0, 0, 1], 50053)),
+        );
+        let mut ring = Strategy::from(RingHash::new(2));
+        ring.add_node(a, DEFAULT_TOKENS);
+        ring.add_node(b, DEFAULT_TOKENS);
+        let map = PartitionMap {
+            epoch: 1,
+            ring,
+            ..PartitionMap::default()
+        };
+        let write = |table: &mut Table, key: &str, value: &str, counter| {
+            let dot = Dot {
+                node: "writer".into(),
+                counter,
+            };
+            let entry = table::stamp(table, key, Some(value.into()), None, dot);
+            table::merge(table, key.into(), entry);
+        };
+
+        let mut on_a = Table::new();
+        for i in 0..500 {
+            write(&mut on_a, &format!("key_{i}"), "v", i);
+        }
+        let mut on_b = on_a.clone();
+        write(&mut on_a, "key_3", "newer on a", 1000);
+        write(&mut on_b, "key_300", "newer on b", 1001);
+        write(&mut on_a, "only_on_a", "v", 1002);
+        write(&mut on_b, "only_on_b", "v", 1003);
+        // Both wrote without seeing the other's, so both versions stay.
+        write(&mut on_a, "key_42", "from a", 1004);
+        write(&mut on_b, "key_42", "from b", 1005);
+
+        // One round, run from a.
+        let b_tree = tree(shared(&on_b, &map, b, a));
+        let buckets = merkle::diff(&tree(shared(&on_a, &map, a, b)), |level, indices| {
+            let hashes = b_tree.hashes(level, &indices).unwrap();
+            async move { Ok(hashes) }
+        })
+        .await
+        .unwrap();
+        assert!(!buckets.is_empty() && buckets.len() <= 5);
+        let theirs: HashMap<_, _> = in_buckets(shared(&on_b, &map, b, a), &buckets)
+            .into_iter()
+            .collect();
+        let ours = in_buckets(shared(&on_a, &map, a, b), &buckets);
+        for (key, entry) in behind(ours, &theirs) {
+            table::merge(&mut on_b, key, entry);
+        }
+        for (key, entry) in theirs {
+            table::merge(&mut on_a, key, entry);
+        }
+
+        assert_eq!(on_a, on_b);
+        assert_eq!(on_a["key_42"].values(), ["from a", "from b"]);
+    }
+}
@@ -0,0 +1,681 @@
+//! Client reads and writes go through whichever replica the client reached first. That node
+//! coordinates: it fans the request out to the rest of the key's write group and answers once
+//! enough of them have.
+
+use comm::{
+    pool::Pool, range_map, Consistency, Entry, ErrorCode, Message, PartitionMap, Quorum, Role,
+    VectorClock,
+};
+use std::{collections::BTreeMap, future::Future, net::SocketAddr, sync::LazyLock, time::Duration};
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    dots, gossip, hints, stats, table, wal, NODE_ADDR, NODE_ID, PARTITION_MAP, TABLE_SERVICE,
+};
+
+/// How long a replica gets to answer before we count it as a failure.
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections to keep open per peer replica.
+const PEER_POOL_SIZE: usize = 2;
+
+/// Connections to the other storage nodes.
+static PEERS: LazyLock<Pool> = LazyLock::new(|| {
+    let node_id = NODE_ID
+        .get()
+        .expect("Node ID is set before we accept connections");
+    Pool::new(
+        Role::Store,
+        node_id.clone(),
+        PEER_POOL_SIZE,
+        REPLICA_TIMEOUT,
+    )
+});
+
+pub fn node_addr() -> SocketAddr {
+    *NODE_ADDR
+        .get()
+        .expect("Address is set before we accept connections")
+}
+
+/// The replicas to use for `key` and how many of them have to answer. Without a partition map
+/// from the manager, we're the only replica there is.
+fn plan(map: &PartitionMap, key: &str, consistency: Consistency) -> (Vec<SocketAddr>, Quorum) {
+    let quorum = map.quorum.with(consistency);
+    let mut group = map.owners(key);
+    if group.is_empty() {
+        group.push(node_addr());
+    }
+    group.truncate(quorum.n);
+    // The ring may have fewer nodes than the cluster is meant to keep copies on.
+    let quorum = Quorum {
+        n: group.len(),
+        r: quorum.r.min(group.len()),
+        w: quorum.w.min(group.len()),
+    };
+    (group, quorum)
+}
+
+/// Where one replica's copy of a write goes.
+#[derive(Clone, Copy)]
+struct Target {
+    owner: SocketAddr,
+    /// `owner` looked down when we planned the write, see [`gossip::is_down`].
+    owner_down: bool,
+    /// Next healthy node along the ring, to hold the write for `owner` if it can't take it.
+    fallback: Option<SocketAddr>,
+}
+
+/// Like [`plan`], but also lines up a fallback for each owner from the nodes after them on the
+/// ring. Owners known to be down get first pick.
+///
+/// During a rebalance, nodes gaining the key get the write too, so they don't miss anything while
+/// the old owners hand it over. They have to acknowledge it on top of the usual quorum, since
+/// reads won't go to them until the handoff is done.
+fn plan_write(map: &PartitionMap, key: &str, consistency: Consistency) -> (Vec<Target>, Quorum) {
+    let (mut group, mut quorum) = plan(map, key, consistency);
+    let gaining = map.gaining(key);
+    let gaining: Vec<_> = gaining.into_iter().take(quorum.n).collect();
+    quorum.w += gaining.len();
+    group.extend(gaining);
+    let preference = map.preference_list(key);
+    let spares = preference
+        .iter()
+        .skip(map.owners(key).len())
+        .copied()
+        .filter(|addr| !gossip::is_down(map, *addr));
+
+    let targets = assign_fallbacks(group, spares, |addr| gossip::is_down(map, addr));
+    (targets, quorum)
+}
+
+fn assign_fallbacks(
+    owners: Vec<SocketAddr>,
+    mut spares: impl Iterator<Item = SocketAddr>,
+    is_down: impl Fn(SocketAddr) -> bool,
+) -> Vec<Target> {
+    let mut targets: Vec<_> = owners
+        .into_iter()
+        .map(|owner| Target {
+            owner,
+            owner_down: is_down(owner),
+            fallback: None,
+        })
+        .collect();
+    for down in [true, false] {
+        for target in targets.iter_mut().filter(|t| t.owner_down == down) {
+            target.fallback = spares.next();
+        }
+    }
+    targets
+}
+
+/// Gives up on `request` if the replica takes too long.
+async fn within<T>(request: impl Future<Output = comm::Result<T>>) -> comm::Result<T> {
+    timeout(REPLICA_TIMEOUT, request)
+        .await
+        .unwrap_or(Err(comm::Error::Timeout))
+}
+
+type Answer<T> = (SocketAddr, comm::Result<T>);
+
+/// The replicas that answered in time to make the quorum, plus a way to hear from the rest.
+struct Quorate<T> {
+    answers: Vec<(SocketAddr, T)>,
+    stragglers: mpsc::UnboundedReceiver<Answer<T>>,
+}
+
+/// Runs `op` against every replica in `group` at once, and returns as soon as `needed` of them
+/// have answered. The stragglers keep going in the background so every replica still gets the
+/// request.
+async fn fan_out<T, F, Fut>(
+    group: &[SocketAddr],
+    needed: usize,
+    op: F,
+) -> Result<Quorate<T>, Message>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = comm::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for &addr in group {
+        let tx = tx.clone();
+        let request = op(addr);
+        tokio::spawn(async move {
+            let _ = tx.send((addr, request.await));
+        });
+    }
+    drop(tx);
+
+    let mut answers = Vec::with_capacity(needed);
+    let mut failed = 0;
+    while answers.len() < needed && group.len() - failed >= needed {
+        let Some((addr, result)) = rx.recv().await else {
+            break;
+        };
+        match result {
+            Ok(answer) => answers.push((addr, answer)),
+            Err(e) => {
+                eprintln!("[WARN] Replica {addr} didn't answer: {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    if answers.len() < needed {
+        return Err(Message::error(
+            ErrorCode::Unavailable,
+            format!(
+                "only {} of the {needed} replicas we needed answered",
+                answers.len()
+            ),
+        ));
+    }
+    Ok(Quorate {
+        answers,
+        stragglers: rx,
+    })
+}
+
+/// Reads `key` from `r` replicas and returns every version that none of them has seen
+/// superseded. Replicas that turn out to be missing some of it get repaired in the background.
+pub async fn get(key: String, consistency: Consistency) -> Message {
+    let (group, quorum) = plan(
+        &PARTITION_MAP.read().expect("Lock poisoned :("),
+        &key,
+        consistency,
+    );
+    let read = |addr| within(read_replica(addr, key.clone()));
+    let quorate = match fan_out(&group, quorum.r, read).await {
+        Ok(quorate) => quorate,
+        Err(response) => return response,
+    };
+
+    let mut entry = Entry::default();
+    for (_, answer) in &quorate.answers {
+        if let Some(answer) = answer {
+            entry.merge(answer.clone());
+        }
+    }
+    let response = if entry.is_deleted() {
+        Message::NotFound
+    } else {
+        Message::Found {
+            values: entry.values(),
+            context: entry.context(),
+        }
+    };
+
+    let Quorate {
+        answers,
+        mut stragglers,
+    } = quorate;
+    for (addr, answer) in answers {
+        repair(addr, &key, answer, &entry);
+    }
+    tokio::spawn(async move {
+        while let Some((addr, answer)) = stragglers.recv().await {
+            if let Ok(answer) = answer {
+                repair(addr, &key, answer, &entry);
+            }
+        }
+    });
+    response
+}
+
+/// Reads up to `limit` live keys from `start` on, stopping at `end` or the end of the range `start`
+/// is in, whichever comes first. `next` says where the rest picks up.
+pub async fn scan(
+    start: String,
+    end: Option<String>,
+    limit: usize,
+    consistency: Consistency,
+) -> Message {
+    if limit == 0 {
+        return Message::error(ErrorCode::BadRequest, "scans need a limit of at least 1");
+    }
+    let range_end = {
+        let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+        let Some(ranges) = map.ring.as_ranges() else {
+            return Message::error(
+                ErrorCode::BadRequest,
+                "only range-partitioned clusters can scan",
+            );
+        };
+        ranges.bounds(&start).1.map(str::to_owned)
+    };
+    let stop = match (&end, &range_end) {
+        (Some(end), Some(range_end)) => Some(end.min(range_end).clone()),
+        (end, range_end) => end.clone().or(range_end.clone()),
+    };
+
+    let (group, quorum) = plan(
+        &PARTITION_MAP.read().expect("Lock poisoned :("),
+        &start,
+        consistency,
+    );
+    let read = |addr| within(scan_replica(addr, start.clone(), stop.clone(), limit));
+    let quorate = match fan_out(&group, quorum.r, read).await {
+        Ok(quorate) => quorate,
+        Err(response) => return response,
+    };
+
+    // A replica that filled its limit might have stopped short of keys the others sent, so past
+    // the earliest place one of them stopped, we can't say what's missing.
+    let cutoff = quorate
+        .answers
+        .iter()
+        .filter(|(_, entries)| entries.len() == limit)
+        .filter_map(|(_, entries)| entries.last().map(|(key, _)| key.clone()))
+        .min();
+    let mut merged = BTreeMap::new();
+    for (_, entries) in quorate.answers {
+        for (key, entry) in entries {
+            if cutoff.as_ref().is_none_or(|cutoff| key <= *cutoff) {
+                table::merge(&mut merged, key, entry);
+            }
+        }
+    }
+
+    let mut live = merged.into_iter().filter(|(_, entry)| !entry.is_deleted());
+    let items = live
+        .by_ref()
+        .take(limit)
+        .map(|(key, entry)| (key, entry.values()))
+        .collect();
+    let next = match (live.next(), cutoff) {
+        (Some((key, _)), _) => Some(key),
+        (None, Some(cutoff)) => Some(range_map::after(&cutoff)),
+        // Nothing left in this range, so pick up at the next one if the scan goes that far.
+        (None, None) => range_end.filter(|range_end| Some(range_end) == stop.as_ref()),
+    };
+    Message::Scanned { items, next }
+}
+
+/// Whether a replica that answered with `theirs` is missing anything in `merged`.
+fn is_stale(theirs: Option<Entry>, merged: &Entry) -> bool {
+    match theirs {
+        Some(mut theirs) => theirs.merge(merged.clone()),
+        None => !merged.siblings.is_empty(),
+    }
+}
+
+/// Pushes `merged` to the replica at `addr` if it answered a read with something older. Doesn't
+/// wait for it to land.
+fn repair(addr: SocketAddr, key: &str, theirs: Option<Entry>, merged: &Entry) {
+    if !is_stale(theirs, merged) {
+        return;
+    }
+    let (key, merged) = (key.to_owned(), merged.clone());
+    tokio::spawn(async move {
+        match within(write_replica(addr, key.clone(), merged, None)).await {
+            Ok(()) => {
+                eprintln!("[INFO] Repaired '{key}' on {addr}");
+                stats::READ_REPAIRS.incr();
+            }
+            Err(e) => {
+                eprintln!("[WARN] Couldn't repair '{key}' on {addr}: {e}");
+                stats::READ_REPAIR_FAILURES.incr();
+            }
+        }
+    });
+}
+
+/// Writes `value` for `key` (or deletes it, if `None`) on every replica in the write group,
+/// superseding the versions in `context`. Returns `done` once `w` of them have it, counting
+/// fallbacks holding a hint for an owner that's down.
+pub async fn write(
+    key: String,
+    value: Option<String>,
+    context: Option<VectorClock>,
+    consistency: Consistency,
+    done: Message,
+) -> Message {
+    let (targets, quorum) = plan_write(
+        &PARTITION_MAP.read().expect("Lock poisoned :("),
+        &key,
+        consistency,
+    );
+    let node_id = NODE_ID
+        .get()
+        .expect("Node ID is set before we accept connections");
+    let at_least = TABLE_SERVICE
+        .read()
+        .expect("Lock poisoned :(")
+        .get(&key)
+        .unwrap_or(&Entry::default())
+        .next_dot(node_id, context.as_ref().unwrap_or(&VectorClock::default()));
+    let dot = match dots::next(at_least).await {
+        Ok(dot) => dot,
+        Err(e) => {
+            return Message::error(
+                ErrorCode::Internal,
+                format!("couldn't save dot counter: {e}"),
+            )
+        }
+    };
+    let entry = table::stamp(
+        &TABLE_SERVICE.read().expect("Lock poisoned :("),
+        &key,
+        value,
+        context,
+        dot,
+    );
+    let group: Vec<_> = targets.iter().map(|target| target.owner).collect();
+    let acks = fan_out(&group, quorum.w, |owner| {
+        let target = *targets
+            .iter()
+            .find(|target| target.owner == owner)
+            .expect("Every owner has a target");
+        write_or_hand_off(target, key.clone(), entry.clone())
+    })
+    .await;
+    match acks {
+        Ok(_) => done,
+        Err(response) => response,
+    }
+}
+
+async fn read_replica(addr: SocketAddr, key: String) -> comm::Result<Option<Entry>> {
+    if addr == node_addr() {
+        return Ok(TABLE_SERVICE
+            .read()
+            .expect("Lock poisoned :(")
+            .get(&key)
+            .cloned());
+    }
+    match PEERS.call(addr, Message::ReplicaGet { key }).await? {
+        Message::Replica { entry } => Ok(entry),
+        other => Err(comm::unexpected(other)),
+    }
+}
+
+async fn scan_replica(
+    addr: SocketAddr,
+    start: String,
+    end: Option<String>,
+    limit: usize,
+) -> comm::Result<Vec<(String, Entry)>> {
+    if addr == node_addr() {
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        return Ok(table::scan(&table, &start, end.as_deref(), limit));
+    }
+    match PEERS
+        .call(addr, Message::ReplicaScan { start, end, limit })
+        .await?
+    {
+        Message::Entries { entries } => Ok(entries),
+        other => Err(comm::unexpected(other)),
+    }
+}
+
+/// Writes to the target's owner, or leaves the write with its fallback if the owner is down or
+/// doesn't answer.
+async fn write_or_hand_off(target: Target, key: String, entry: Entry) -> comm::Result<()> {
+    let Target {
+        owner,
+        owner_down,
+        fallback,
+    } = target;
+    let Some(fallback) = fallback else {
+        // Nowhere else to go, so try the owner even if the manager thinks it's down.
+        return within(write_replica(owner, key, entry, None)).await;
+    };
+    if !owner_down {
+        match within(write_replica(owner, key.clone(), entry.clone(), None)).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("[WARN] {owner} didn't take '{key}' ({e}), leaving it with {fallback}")
+            }
+        }
+    }
+    within(write_replica(fallback, key, entry, Some(owner))).await
+}
+
+/// Pushes a version of `key` straight to the replica at `addr`, for when something other than a
+/// client write finds out it's behind.
+pub async fn push(addr: SocketAddr, key: String, entry: Entry) -> comm::Result<()> {
+    within(write_replica(addr, key, entry, None)).await
+}
+
+/// Sends `msg` to another storage node, giving up if it takes too long.
+pub async fn call_peer(addr: SocketAddr, msg: Message) -> comm::Result<Message> {
+    within(PEERS.call(addr, msg)).await
+}
+
+async fn write_replica(
+    addr: SocketAddr,
+    key: String,
+    entry: Entry,
+    hint: Option<SocketAddr>,
+) -> comm::Result<()> {
+    if addr == node_addr() {
+        match hint {
+            Some(owner) => hints::record(owner, key, entry).await?,
+            None => {
+                wal::merge(key, entry).await?;
+            }
+        }
+        return Ok(());
+    }
+    match PEERS
+        .call(addr, Message::ReplicaPut { key, entry, hint })
+        .await?
+    {
+        Message::DonePut => Ok(()),
+        other => Err(comm::unexpected(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::{
+        placement::{Placement, Strategy, DEFAULT_TOKENS},
+        ring_hash::RingHash,
+        version::{Dot, Sibling},
+    };
+
+    #[test]
+    fn only_replicas_missing_versions_are_stale() {
+        let sibling = |node: &str, value: &str| Sibling {
+            dot: Dot {
+                node: node.into(),
+                counter: 1,
+            },
+            context: VectorClock::default(),
+            value: Some(value.into()),
+            timestamp: 0,
+        };
+        let left = Entry {
+            siblings: vec![sibling("a", "left")],
+        };
+        let mut merged = left.clone();
+        merged.merge(Entry {
+            siblings: vec![sibling("b", "right")],
+        });
+
+        assert!(!is_stale(Some(merged.clone()), &merged));
+        assert!(is_stale(Some(left), &merged));
+        assert!(is_stale(None, &merged));
+        // Nobody has the key, so there's nothing to repair.
+        assert!(!is_stale(None, &Entry::default()));
+    }
+
+    #[test]
+    fn down_owners_get_first_pick_of_fallbacks() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let owners = vec![addr(1), addr(2), addr(3)];
+        let targets = assign_fallbacks(owners, [addr(4)].into_iter(), |a| a == addr(3));
+        let fallbacks: Vec<_> = targets.iter().map(|t| t.fallback).collect();
+        assert_eq!(fallbacks, [None, None, Some(addr(4))]);
+        assert!(targets[2].owner_down);
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[derive(Clone, Copy)]
+    enum Stub {
+        Acks,
+        Fails,
+        Hangs,
+    }
+
+    /// Stands in for the replica at `addr` answering a request, the way `stubs[port - 1]` says.
+    fn replica(
+        stubs: &[Stub],
+        addr: SocketAddr,
+    ) -> impl Future<Output = comm::Result<()>> + Send + 'static {
+        let stub = stubs[addr.port() as usize - 1];
+        async move {
+            match stub {
+                Stub::Acks => Ok(()),
+                Stub::Fails => Err(comm::Error::Timeout),
+                Stub::Hangs => std::future::pending().await,
+            }
+        }
+    }
+
+    fn group(size: u16) -> Vec<SocketAddr> {
+        (1..=size).map(addr).collect()
+    }
+
+    #[tokio::test]
+    async fn answers_once_enough_replicas_ack() {
+        let stubs = [Stub::Acks, Stub::Hangs, Stub::Acks];
+        let group = group(3);
+        let read = fan_out(&group, 2, |addr| replica(&stubs, addr));
+        let quorate = timeout(Duration::from_secs(1), read)
+            .await
+            .expect("Waited on the replica that hangs")
+            .unwrap_or_else(|_| panic!("Two replicas acked"));
+        let mut acked: Vec<_> = quorate.answers.iter().map(|(addr, _)| *addr).collect();
+        acked.sort();
+        assert_eq!(acked, [addr(1), addr(3)]);
+    }
+
+    #[tokio::test]
+    async fn unavailable_once_too_few_replicas_can_answer() {
+        // Once two have failed, three can't answer, so there's no waiting on the one that hangs.
+        let stubs = [Stub::Acks, Stub::Fails, Stub::Hangs, Stub::Fails];
+        let group = group(4);
+        let read = fan_out(&group, 3, |addr| replica(&stubs, addr));
+        let response = timeout(Duration::from_secs(1), read)
+            .await
+            .expect("Waited on the replica that hangs");
+        assert!(matches!(
+            response,
+            Err(Message::Error {
+                code: ErrorCode::Unavailable,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn requests_can_ask_for_their_own_quorum() {
+        let mut ring = Strategy::from(RingHash::new(3));
+        for node in group(3) {
+            ring.add_node(node, DEFAULT_TOKENS);
+        }
+        let map = PartitionMap {
+            epoch: 1,
+            ring,
+            quorum: Quorum::majority(3),
+            ..PartitionMap::default()
+        };
+        let one = |r, w| Consistency {
+            n: None,
+            r: Some(r),
+            w: Some(w),
+        };
+
+        // Only the first owner answers: not enough for the cluster's majority, but enough for a
+        // request that only wants one.
+        let first = map.owners("key")[0];
+        let stubs: Vec<_> = group(3)
+            .into_iter()
+            .map(|node| match node == first {
+                true => Stub::Acks,
+                false => Stub::Fails,
+            })
+            .collect();
+        for (consistency, answers) in [(Consistency::default(), false), (one(1, 1), true)] {
+            let (owners, quorum) = plan(&map, "key", consistency);
+            assert_eq!(owners.len(), 3);
+            let read = fan_out(&owners, quorum.r, |addr| replica(&stubs, addr)).await;
+            assert_eq!(read.is_ok(), answers);
+
+            let (targets, quorum) = plan_write(&map, "key", consistency);
+            let owners: Vec<_> = targets.iter().map(|target| target.owner).collect();
+            assert_eq!(owners.len(), 3);
+            let write = fan_out(&owners, quorum.w, |addr| replica(&stubs, addr)).await;
+            assert_eq!(write.is_ok(), answers);
+        }
+
+        // Asking for every replica to answer, or for fewer replicas.
+        assert_eq!(plan(&map, "key", one(3, 3)).1, Quorum { n: 3, r: 3, w: 3 });
+        let fewer = Consistency {
+            n: Some(1),
+            ..Consistency::default()
+        };
+        let (owners, quorum) = plan(&map, "key", fewer);
+        assert_eq!(owners, [first]);
+        assert_eq!(quorum, Quorum { n: 1, r: 1, w: 1 });
+
+        // Asking for more replicas than the cluster keeps gets all of them, not an error.
+        let more = Consistency {
+            n: Some(5),
+            r: None,
+            w: Some(5),
+        };
+        let (owners, quorum) = plan(&map, "key", more);
+        assert_eq!(owners.len(), 3);
+        assert_eq!(quorum, Quorum { n: 3, r: 2, w: 3 });
+        let stubs = [Stub::Acks; 3];
+        assert!(fan_out(&owners, quorum.w, |addr| replica(&stubs, addr))
+            .await
+            .is_ok());
+
+        // A ring with fewer nodes than the cluster wants copies can still reach everyone on it.
+        let mut small = map.clone();
+        small.ring.remove_node(addr(3));
+        let (owners, quorum) = plan(&small, "key", one(3, 3));
+        assert_eq!(owners.len(), 2);
+        assert_eq!(quorum, Quorum { n: 2, r: 2, w: 2 });
+    }
+
+    /// Makes this process a lone storage node, with no partition map to send writes elsewhere.
+    fn alone() {
+        let addr = NODE_ADDR.get_or_init(|| SocketAddr::from(([127, 0, 0, 1], 50052)));
+        NODE_ID.get_or_init(|| format!("store@{addr}"));
+    }
+
+    #[tokio::test]
+    async fn racing_writes_through_one_node_both_survive() {
+        alone();
+        let consistency = Consistency::default();
+        let (left, right) = tokio::join!(
+            write(
+                "race".into(),
+                Some("left".into()),
+                None,
+                consistency,
+                Message::DonePut
+            ),
+            write(
+                "race".into(),
+                Some("right".into()),
+                None,
+                consistency,
+                Message::DonePut
+            ),
+        );
+        assert_eq!(left, Message::DonePut);
+        assert_eq!(right, Message::DonePut);
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        assert_eq!(table["race"].values(), ["left", "right"]);
+    }
+}
@@ -0,0 +1,133 @@
+//! Counters for the dots this node stamps the writes it coordinates with (see [`comm::version`]).
+//! Two writes with the same dot look like one, so each counter is only ever handed out once. One
+//! counter covers every key, and it only goes up. It's never worked out from the table, which might
+//! not have the key at all.
+//!
+//! Before handing out a counter past the last bound written to disk, we write down a new bound a
+//! block ahead. After a restart, we pick up from the last bound, which is past anything we could
+//! have handed out before.
+
+use comm::version::Dot;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+use tokio::sync::Mutex;
+
+use crate::{snapshot, table};
+
+/// How far ahead of the counter to write down a bound, so most writes don't have to wait on disk.
+const LEASE_BLOCK: u64 = 10_000;
+
+/// The last counter handed out.
+static LAST: AtomicU64 = AtomicU64::new(0);
+/// The bound on disk. Counters past it can't go out until it's moved up.
+static LEASED: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Where the bound is kept. Unset means nothing is kept.
+static LEASE: OnceLock<Mutex<Lease>> = OnceLock::new();
+
+struct Lease {
+    path: PathBuf,
+    until: u64,
+}
+
+fn load(path: &Path) -> io::Result<u64> {
+    let bytes = fs::read(path)?;
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| io::Error::other(format!("{path:?} isn't a dot counter")))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Writes down `until`, all at once or not at all.
+fn save(path: &Path, until: u64) -> io::Result<()> {
+    let mut tmp_path = path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&until.to_le_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    snapshot::sync_parent(path)
+}
+
+/// Picks up the counter from where it was kept at `path` before a restart, and keeps it there
+/// from now on.
+pub fn recover(path: Option<PathBuf>) -> io::Result<()> {
+    let Some(path) = path else {
+        // Nothing to pick up from, so go by the clock, in microseconds. That's past anything we
+        // handed out before a restart, unless we were coordinating a million writes a second.
+        LAST.store(table::now_millis() * 1000, Ordering::SeqCst);
+        return Ok(());
+    };
+    let until = match fs::exists(&path)? {
+        true => load(&path)?,
+        false => 0,
+    };
+    eprintln!("[INFO] Handing out dots from {until}");
+    LAST.store(until, Ordering::SeqCst);
+    LEASED.store(until, Ordering::SeqCst);
+    LEASE
+        .set(Mutex::new(Lease { path, until }))
+        .map_err(|_| io::Error::other("dot counter recovered twice"))
+}
+
+/// Moves the bound on disk up past `counter`.
+async fn extend(counter: u64) -> io::Result<()> {
+    let Some(lease) = LEASE.get() else {
+        return Ok(());
+    };
+    let mut lease = lease.lock().await;
+    // Whoever had the lock before us might have moved it far enough already.
+    if counter <= lease.until {
+        return Ok(());
+    }
+    let until = counter + LEASE_BLOCK;
+    let path = lease.path.clone();
+    tokio::task::spawn_blocking(move || save(&path, until))
+        .await
+        .expect("Saving the dot counter panicked")?;
+    lease.until = until;
+    LEASED.store(until, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Hands out the dot for a new write, with a counter nobody's had before that's at least the one
+/// in `at_least`.
+pub async fn next(at_least: Dot) -> io::Result<Dot> {
+    let bump = |last: u64| (last + 1).max(at_least.counter);
+    let last = LAST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(bump(last)))
+        .expect("The update always succeeds");
+    let counter = bump(last);
+    if counter > LEASED.load(Ordering::SeqCst) {
+        extend(counter).await?;
+    }
+    Ok(Dot {
+        node: at_least.node,
+        counter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_survive_restarts() {
+        let dir = std::env::temp_dir().join(format!("dots-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("node.dots");
+        save(&path, 10).unwrap();
+        save(&path, 20_010).unwrap();
+        assert_eq!(load(&path).unwrap(), 20_010);
+        fs::write(&path, b"short").unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! Dynamo-style gossip between storage nodes. Every round we swap partition maps and heartbeats
+//! with one peer, so a map the manager sent anyone eventually reaches everyone, and we can tell
+//! who's down without asking the manager. If the manager goes away, the cluster keeps going with
+//! the last map it handed out.
+
+use comm::{Member, Message, PartitionMap};
+use fnv::FnvHashMap;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{coordinator, table, PARTITION_MAP};
+
+const GOSSIP_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a node's heartbeat can sit still before we count it as down. News about most nodes
+/// reaches us secondhand, so this has to cover a few rounds of it going around.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything we've heard about the other nodes.
+#[derive(Default)]
+struct Membership {
+    /// The newest report about each node, and when its heartbeat last moved.
+    heard: FnvHashMap<SocketAddr, (Member, Instant)>,
+}
+
+impl Membership {
+    /// Keeps whichever reports are newer than what we had. Reports about `us` are old news.
+    fn merge(&mut self, us: SocketAddr, members: impl IntoIterator<Item = Member>, now: Instant) {
+        for member in members {
+            if member.addr == us {
+                continue;
+            }
+            match self.heard.get(&member.addr) {
+                Some((known, _)) if known.version() >= member.version() => {}
+                _ => {
+                    self.heard.insert(member.addr, (member, now));
+                }
+            }
+        }
+    }
+
+    /// Whether `addr` has gone quiet. `None` if we've never heard of it.
+    fn is_silent(&self, addr: SocketAddr, now: Instant) -> Option<bool> {
+        let (_, moved) = self.heard.get(&addr)?;
+        Some(now.duration_since(*moved) >= FAILURE_TIMEOUT)
+    }
+}
+
+static MEMBERSHIP: LazyLock<Mutex<Membership>> =
+    LazyLock::new(|| Mutex::new(Membership::default()));
+
+/// When we started, see [`Member::generation`].
+static GENERATION: LazyLock<u64> = LazyLock::new(table::now_millis);
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+/// Our own report plus the newest we've heard about everyone else.
+fn roster() -> Vec<Member> {
+    let us = Member {
+        addr: coordinator::node_addr(),
+        generation: *GENERATION,
+        heartbeat: HEARTBEAT.load(Ordering::Relaxed),
+    };
+    let membership = MEMBERSHIP.lock().expect("Lock poisoned :(");
+    std::iter::once(us)
+        .chain(membership.heard.values().map(|(member, _)| *member))
+        .collect()
+}
+
+/// Whether `addr` should be treated as down. Gossip has the last word, since it's fresher than
+/// the manager's map and keeps working without the manager. Nodes nobody has gossiped about yet
+/// go by `map`.
+pub fn is_down(map: &PartitionMap, addr: SocketAddr) -> bool {
+    if addr == coordinator::node_addr() {
+        return false;
+    }
+    let silent = MEMBERSHIP
+        .lock()
+        .expect("Lock poisoned :(")
+        .is_silent(addr, Instant::now());
+    silent.unwrap_or_else(|| map.is_down(addr))
+}
+
+fn absorb(map: PartitionMap, members: Vec<Member>) {
+    crate::adopt_partition_map(map);
+    MEMBERSHIP.lock().expect("Lock poisoned :(").merge(
+        coordinator::node_addr(),
+        members,
+        Instant::now(),
+    );
+}
+
+pub fn handle_gossip(map: PartitionMap, members: Vec<Member>) -> Message {
+    absorb(map, members);
+    Message::Gossip {
+        map: PARTITION_MAP.read().expect("Lock poisoned :(").clone(),
+        members: roster(),
+    }
+}
+
+pub async fn gossip_loop() {
+    let mut interval = tokio::time::interval(GOSSIP_PERIOD);
+    let mut turn = 0;
+    loop {
+        interval.tick().await;
+        HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+        let map = PARTITION_MAP.read().expect("Lock poisoned :(").clone();
+        let us = coordinator::node_addr();
+        // Down nodes included, or we'd never notice them coming back.
+        let peers: Vec<_> = map.members().into_iter().filter(|&n| n != us).collect();
+        if peers.is_empty() {
+            continue;
+        }
+
+        let peer = peers[turn % peers.len()];
+        turn += 1;
+        let msg = Message::Gossip {
+            map,
+            members: roster(),
+        };
+        // Peers that don't answer just don't get news this round. Their silence says the rest.
+        if let Ok(Message::Gossip { map, members }) = coordinator::call_peer(peer, msg).await {
+            absorb(map, members);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(port: u16, generation: u64, heartbeat: u64) -> Member {
+        Member {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            generation,
+            heartbeat,
+        }
+    }
+
+    #[test]
+    fn only_moving_heartbeats_keep_a_node_alive() {
+        let us = member(50052, 1, 0).addr;
+        let them = member(50053, 1, 0).addr;
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+        let mut membership = Membership::default();
+        assert_eq!(membership.is_silent(them, start), None);
+
+        membership.merge(us, [member(50052, 1, 5), member(50053, 1, 5)], start);
+        assert_eq!(membership.is_silent(us, start), None);
+        assert_eq!(membership.is_silent(them, later(9)), Some(false));
+
+        // Hearing the same heartbeat again, secondhand, isn't news.
+        membership.merge(us, [member(50053, 1, 5)], later(8));
+        assert_eq!(membership.is_silent(them, later(10)), Some(true));
+
+        membership.merge(us, [member(50053, 1, 6)], later(11));
+        assert_eq!(membership.is_silent(them, later(12)), Some(false));
+
+        // Restarted: counting from zero again, but in a newer generation.
+        membership.merge(us, [member(50053, 2, 0)], later(30));
+        assert_eq!(membership.is_silent(them, later(31)), Some(false));
+        membership.merge(us, [member(50053, 1, 100)], later(40));
+        assert_eq!(membership.heard[&them].0, member(50053, 2, 0));
+    }
+}
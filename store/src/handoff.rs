@@ -0,0 +1,252 @@
+//! Moving keys when the ring changes. While the manager has a rebalance pending, every node pulls
+//! the keys it's about to own from everyone else, a few Merkle buckets at a time. The manager
+//! watches how far along everyone is and only switches reads over to the new ring once they've
+//! all caught up. Writes go to both sets of owners in the meantime, so nothing slips through.
+
+use comm::{placement::Placement, Entry, Epoch, ErrorCode, Message, PartitionMap};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{coordinator, gossip, merkle, stats, table::Table, wal, PARTITION_MAP, TABLE_SERVICE};
+
+/// How often to check for a new rebalance, or retry one that stalled.
+const HANDOFF_CHECK_PERIOD: Duration = Duration::from_secs(1);
+/// Buckets to ask for at once.
+const BATCH_BUCKETS: u32 = 8;
+const TOTAL_BUCKETS: u32 = 1 << merkle::DEPTH;
+
+/// Most keys per second to pull in, so a handoff doesn't starve client requests.
+static RATE: OnceLock<u64> = OnceLock::new();
+
+/// The rebalance we're pulling keys for, and how many buckets we have so far.
+static PROGRESS: LazyLock<Mutex<(Epoch, u32)>> = LazyLock::new(|| Mutex::new((0, 0)));
+
+/// The keys a node is gaining from us, by bucket.
+type Gained = Vec<Vec<String>>;
+
+/// Node pulling from us to what it's gaining, and the rebalance that's for.
+type Pulling = HashMap<SocketAddr, (Epoch, Arc<Gained>)>;
+
+/// Worked out in one pass over the table when a node first asks, so the batches after that only
+/// look up their own keys. Keys written since then went to it directly, since writes go to both
+/// rings during a rebalance.
+static GAINING: LazyLock<Mutex<Pulling>> = LazyLock::new(|| Mutex::new(Pulling::new()));
+
+pub fn set_rate(keys_per_sec: u64) {
+    RATE.set(keys_per_sec.max(1))
+        .expect("Handoff rate set twice?");
+}
+
+/// Every key in `table` that `peer` is gaining under `map`, by bucket.
+fn gained_keys(table: &Table, map: &PartitionMap, peer: SocketAddr) -> Gained {
+    let mut gained = vec![Vec::new(); TOTAL_BUCKETS as usize];
+    for key in table.keys() {
+        if map.gaining(key).contains(&peer) {
+            gained[merkle::bucket(key) as usize].push(key.clone());
+        }
+    }
+    gained
+}
+
+/// What `table` has of the keys in `gained` that are in `buckets`.
+fn gained_entries(table: &Table, gained: &Gained, buckets: &[u32]) -> Vec<(String, Entry)> {
+    buckets
+        .iter()
+        .filter_map(|&bucket| gained.get(bucket as usize))
+        .flatten()
+        .filter_map(|key| Some((key.clone(), table.get(key)?.clone())))
+        .collect()
+}
+
+pub async fn handle_get_range(peer: SocketAddr, since: Epoch, buckets: Vec<u32>) -> Message {
+    let map = {
+        let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+        match &map.pending {
+            Some(pending) if pending.since == since => {}
+            _ if map.epoch < since => {
+                return Message::error(
+                    ErrorCode::Busy,
+                    format!("haven't caught up to epoch {since} yet"),
+                )
+            }
+            _ => return Message::StaleEpoch { current: map.epoch },
+        }
+        map.clone()
+    };
+
+    let cached = match GAINING.lock().expect("Lock poisoned :(").get(&peer) {
+        Some((built_for, gained)) if *built_for == since => Some(gained.clone()),
+        _ => None,
+    };
+    // The last batch, so it won't be back for this rebalance.
+    let last = buckets.contains(&(TOTAL_BUCKETS - 1));
+    // Both walk the table, so do them in one go under one lock, off the runtime's worker threads.
+    let (gained, entries) = tokio::task::spawn_blocking(move || {
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        let gained = cached.unwrap_or_else(|| Arc::new(gained_keys(&table, &map, peer)));
+        let entries = gained_entries(&table, &gained, &buckets);
+        (gained, entries)
+    })
+    .await
+    .expect("Collecting handoff keys panicked");
+    let mut gaining = GAINING.lock().expect("Lock poisoned :(");
+    if last {
+        gaining.remove(&peer);
+    } else {
+        gaining.insert(peer, (since, gained));
+    }
+    stats::HANDOFF_KEYS_SENT.add(entries.len() as u64);
+    Message::Entries { entries }
+}
+
+pub fn handle_get_handoff() -> Message {
+    let (since, done) = *PROGRESS.lock().expect("Lock poisoned :(");
+    Message::Handoff {
+        since,
+        done,
+        total: TOTAL_BUCKETS,
+    }
+}
+
+/// Pulls in `buckets` of the rebalance that started at `since` from every other node we can
+/// reach. Returns how many keys we got.
+async fn pull(since: Epoch, buckets: Vec<u32>) -> comm::Result<u64> {
+    let us = coordinator::node_addr();
+    let sources: Vec<_> = {
+        let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+        map.members()
+            .into_iter()
+            .filter(|&node| node != us && !gossip::is_down(&map, node))
+            .collect()
+    };
+
+    let mut pulled = 0;
+    for source in sources {
+        let msg = Message::GetRange {
+            peer: us,
+            since,
+            buckets: buckets.clone(),
+        };
+        let entries = match coordinator::call_peer(source, msg).await? {
+            Message::Entries { entries } => entries,
+            other => return Err(comm::unexpected(other)),
+        };
+        pulled += entries.len() as u64;
+        wal::merge_all(entries).await?;
+    }
+    Ok(pulled)
+}
+
+pub async fn handoff_loop() {
+    let rate = *RATE.get().expect("Handoff rate is set before we start");
+    let mut interval = tokio::time::interval(HANDOFF_CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        let since = {
+            let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+            match &map.pending {
+                // Nodes on their way out have nothing to pull in.
+                Some(pending) if pending.ring.contains(coordinator::node_addr()) => pending.since,
+                _ => continue,
+            }
+        };
+        let done = {
+            let mut progress = PROGRESS.lock().expect("Lock poisoned :(");
+            if progress.0 != since {
+                eprintln!("[INFO] Pulling in keys for the rebalance at epoch {since}");
+                *progress = (since, 0);
+            }
+            progress.1
+        };
+
+        for start in (done..TOTAL_BUCKETS).step_by(BATCH_BUCKETS as usize) {
+            // Someone finished it, or started another, while we were at it.
+            let current = PARTITION_MAP
+                .read()
+                .expect("Lock poisoned :(")
+                .pending
+                .clone();
+            if current.is_none_or(|pending| pending.since != since) {
+                break;
+            }
+
+            let end = (start + BATCH_BUCKETS).min(TOTAL_BUCKETS);
+            let pulled = match pull(since, (start..end).collect()).await {
+                Ok(pulled) => pulled,
+                Err(e) => {
+                    // Picks up from here next time around.
+                    eprintln!("[WARN] Handoff stalled at bucket {start}: {e}");
+                    break;
+                }
+            };
+            stats::HANDOFF_KEYS_RECEIVED.add(pulled);
+            PROGRESS.lock().expect("Lock poisoned :(").1 = end;
+            eprintln!(
+                "[INFO] Handoff for epoch {since}: {end}/{TOTAL_BUCKETS} buckets, {pulled} keys in this batch"
+            );
+            tokio::time::sleep(Duration::from_secs_f64(pulled as f64 / rate as f64)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::{
+        placement::{Placement, Strategy, DEFAULT_TOKENS},
+        ring_hash::RingHash,
+        Quorum, Rebalance,
+    };
+
+    #[test]
+    fn only_hands_over_keys_the_peer_is_gaining() {
+        let nodes: Vec<_> = (0..3)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 50052 + i)))
+            .collect();
+        let mut ring = Strategy::from(RingHash::new(1));
+        ring.add_node(nodes[0], DEFAULT_TOKENS);
+        ring.add_node(nodes[1], DEFAULT_TOKENS);
+        let mut next = ring.clone();
+        next.add_node(nodes[2], DEFAULT_TOKENS);
+        let map = PartitionMap {
+            epoch: 2,
+            ring,
+            quorum: Quorum::majority(1),
+            pending: Some(Rebalance {
+                ring: next,
+                since: 2,
+            }),
+            ..PartitionMap::default()
+        };
+
+        let mut table: Table = (0..64)
+            .map(|i| (format!("key_{i}"), Entry::default()))
+            .collect();
+        let everything: Vec<_> = (0..TOTAL_BUCKETS).collect();
+        let gained = gained_keys(&table, &map, nodes[2]);
+        let entries = gained_entries(&table, &gained, &everything);
+        assert!(!entries.is_empty() && entries.len() < table.len());
+        for (key, _) in &entries {
+            assert_eq!(map.gaining(key), [nodes[2]]);
+        }
+        // Nothing moves to nodes that already own everything they're going to.
+        let nothing = gained_keys(&table, &map, nodes[0]);
+        assert!(gained_entries(&table, &nothing, &everything).is_empty());
+
+        // Batches get what the table has now, minus anything that's gone since.
+        let (key, _) = entries[0].clone();
+        table.remove(&key);
+        let later = gained_entries(&table, &gained, &everything);
+        assert_eq!(later.len(), entries.len() - 1);
+
+        let some: Vec<_> = (0..TOTAL_BUCKETS / 2).collect();
+        let partial = gained_entries(&table, &gained, &some);
+        assert!(partial
+            .iter()
+            .all(|(key, _)| some.contains(&merkle::bucket(key))));
+    }
+}
@@ -0,0 +1,261 @@
+//! Writes we're holding for replicas that were down when they happened. The write still counts
+//! toward the quorum, and the real owner gets it as soon as it answers again. Dynamo calls this
+//! hinted handoff.
+
+use comm::Entry;
+use fnv::FnvHashMap;
+use rmp_serde::{from_read, Serializer};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    coordinator, snapshot, stats,
+    table::{self, Table},
+};
+
+/// Intended owner to the writes we're holding for it.
+type Hints = FnvHashMap<SocketAddr, Table>;
+
+static HINTS: LazyLock<Mutex<Hints>> = LazyLock::new(|| Mutex::new(Hints::default()));
+
+/// Counts changes to `HINTS`, so a save can tell whether an earlier one already covered it. Only
+/// goes up while `HINTS` is locked.
+static CHANGES: AtomicU64 = AtomicU64::new(0);
+
+/// Where hints are kept on disk. Unset means they only live in memory.
+static HINTS_FILE: OnceLock<tokio::sync::Mutex<HintsFile>> = OnceLock::new();
+
+struct HintsFile {
+    path: PathBuf,
+    /// The last change that's on disk.
+    saved: u64,
+}
+
+/// How often to check whether the owners we're holding hints for are back.
+const REPLAY_PERIOD: Duration = Duration::from_secs(5);
+
+fn load(path: &Path) -> io::Result<Hints> {
+    from_read(File::open(path)?).map_err(io::Error::other)
+}
+
+/// Loads hints left over from before a restart, and remembers to keep new ones in `path`.
+pub fn recover(path: PathBuf) -> io::Result<()> {
+    if fs::exists(&path)? {
+        let hints = load(&path)?;
+        let count: usize = hints.values().map(Table::len).sum();
+        eprintln!("[INFO] Recovered {count} hints @ {path:?}");
+        *HINTS.lock().expect("Lock poisoned :(") = hints;
+    }
+    let saved = CHANGES.load(Ordering::SeqCst);
+    HINTS_FILE
+        .set(tokio::sync::Mutex::new(HintsFile { path, saved }))
+        .map_err(|_| io::Error::other("hints file set twice"))
+}
+
+/// Holds `entry` for `owner`. Doesn't return until the hint is on disk, since the coordinator is
+/// counting it toward the write quorum.
+pub async fn record(owner: SocketAddr, key: String, entry: Entry) -> io::Result<()> {
+    let change = {
+        let mut hints = HINTS.lock().expect("Lock poisoned :(");
+        table::merge(hints.entry(owner).or_default(), key, entry);
+        CHANGES.fetch_add(1, Ordering::SeqCst) + 1
+    };
+    stats::HINTS_STORED.incr();
+    save(change).await
+}
+
+/// Gets `HINTS` onto disk as of `change` or later. Hints recorded while another save was writing
+/// all go out together in the next one.
+async fn save(change: u64) -> io::Result<()> {
+    let Some(file) = HINTS_FILE.get() else {
+        return Ok(());
+    };
+    let mut file = file.lock().await;
+    if file.saved >= change {
+        return Ok(());
+    }
+    let (latest, buffer) = {
+        let hints = HINTS.lock().expect("Lock poisoned :(");
+        let mut buffer = Vec::new();
+        hints
+            .serialize(&mut Serializer::new(&mut buffer))
+            .map_err(io::Error::other)?;
+        (CHANGES.load(Ordering::SeqCst), buffer)
+    };
+    let path = file.path.clone();
+    tokio::task::spawn_blocking(move || write(&path, &buffer))
+        .await
+        .expect("Saving hints panicked")?;
+    file.saved = latest;
+    Ok(())
+}
+
+/// Rewrites the hints file. Goes through a temporary file so a crash halfway through leaves the
+/// old hints intact.
+fn write(path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(buffer)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    snapshot::sync_parent(path)
+}
+
+/// Tries to hand every hint to its owner. Owners that still don't answer keep theirs until the
+/// next round.
+async fn replay() {
+    let pending: Vec<(SocketAddr, Vec<(String, Entry)>)> = HINTS
+        .lock()
+        .expect("Lock poisoned :(")
+        .iter()
+        .map(|(owner, writes)| (*owner, writes.clone().into_iter().collect()))
+        .collect();
+    if pending.is_empty() {
+        return;
+    }
+
+    let mut replayed = 0;
+    for (owner, writes) in pending {
+        for (key, entry) in writes {
+            if let Err(e) = coordinator::push(owner, key.clone(), entry.clone()).await {
+                eprintln!("[INFO] {owner} still isn't taking its hints: {e}");
+                break;
+            }
+
+            let mut hints = HINTS.lock().expect("Lock poisoned :(");
+            if let Some(held) = hints.get_mut(&owner) {
+                // Only drop the hint if nothing newer arrived for it while we were busy.
+                if held.get(&key) == Some(&entry) {
+                    held.remove(&key);
+                    CHANGES.fetch_add(1, Ordering::SeqCst);
+                }
+                if held.is_empty() {
+                    hints.remove(&owner);
+                }
+            }
+            stats::HINTS_REPLAYED.incr();
+            replayed += 1;
+        }
+    }
+
+    if replayed > 0 {
+        eprintln!("[INFO] Handed off {replayed} hinted writes.");
+        if let Err(e) = save(CHANGES.load(Ordering::SeqCst)).await {
+            eprintln!("[WARN] Couldn't save hints: {e}");
+        }
+    }
+}
+
+pub async fn replay_loop() {
+    let mut interval = tokio::time::interval(REPLAY_PERIOD);
+    loop {
+        interval.tick().await;
+        replay().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NODE_ADDR, NODE_ID};
+    use comm::{handshake, mux, version::Dot, Envelope, Message, Role};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    fn entry(value: &str) -> Entry {
+        let dot = Dot {
+            node: "a".into(),
+            counter: 1,
+        };
+        table::stamp(&Table::default(), value, Some(value.into()), None, dot)
+    }
+
+    #[tokio::test]
+    async fn recorded_hints_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("hints-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("node.hints");
+        recover(path.clone()).unwrap();
+
+        // Nobody's listening here, so these stay put.
+        let owner = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (left, right) = tokio::join!(
+            record(owner, "left".into(), entry("left")),
+            record(owner, "right".into(), entry("right")),
+        );
+        left.unwrap();
+        right.unwrap();
+
+        // What a restart would pick up.
+        let recovered = load(&path).unwrap();
+        assert_eq!(
+            recovered[&owner],
+            HINTS.lock().expect("Lock poisoned :(")[&owner]
+        );
+        assert_eq!(recovered[&owner].len(), 2);
+        // The directory stays, since hints from the other tests go there too now.
+    }
+
+    /// An owner that takes every write it's handed and passes it on to `got`.
+    async fn owner(got: mpsc::UnboundedSender<String>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let got = got.clone();
+                tokio::spawn(async move {
+                    handshake::accept(&mut conn, Role::Store, "owner")
+                        .await
+                        .unwrap();
+                    let handler = move |request: Envelope| {
+                        let got = got.clone();
+                        async move {
+                            match request.msg {
+                                Message::ReplicaPut {
+                                    key, hint: None, ..
+                                } => {
+                                    let _ = got.send(key);
+                                    Message::DonePut
+                                }
+                                other => Message::error(
+                                    comm::ErrorCode::BadRequest,
+                                    format!("not a write: {other:?}"),
+                                ),
+                            }
+                        }
+                    };
+                    let _ = mux::serve(conn, Duration::from_secs(60), handler).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn replayed_hints_are_dropped_once_the_owner_has_them() {
+        let addr = NODE_ADDR.get_or_init(|| SocketAddr::from(([127, 0, 0, 1], 50052)));
+        NODE_ID.get_or_init(|| format!("store@{addr}"));
+        let (got_tx, mut got_rx) = mpsc::unbounded_channel();
+        let owner = owner(got_tx).await;
+        record(owner, "a".into(), entry("a")).await.unwrap();
+        record(owner, "b".into(), entry("b")).await.unwrap();
+
+        replay().await;
+        assert!(!HINTS.lock().expect("Lock poisoned :(").contains_key(&owner));
+        let mut got = vec![got_rx.recv().await.unwrap(), got_rx.recv().await.unwrap()];
+        got.sort();
+        assert_eq!(got, ["a", "b"]);
+    }
+}
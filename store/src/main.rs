@@ -1,72 +1,251 @@
 use clap::Parser;
-use comm::{recv_msg, send_msg, Message, Result};
-use fnv::FnvHashMap;
-use rmp_serde::{from_read, Serializer};
-use serde::Serialize;
+use comm::{handshake, mux, Envelope, Epoch, ErrorCode, Message, PartitionMap, Result, Role};
 use std::{
-    fs::{self, File},
-    io::{Seek, SeekFrom, Write},
     net::SocketAddr,
     path::PathBuf,
-    sync::{LazyLock, RwLock},
+    sync::{LazyLock, OnceLock, RwLock},
     time::Duration,
 };
-use tokio::net::{TcpListener, TcpStream};
+use table::Table;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{oneshot, Notify},
+};
+
+mod anti_entropy;
+mod coordinator;
+mod dots;
+mod gossip;
+mod handoff;
+mod hints;
+mod merkle;
+mod ranges;
+mod snapshot;
+mod stats;
+mod table;
+mod wal;
 
-// TODO: Fix port argument not to collide with default manager port.
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct StoreArgs {
     /// Port of this storage node.
-    #[arg(short, long, default_value_t = 50051)]
+    #[arg(short, long, default_value_t = 50052)]
     port: u16,
     /// File to persist state to. If none is provided, does not persist state.
     #[arg(short, long)]
     node_state: Option<PathBuf>,
+    /// Largest message body (in bytes) this node will send or accept.
+    #[arg(long, default_value_t = comm::DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// How long (in seconds) to remember deleted keys before forgetting them entirely.
+    #[arg(long, default_value_t = 24 * 60 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    tombstone_grace: u64,
+    /// Most keys per second to pull in from other nodes when the ring changes.
+    #[arg(long, default_value_t = 1000)]
+    handoff_rate: u64,
+    /// Snapshots of the table to keep around, in case the newest is damaged.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    snapshots: u64,
+    /// How long (in milliseconds) to hold a write before syncing the write-ahead log, so writes
+    /// arriving together share one fsync. With 0, writes only share one if they arrive while the
+    /// last is still syncing.
+    #[arg(long, default_value_t = 0)]
+    group_commit: u64,
 }
 
 // TODO: Benchmark this vs Dashmap, which presumably only uses atomics like ConcurrentHashMap from
 // Java (wow imagine Java doing anything correctly...)
-static TABLE_SERVICE: LazyLock<RwLock<FnvHashMap<String, String>>> =
-    LazyLock::new(|| RwLock::new(FnvHashMap::default()));
+static TABLE_SERVICE: LazyLock<RwLock<Table>> = LazyLock::new(|| RwLock::new(Table::default()));
+
+/// Newest partition map the manager has told us about.
+static PARTITION_MAP: LazyLock<RwLock<PartitionMap>> =
+    LazyLock::new(|| RwLock::new(PartitionMap::default()));
+
+/// Where this node listens, which is how the partition map refers to it.
+static NODE_ADDR: OnceLock<SocketAddr> = OnceLock::new();
+/// How this node introduces itself during the handshake.
+static NODE_ID: OnceLock<String> = OnceLock::new();
+
+/// Poked when the manager tells us we've been decommissioned.
+static SHUTDOWN: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Connections are long-lived now, but we don't want to hang on to ones nobody is using.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 async fn handle_client(mut conn: TcpStream) {
-    match recv_msg(&mut conn).await {
-        Ok(msg) => match msg {
-            Message::Get { key } => {
-                let response = TABLE_SERVICE
-                    .read()
-                    .expect("Lock poisoned :(")
-                    .get(&key)
-                    .map_or(Message::NotFound, |val| Message::Found {
-                        value: val.clone(),
-                    });
-
-                if let Err(e) = send_msg(&mut conn, response).await {
-                    eprintln!("[ERROR] Failed to respond to GET request from {conn:?}: {e}");
-                }
-            }
-            Message::Put { key, value } => {
-                TABLE_SERVICE
-                    .write()
-                    .expect("Lock poisoned :(")
-                    .insert(key, value);
-                if let Err(e) = send_msg(&mut conn, Message::DonePut).await {
-                    eprintln!("[ERROR] Failed to respond to PUT request from {conn:?}: {e}");
-                }
-            }
-            _ => unreachable!(),
-        },
-        Err(e) => {
-            eprintln!("[ERROR] Failed to recv msg from {conn:?}: {e}");
+    let node_id = NODE_ID
+        .get()
+        .expect("Node ID is set before we accept connections");
+    if let Err(e) = handshake::accept_within(
+        &mut conn,
+        Role::Store,
+        node_id,
+        handshake::HANDSHAKE_TIMEOUT,
+    )
+    .await
+    {
+        eprintln!("[ERROR] Handshake with {conn:?} failed: {e}");
+        return;
+    }
+
+    let peer = conn.peer_addr();
+    if let Err(e) = mux::serve(conn, IDLE_TIMEOUT, handle_request).await {
+        eprintln!("[ERROR] Connection with {peer:?} failed: {e}");
+    }
+}
+
+/// Checks that a request for `key` was routed here with an up-to-date partition map. Returns the
+/// response to send instead if it wasn't.
+fn check_routing(key: &str, epoch: Option<Epoch>) -> Option<Message> {
+    // Untagged requests didn't use a map, so there's nothing to check.
+    let epoch = epoch?;
+    let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+    if epoch < map.epoch {
+        return Some(Message::StaleEpoch { current: map.epoch });
+    }
+    // If the client's map is newer than ours, the manager just hasn't gotten to us yet. Trust
+    // the client.
+    let node_addr = *NODE_ADDR
+        .get()
+        .expect("Address is set before we accept connections");
+    if epoch == map.epoch && !map.is_owner(node_addr, key) {
+        return Some(Message::error(
+            ErrorCode::NotOwner,
+            format!("{node_addr} doesn't own '{key}' as of epoch {epoch}"),
+        ));
+    }
+    None
+}
+
+/// Switches to `map` if it's newer than ours, whether it came from the manager or a peer. Returns
+/// whichever map we end up with.
+fn adopt_partition_map(map: PartitionMap) -> PartitionMap {
+    let mut current = PARTITION_MAP.write().expect("Lock poisoned :(");
+    if map.epoch > current.epoch {
+        eprintln!("[INFO] Moving to partition map epoch {}", map.epoch);
+        *current = map;
+    }
+    current.clone()
+}
+
+/// The manager has taken us out of the ring. We only go once the reply is on its way, so the
+/// manager doesn't mistake our leaving for a failed request.
+fn decommissioned() -> mux::Reply {
+    eprintln!("[INFO] Decommissioned by the manager, shutting down");
+    let (sent_tx, sent) = oneshot::channel();
+    tokio::spawn(async move {
+        // If the connection broke first there's nobody left to tell, so go anyway.
+        let _ = sent.await;
+        SHUTDOWN.notify_one();
+    });
+    mux::Reply::then_notify(Message::Accepted, sent_tx)
+}
+
+async fn handle_request(request: Envelope) -> mux::Reply {
+    let Envelope { msg, epoch, .. } = request;
+    if let Message::Shutdown = msg {
+        return decommissioned();
+    }
+    if let Message::Get { key, .. }
+    | Message::Put { key, .. }
+    | Message::Delete { key, .. }
+    | Message::Scan { start: key, .. } = &msg
+    {
+        if let Some(response) = check_routing(key, epoch) {
+            return response.into();
         }
     }
+
+    let response = match msg {
+        Message::Get { key, consistency } => coordinator::get(key, consistency).await,
+        Message::Put {
+            key,
+            value,
+            consistency,
+            context,
+        } => coordinator::write(key, Some(value), context, consistency, Message::DonePut).await,
+        Message::Delete {
+            key,
+            consistency,
+            context,
+        } => coordinator::write(key, None, context, consistency, Message::DoneDelete).await,
+        Message::Scan {
+            start,
+            end,
+            limit,
+            consistency,
+        } => coordinator::scan(start, end, limit, consistency).await,
+        Message::ReplicaGet { key } => Message::Replica {
+            entry: TABLE_SERVICE
+                .read()
+                .expect("Lock poisoned :(")
+                .get(&key)
+                .cloned(),
+        },
+        Message::ReplicaPut {
+            key,
+            entry,
+            hint: None,
+        } => match wal::merge(key, entry).await {
+            Ok(_) => Message::DonePut,
+            Err(e) => Message::error(ErrorCode::Internal, format!("couldn't log write: {e}")),
+        },
+        Message::ReplicaPut {
+            key,
+            entry,
+            hint: Some(owner),
+        } => match hints::record(owner, key, entry).await {
+            Ok(()) => Message::DonePut,
+            Err(e) => Message::error(ErrorCode::Internal, format!("couldn't save hint: {e}")),
+        },
+        Message::UpdatePartitionMap { map } => Message::PartitionMap {
+            map: adopt_partition_map(map),
+        },
+        Message::Gossip { map, members } => gossip::handle_gossip(map, members),
+        Message::GetPartitionMap => Message::PartitionMap {
+            map: PARTITION_MAP.read().expect("Lock poisoned :(").clone(),
+        },
+        Message::GetMerkle {
+            peer,
+            epoch,
+            level,
+            indices,
+        } => anti_entropy::handle_get_merkle(peer, epoch, level, indices).await,
+        Message::GetBuckets {
+            peer,
+            epoch,
+            buckets,
+        } => anti_entropy::handle_get_buckets(peer, epoch, buckets).await,
+        Message::GetRange {
+            peer,
+            since,
+            buckets,
+        } => handoff::handle_get_range(peer, since, buckets).await,
+        Message::GetHandoff => handoff::handle_get_handoff(),
+        Message::ReplicaScan { start, end, limit } => Message::Entries {
+            entries: table::scan(
+                &TABLE_SERVICE.read().expect("Lock poisoned :("),
+                &start,
+                end.as_deref(),
+                limit,
+            ),
+        },
+        Message::GetRangeSizes => ranges::handle_get_range_sizes(),
+        Message::Heartbeat => Message::Heartbeat,
+        Message::GetStats => Message::Stats {
+            counters: stats::snapshot(),
+        },
+        other => Message::error(
+            ErrorCode::BadRequest,
+            format!("storage nodes don't handle '{other:?}'"),
+        ),
+    };
+    response.into()
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    const TIMEOUT: Duration = Duration::from_secs(2);
-    const DISK_SYNC_PERIOD: Duration = Duration::from_secs(10);
+    const TOMBSTONE_GC_PERIOD: Duration = Duration::from_secs(60);
     // This guy serves multple connections.
     // So I guess our storage node should listen to one of multiple possibilites.
     // Here is where workspace-shared code comes in: serde + message type
@@ -79,6 +258,7 @@ async fn main() -> Result<()> {
     //    advance to the main request loop, which responds to GET/PUT/HB.
 
     let args = StoreArgs::parse();
+    comm::set_max_frame_size(args.max_frame_size);
 
     // Synchronize hash table to disk.
     // I'd like to abstract this out into its own function but this lambda returns a JoinHandle<!>,
@@ -86,52 +266,68 @@ async fn main() -> Result<()> {
     // We have the nightly compiler so ig it's fine.
     // I really just want a semaphore but this'll probably do.
     let (quit_tx, quit_rx) = std::sync::mpsc::channel();
+    let dots_path = args.node_state.as_ref().map(|path| {
+        let mut dots_path = path.clone().into_os_string();
+        dots_path.push(".dots");
+        PathBuf::from(dots_path)
+    });
+    dots::recover(dots_path).expect("Couldn't recover the dot counter:");
+    if let Some(path) = &args.node_state {
+        let mut hints_path = path.clone().into_os_string();
+        hints_path.push(".hints");
+        hints::recover(hints_path.into()).expect("Couldn't recover hints:");
+    }
     let sync_handle = args.node_state.map(|path| {
-        let mut backing_file = if fs::exists(&path).expect("Bigger FS problem:") {
-            eprintln!("[INFO] Attempting to recover node state @ {path:?}");
-            let file = File::options()
-                .read(true)
-                .write(true)
-                .open(path)
-                .expect("Bigger FS problem:");
-            let got_map = from_read(&file).expect("Failed to deserialize:");
-            eprintln!("[INFO] Recovered state {got_map:#?}");
-            *TABLE_SERVICE.write().expect("Lock already poisoned?!") = got_map;
-            file
-        } else {
-            eprintln!("[INFO] Creating backing file @ {path:?}");
-            File::create_new(path).expect("Bigger FS problem:")
-        };
-
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(DISK_SYNC_PERIOD);
-            let mut table_buffer = Vec::new();
-            loop {
-                interval.tick().await;
-                {
-                    let table_guard = TABLE_SERVICE.read().expect("Lock poisoned :(");
-                    table_guard
-                        .serialize(&mut Serializer::new(&mut table_buffer))
-                        .expect("Failed to serialize table!");
-                    backing_file
-                        .write_all(&mut table_buffer[..])
-                        .expect("Couldn't fully write state!");
-                    backing_file
-                        .seek(SeekFrom::Start(0))
-                        .expect("Couldn't seek.");
-                    table_buffer.clear();
-                    eprintln!("[INFO] Synced to disk!");
-                }
-
-                if let Ok(()) = quit_rx.try_recv() {
-                    eprintln!("[INFO] Main storage loop exited! Quitting...");
-                    break;
-                }
+        let (since, table) = snapshot::recover(&path).expect("Couldn't recover node state:");
+        let mut table_guard = TABLE_SERVICE.write().expect("Lock already poisoned?!");
+        *table_guard = table;
+        // Writes since the last snapshot only made it as far as the log.
+        let mut wal_path = path.clone().into_os_string();
+        wal_path.push(".wal");
+        let current = wal::recover(
+            &PathBuf::from(wal_path),
+            since,
+            &mut table_guard,
+            Duration::from_millis(args.group_commit),
+        )
+        .expect("Couldn't replay the write-ahead log:");
+        drop(table_guard);
+
+        // Number new snapshots past anything a crash left lying around.
+        let generation = since.max(current);
+        let keep = args.snapshots as usize;
+        tokio::spawn(snapshot::snapshot_loop(path, generation, keep, quit_rx))
+    });
+
+    // Tombstones only need to outlive any stale copy of the value they replaced. Once they're
+    // older than the grace period, sweep them out so deleted keys stop costing memory.
+    let tombstone_grace = Duration::from_secs(args.tombstone_grace);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TOMBSTONE_GC_PERIOD.min(tombstone_grace));
+        loop {
+            interval.tick().await;
+            let collected = table::collect_garbage(
+                &mut TABLE_SERVICE.write().expect("Lock poisoned :("),
+                tombstone_grace,
+                table::now_millis(),
+            );
+            if collected > 0 {
+                eprintln!("[INFO] Collected {collected} expired tombstones.");
             }
-        })
+        }
     });
 
     let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    NODE_ADDR.set(addr).expect("Node address set twice?");
+    NODE_ID
+        .set(format!("store@{addr}"))
+        .expect("Node ID set twice?");
+    tokio::spawn(wal::flush_loop());
+    tokio::spawn(hints::replay_loop());
+    tokio::spawn(anti_entropy::anti_entropy_loop());
+    tokio::spawn(gossip::gossip_loop());
+    handoff::set_rate(args.handoff_rate);
+    tokio::spawn(handoff::handoff_loop());
     let listener = TcpListener::bind(addr).await?;
 
     eprintln!("[INFO] Listening on {}", args.port);
@@ -166,21 +362,23 @@ async fn main() -> Result<()> {
     // });
 
     // Now we can handle connections normally.
-    let conn_loop = tokio::spawn(async move {
+    let mut conn_loop = tokio::spawn(async move {
         loop {
-            use tokio::time::timeout;
             let (conn, _) = listener
                 .accept()
                 .await
                 .expect("Failed to accept connection!");
             eprintln!("[INFO] Got connection!");
-            tokio::spawn(timeout(TIMEOUT, handle_client(conn)));
+            tokio::spawn(handle_client(conn));
         }
     });
 
     // If something horrible goes wrong, ensure the synchronizing loop can finish.
-    // We need to signal to the sync loop to shutdown.
-    let _ = conn_loop.await;
+    // We need to signal to the sync loop to shutdown. Same goes for being decommissioned.
+    tokio::select! {
+        _ = &mut conn_loop => {}
+        _ = SHUTDOWN.notified() => conn_loop.abort(),
+    }
     if let Some(handle) = sync_handle {
         if let Err(e) = quit_tx.send(()) {
             eprintln!("[WARN] Couldn't send quit signal to disk coroutine because: {e}");
@@ -0,0 +1,164 @@
+//! Hash trees over a set of keys, so two replicas can find out where they disagree by swapping a
+//! handful of hashes instead of their whole tables.
+//!
+//! Keys are sorted into `2^DEPTH` buckets by hash. Each leaf hashes the entries in its bucket and
+//! each branch hashes its two children, so matching roots mean matching tables. Where hashes
+//! differ, [`diff`] walks down one level at a time until it's down to the buckets that need
+//! swapping.
+
+use comm::Entry;
+use sha1::{Digest, Sha1};
+use std::future::Future;
+
+/// Levels below the root. 256 buckets is plenty at the sizes we run at.
+pub const DEPTH: u32 = 8;
+
+/// Bucket `key` lands in.
+pub fn bucket(key: &str) -> u32 {
+    let hash: [u8; 8] = Sha1::digest(key)[..8].try_into().unwrap();
+    (u64::from_be_bytes(hash) >> (64 - DEPTH)) as u32
+}
+
+fn hash_entry(key: &str, entry: &Entry) -> u64 {
+    // Siblings are kept sorted and clocks are ordered maps, so equal entries encode the same.
+    let encoded = rmp_serde::to_vec(entry).expect("Entries always serialize");
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update([0]);
+    hasher.update(encoded);
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+fn hash_children(left: u64, right: u64) -> u64 {
+    let mut hasher = Sha1::new();
+    hasher.update(left.to_be_bytes());
+    hasher.update(right.to_be_bytes());
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+pub struct MerkleTree {
+    /// `levels[0]` is just the root, `levels[DEPTH]` are the buckets.
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    pub fn build<'a>(entries: impl IntoIterator<Item = (&'a str, &'a Entry)>) -> Self {
+        // XOR so the order we see keys in doesn't matter.
+        let mut leaves = vec![0; 1 << DEPTH];
+        for (key, entry) in entries {
+            leaves[bucket(key) as usize] ^= hash_entry(key, entry);
+        }
+
+        let mut levels = vec![leaves];
+        while levels[0].len() > 1 {
+            let parents = levels[0]
+                .chunks(2)
+                .map(|pair| hash_children(pair[0], pair[1]))
+                .collect();
+            levels.insert(0, parents);
+        }
+        Self { levels }
+    }
+
+    /// Hashes of the nodes at `indices` on `level`. `None` if any of them don't exist.
+    pub fn hashes(&self, level: u32, indices: &[u32]) -> Option<Vec<u64>> {
+        let level = self.levels.get(level as usize)?;
+        indices
+            .iter()
+            .map(|&i| level.get(i as usize).copied())
+            .collect()
+    }
+}
+
+/// Finds the buckets where `ours` and some other tree disagree. `fetch(level, indices)` should
+/// come back with the other tree's hashes for those nodes.
+pub async fn diff<F, Fut>(ours: &MerkleTree, mut fetch: F) -> comm::Result<Vec<u32>>
+where
+    F: FnMut(u32, Vec<u32>) -> Fut,
+    Fut: Future<Output = comm::Result<Vec<u64>>>,
+{
+    let mut differing = vec![0];
+    for level in 0..=DEPTH {
+        let theirs = fetch(level, differing.clone()).await?;
+        if theirs.len() != differing.len() {
+            return Err(comm::Error::Protocol(format!(
+                "asked for {} hashes, got {}",
+                differing.len(),
+                theirs.len()
+            )));
+        }
+        let level_hashes = &ours.levels[level as usize];
+        differing = differing
+            .into_iter()
+            .zip(theirs)
+            .filter(|&(i, hash)| level_hashes[i as usize] != hash)
+            .map(|(i, _)| i)
+            .collect();
+        if differing.is_empty() || level == DEPTH {
+            break;
+        }
+        differing = differing
+            .into_iter()
+            .flat_map(|i| [2 * i, 2 * i + 1])
+            .collect();
+    }
+    Ok(differing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::version::{Dot, Sibling};
+    use comm::VectorClock;
+
+    fn entry(value: &str) -> Entry {
+        Entry {
+            siblings: vec![Sibling {
+                dot: Dot {
+                    node: "a".into(),
+                    counter: 1,
+                },
+                context: VectorClock::default(),
+                value: Some(value.into()),
+                timestamp: 0,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn diff_finds_just_the_buckets_that_differ() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key_{i}")).collect();
+        let ours: Vec<_> = keys.iter().map(|k| (k.as_str(), entry("v"))).collect();
+        let mut theirs = ours.clone();
+        theirs[17].1 = entry("changed");
+        theirs.remove(400);
+
+        let our_tree = MerkleTree::build(ours.iter().map(|(k, e)| (*k, e)));
+        let their_tree = MerkleTree::build(theirs.iter().map(|(k, e)| (*k, e)));
+        assert_ne!(our_tree.hashes(0, &[0]), their_tree.hashes(0, &[0]));
+
+        let mut rounds = 0;
+        let differing = diff(&our_tree, |level, indices| {
+            rounds += 1;
+            let hashes = their_tree.hashes(level, &indices).unwrap();
+            async move { Ok(hashes) }
+        })
+        .await
+        .unwrap();
+        let mut expected = vec![bucket("key_17"), bucket("key_400")];
+        expected.sort();
+        expected.dedup();
+        assert_eq!(differing, expected);
+        assert_eq!(rounds, DEPTH + 1);
+
+        // Same contents in a different order means nothing to do.
+        let shuffled = MerkleTree::build(ours.iter().rev().map(|(k, e)| (*k, e)));
+        let differing = diff(&our_tree, |level, indices| {
+            let hashes = shuffled.hashes(level, &indices).unwrap();
+            async move { Ok(hashes) }
+        })
+        .await
+        .unwrap();
+        assert!(differing.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Telling the manager how big our ranges have gotten, on range-partitioned clusters. Only the
+//! first owner of each range reports on it, so the manager hears about every range once.
+
+use comm::{
+    range_map::{RangeMap, RangeSize},
+    Message,
+};
+use std::net::SocketAddr;
+
+use crate::{coordinator, table::Table, PARTITION_MAP, TABLE_SERVICE};
+
+/// How many live keys each range `us` is first in line for has, and where to split it.
+fn range_sizes(table: &Table, ranges: &RangeMap, us: SocketAddr) -> Vec<RangeSize> {
+    let ours = ranges.ranges().iter().enumerate();
+    let ours = ours.filter(|(_, range)| range.owners.first() == Some(&us));
+    ours.map(|(i, range)| {
+        let end = ranges.ranges().get(i + 1).map(|next| next.start.as_str());
+        let live: Vec<_> = table
+            .range(range.start.clone()..)
+            .take_while(|(key, _)| end.is_none_or(|end| key.as_str() < end))
+            .filter(|(_, entry)| !entry.is_deleted())
+            .map(|(key, _)| key)
+            .collect();
+        // With at least two keys, the middle one is past the start, so the split leaves something
+        // on each side.
+        let middle = match live.len() {
+            0 | 1 => None,
+            len => Some(live[len / 2].clone()),
+        };
+        RangeSize {
+            start: range.start.clone(),
+            keys: live.len() as u64,
+            middle,
+        }
+    })
+    .collect()
+}
+
+pub fn handle_get_range_sizes() -> Message {
+    let map = PARTITION_MAP.read().expect("Lock poisoned :(");
+    let sizes = match map.ring.as_ranges() {
+        Some(ranges) => range_sizes(
+            &TABLE_SERVICE.read().expect("Lock poisoned :("),
+            ranges,
+            coordinator::node_addr(),
+        ),
+        None => Vec::new(),
+    };
+    Message::RangeSizes { sizes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table;
+    use comm::{
+        placement::{Placement, DEFAULT_TOKENS},
+        version::Dot,
+    };
+
+    #[test]
+    fn reports_the_ranges_it_leads() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut ranges = RangeMap::new(1);
+        ranges.add_node(addr(50052), DEFAULT_TOKENS);
+        ranges.add_node(addr(50053), DEFAULT_TOKENS);
+        ranges.split("m");
+        ranges.spread("m");
+
+        let dot = |counter| Dot {
+            node: "a".into(),
+            counter,
+        };
+        let mut table = Table::default();
+        for key in ["apple", "banana", "cherry", "date", "melon", "zucchini"] {
+            let entry = table::stamp(&table, key, Some(key.into()), None, dot(1));
+            table::merge(&mut table, key.into(), entry);
+        }
+        let deleted = table::stamp(&table, "cherry", None, None, dot(2));
+        table::merge(&mut table, "cherry".into(), deleted);
+
+        let sizes = range_sizes(&table, &ranges, addr(50052));
+        assert_eq!(
+            sizes,
+            [RangeSize {
+                start: "".into(),
+                keys: 3,
+                middle: Some("banana".into()),
+            }]
+        );
+        let sizes = range_sizes(&table, &ranges, addr(50053));
+        assert_eq!(sizes[0].start, "m");
+        assert_eq!(sizes[0].keys, 2);
+        assert_eq!(sizes[0].middle.as_deref(), Some("zucchini"));
+    }
+}
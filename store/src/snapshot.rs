@@ -0,0 +1,286 @@
+//! Snapshots of the table on disk. Each one is written to a temporary file and only renamed into
+//! place once it's fsynced, so a crash halfway through never touches the snapshots already there.
+//! They're numbered and checksummed, and the newest few are kept: on startup the newest one that
+//! checks out wins, and the write-ahead log (see [`wal`]) fills in everything since.
+
+use rmp_serde::{from_read, Serializer};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use crate::{table::Table, wal, TABLE_SERVICE};
+
+/// How often to take a snapshot.
+const SNAPSHOT_PERIOD: Duration = Duration::from_secs(10);
+const MAGIC: &[u8; 8] = b"mdsnap01";
+/// The magic, then the payload's length and CRC-32 (little-endian), then the table as MessagePack.
+const HEADER_LEN: usize = 20;
+
+/// Where generation `generation` of whatever's kept at `base` goes. Snapshots and log segments
+/// are both numbered this way.
+pub fn generation_path(base: &Path, generation: u64) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{generation:020}"));
+    path.into()
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Every generation there's a file for next to `base`, oldest first.
+pub fn generations(base: &Path) -> io::Result<Vec<u64>> {
+    let name = base
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::other(format!("{base:?} isn't a usable file name")))?;
+    let mut generations = Vec::new();
+    for file in fs::read_dir(parent(base))? {
+        let file_name = file?.file_name();
+        let suffix = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(name))
+            .and_then(|rest| rest.strip_prefix('.'));
+        // Leftover temporary files and snapshots set aside as corrupt don't count.
+        if let Some(Ok(generation)) = suffix
+            .filter(|suffix| suffix.bytes().all(|b| b.is_ascii_digit()))
+            .map(str::parse)
+        {
+            generations.push(generation);
+        }
+    }
+    generations.sort();
+    Ok(generations)
+}
+
+/// Fsyncs the directory `path` is in, so a file created or renamed there survives a crash.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    File::open(parent(path))?.sync_all()
+}
+
+fn encode(table: &Table) -> Vec<u8> {
+    let mut bytes = vec![0; HEADER_LEN];
+    table
+        .serialize(&mut Serializer::new(&mut bytes))
+        .expect("Failed to serialize table!");
+    let payload = &bytes[HEADER_LEN..];
+    let len = (payload.len() as u64).to_le_bytes();
+    let crc = crc32fast::hash(payload).to_le_bytes();
+    bytes[..8].copy_from_slice(MAGIC);
+    bytes[8..16].copy_from_slice(&len);
+    bytes[16..HEADER_LEN].copy_from_slice(&crc);
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Table, String> {
+    if bytes.len() < HEADER_LEN || &bytes[..8] != MAGIC {
+        return Err("not a snapshot".into());
+    }
+    let len = u64::from_le_bytes(bytes[8..16].try_into().expect("8 bytes"));
+    let crc = u32::from_le_bytes(bytes[16..HEADER_LEN].try_into().expect("4 bytes"));
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() as u64 != len {
+        return Err(format!("expected {len} bytes, found {}", payload.len()));
+    }
+    if crc32fast::hash(payload) != crc {
+        return Err("checksum doesn't match".into());
+    }
+    rmp_serde::from_slice(payload).map_err(|e| e.to_string())
+}
+
+/// Puts `bytes` in place as snapshot `generation`, all at once or not at all.
+fn write(base: &Path, generation: u64, bytes: &[u8]) -> io::Result<()> {
+    let path = generation_path(base, generation);
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(bytes)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_parent(&path)
+}
+
+/// Deletes all but the newest `keep` snapshots. Returns the oldest one left.
+fn prune(base: &Path, keep: usize) -> io::Result<u64> {
+    let generations = generations(base)?;
+    let cut = generations.len().saturating_sub(keep);
+    for &generation in &generations[..cut] {
+        fs::remove_file(generation_path(base, generation))?;
+    }
+    // The old unnumbered snapshot, if there was one, is covered by the ones since.
+    match fs::remove_file(base) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(generations.get(cut).copied().unwrap_or(0)),
+    }
+}
+
+/// The newest snapshot at `base` that checks out, and its generation. Ones that don't are set
+/// aside with a `.corrupt` suffix. Generation 0 is the empty table, for when there aren't any.
+pub fn recover(base: &Path) -> io::Result<(u64, Table)> {
+    let generations = generations(base)?;
+    for &generation in generations.iter().rev() {
+        let path = generation_path(base, generation);
+        match decode(&fs::read(&path)?) {
+            Ok(table) => {
+                eprintln!(
+                    "[INFO] Recovered {} keys from snapshot {path:?}",
+                    table.len()
+                );
+                return Ok((generation, table));
+            }
+            Err(e) => {
+                eprintln!("[WARN] Snapshot {path:?} is no good ({e}), trying an older one");
+                let mut corrupt = path.clone().into_os_string();
+                corrupt.push(".corrupt");
+                fs::rename(&path, corrupt)?;
+            }
+        }
+    }
+    if !generations.is_empty() {
+        eprintln!("[WARN] No snapshot at {base:?} checks out, starting over from the log");
+    }
+
+    // Before snapshots were numbered, the table was written straight to `base`.
+    if fs::exists(base)? {
+        match from_read(File::open(base)?) {
+            Ok(table) => {
+                eprintln!("[INFO] Recovered node state @ {base:?}");
+                return Ok((0, table));
+            }
+            Err(e) => eprintln!("[WARN] Couldn't read old node state @ {base:?}: {e}"),
+        }
+    }
+    Ok((0, Table::default()))
+}
+
+/// Writes the table out as snapshot `generation`, then lets go of whatever's too old to keep.
+fn take(base: &Path, generation: u64, keep: usize) -> io::Result<()> {
+    let bytes = {
+        let table = TABLE_SERVICE.read().expect("Lock poisoned :(");
+        // Writes from here on go in the next segment of the log, which replays on top of this.
+        wal::rotate(generation)?;
+        encode(&table)
+    };
+    write(base, generation, &bytes)?;
+    let oldest = prune(base, keep)?;
+    wal::prune(oldest)
+}
+
+/// Takes a snapshot every so often, numbering them on from `generation`. Stops after the next one
+/// once something comes in on `quit`.
+pub async fn snapshot_loop(
+    base: PathBuf,
+    mut generation: u64,
+    keep: usize,
+    quit: mpsc::Receiver<()>,
+) {
+    let mut interval = tokio::time::interval(SNAPSHOT_PERIOD);
+    loop {
+        interval.tick().await;
+        generation += 1;
+        let path = base.clone();
+        let taken = tokio::task::spawn_blocking(move || take(&path, generation, keep)).await;
+        match taken.expect("Snapshot task panicked") {
+            Ok(()) => eprintln!("[INFO] Synced to disk!"),
+            Err(e) => eprintln!("[ERROR] Couldn't write snapshot {generation}: {e}"),
+        }
+
+        if let Ok(()) = quit.try_recv() {
+            eprintln!("[INFO] Main storage loop exited! Quitting...");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table;
+    use comm::version::Dot;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir.join("node")
+    }
+
+    fn table_with(keys: usize) -> Table {
+        let mut table = Table::default();
+        for i in 0..keys {
+            let key = format!("key_{i}");
+            let dot = Dot {
+                node: "a".into(),
+                counter: 1,
+            };
+            let entry = table::stamp(&table, &key, Some(key.clone()), None, dot);
+            table::merge(&mut table, key, entry);
+        }
+        table
+    }
+
+    #[test]
+    fn only_the_newest_are_kept() {
+        let base = scratch("retention");
+        let tables: Vec<_> = (0..=5).map(table_with).collect();
+        for generation in 1..=5 {
+            write(&base, generation, &encode(&tables[generation as usize])).unwrap();
+            prune(&base, 3).unwrap();
+        }
+        assert_eq!(generations(&base).unwrap(), [3, 4, 5]);
+        let (generation, table) = recover(&base).unwrap();
+        assert_eq!(generation, 5);
+        assert_eq!(table, tables[5]);
+        fs::remove_dir_all(parent(&base)).unwrap();
+    }
+
+    #[test]
+    fn bad_snapshots_fall_back_to_older_ones() {
+        let base = scratch("fallback");
+        assert_eq!(recover(&base).unwrap(), (0, Table::default()));
+        let tables: Vec<_> = (0..=3).map(table_with).collect();
+        for generation in 1..=3 {
+            write(&base, generation, &encode(&tables[generation as usize])).unwrap();
+        }
+        // A flipped bit, and a snapshot that was cut short.
+        let mut bytes = fs::read(generation_path(&base, 3)).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(generation_path(&base, 3), &bytes).unwrap();
+        let bytes = fs::read(generation_path(&base, 2)).unwrap();
+        fs::write(generation_path(&base, 2), &bytes[..bytes.len() / 2]).unwrap();
+        // A crash before the rename leaves a temporary file nobody should read.
+        fs::write(
+            format!("{}.tmp", generation_path(&base, 4).display()),
+            b"junk",
+        )
+        .unwrap();
+
+        let (generation, table) = recover(&base).unwrap();
+        assert_eq!(generation, 1);
+        assert_eq!(table, tables[1]);
+        // The bad ones are out of the way, so they don't count toward the ones kept.
+        assert_eq!(generations(&base).unwrap(), [1]);
+        fs::remove_dir_all(parent(&base)).unwrap();
+    }
+
+    #[test]
+    fn snapshots_dont_leave_anything_behind() {
+        let base = scratch("shrink");
+        write(&base, 1, &encode(&table_with(50))).unwrap();
+        // Unlike writing over the same file, a smaller table doesn't leave the end of a bigger
+        // one hanging off it.
+        let small = table_with(1);
+        write(&base, 2, &encode(&small)).unwrap();
+        assert_eq!(recover(&base).unwrap(), (2, small));
+        assert!(decode(b"mdsnap01").is_err());
+        fs::remove_dir_all(parent(&base)).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+//! Counters for the things this node does behind the scenes. Anyone can fetch them with
+//! `GetStats`.
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+pub struct Counter {
+    name: &'static str,
+    count: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.count.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// Stale replicas brought up to date after a read.
+pub static READ_REPAIRS: Counter = Counter::new("read_repairs");
+/// Stale replicas we tried to repair but couldn't reach.
+pub static READ_REPAIR_FAILURES: Counter = Counter::new("read_repair_failures");
+
+/// Writes we agreed to hold for a replica that was down.
+pub static HINTS_STORED: Counter = Counter::new("hints_stored");
+/// Held writes we've since handed to their owner.
+pub static HINTS_REPLAYED: Counter = Counter::new("hints_replayed");
+
+/// Merkle tree exchanges we've started.
+pub static ANTI_ENTROPY_ROUNDS: Counter = Counter::new("anti_entropy_rounds");
+/// Keys a peer had newer versions of.
+pub static ANTI_ENTROPY_KEYS_PULLED: Counter = Counter::new("anti_entropy_keys_pulled");
+/// Keys we had newer versions of than a peer.
+pub static ANTI_ENTROPY_KEYS_PUSHED: Counter = Counter::new("anti_entropy_keys_pushed");
+
+/// Keys pulled in from other nodes because a rebalance gave them to us.
+pub static HANDOFF_KEYS_RECEIVED: Counter = Counter::new("handoff_keys_received");
+/// Keys handed to other nodes because a rebalance gave them away.
+pub static HANDOFF_KEYS_SENT: Counter = Counter::new("handoff_keys_sent");
+
+static ALL: &[&Counter] = &[
+    &READ_REPAIRS,
+    &READ_REPAIR_FAILURES,
+    &HINTS_STORED,
+    &HINTS_REPLAYED,
+    &ANTI_ENTROPY_ROUNDS,
+    &ANTI_ENTROPY_KEYS_PULLED,
+    &ANTI_ENTROPY_KEYS_PUSHED,
+    &HANDOFF_KEYS_RECEIVED,
+    &HANDOFF_KEYS_SENT,
+];
+
+pub fn snapshot() -> BTreeMap<String, u64> {
+    ALL.iter()
+        .map(|counter| (counter.name.to_owned(), counter.get()))
+        .collect()
+}
@@ -0,0 +1,172 @@
+//! The key-value table itself, plus the bookkeeping that keeps deleted keys deleted.
+
+use comm::{
+    version::{Dot, Sibling},
+    Entry, VectorClock,
+};
+use std::{
+    collections::BTreeMap,
+    ops::Bound::{Excluded, Included, Unbounded},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Kept in key order so range-partitioned clusters can scan it.
+pub type Table = BTreeMap<String, Entry>;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is set before 1970?")
+        .as_millis() as u64
+}
+
+/// Stamps a new version of `key` as the write `dot`. It supersedes everything in `context`, or
+/// everything we have if there's no context.
+pub fn stamp(
+    table: &Table,
+    key: &str,
+    value: Option<String>,
+    context: Option<VectorClock>,
+    dot: Dot,
+) -> Entry {
+    let empty = Entry::default();
+    let current = table.get(key).unwrap_or(&empty);
+    let context = context.unwrap_or_else(|| current.context());
+    // Tombstones are collected by age, so never let one look older than what it deleted.
+    let timestamp = now_millis().max(current.timestamp() + 1);
+    Entry {
+        siblings: vec![Sibling {
+            dot,
+            context,
+            value,
+            timestamp,
+        }],
+    }
+}
+
+/// Folds `entry` into what we have for `key`. Returns whether anything changed.
+pub fn merge(table: &mut Table, key: String, entry: Entry) -> bool {
+    table.entry(key).or_default().merge(entry)
+}
+
+/// Up to `limit` keys from `start` up to (not including) `end`, in order. Tombstones count, so
+/// replicas that deleted a key and ones that haven't heard yet line up.
+pub fn scan(table: &Table, start: &str, end: Option<&str>, limit: usize) -> Vec<(String, Entry)> {
+    let end = match end {
+        Some(end) if end <= start => return Vec::new(),
+        Some(end) => Excluded(end),
+        None => Unbounded,
+    };
+    table
+        .range::<str, _>((Included(start), end))
+        .take(limit)
+        .map(|(key, entry)| (key.clone(), entry.clone()))
+        .collect()
+}
+
+/// Drops keys whose every version is a tombstone older than `grace` as of `now` (milliseconds
+/// since the epoch). Returns how many were dropped.
+pub fn collect_garbage(table: &mut Table, grace: Duration, now: u64) -> usize {
+    let cutoff = now.saturating_sub(grace.as_millis() as u64);
+    let before = table.len();
+    table.retain(|_, entry| !entry.is_deleted() || entry.timestamp() >= cutoff);
+    before - table.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(table: &mut Table, node: &str, key: &str, value: Option<&str>) -> bool {
+        let current = table.get(key).cloned().unwrap_or_default();
+        let dot = current.next_dot(node, &current.context());
+        let entry = stamp(table, key, value.map(Into::into), None, dot);
+        merge(table, key.into(), entry)
+    }
+
+    #[test]
+    fn deleted_keys_read_as_missing() {
+        let mut table = Table::default();
+        assert!(write(&mut table, "a", "k", Some("v")));
+        assert_eq!(table["k"].values(), ["v"]);
+        assert!(write(&mut table, "a", "k", None));
+        assert!(table["k"].values().is_empty());
+        // ...but the tombstone is still there, and supersedes the value it replaced.
+        assert!(table["k"].is_deleted());
+        assert_eq!(table["k"].siblings.len(), 1);
+    }
+
+    #[test]
+    fn scans_come_back_in_order() {
+        let mut table = Table::default();
+        for key in ["pear", "apple", "fig", "banana"] {
+            write(&mut table, "a", key, Some(key));
+        }
+        write(&mut table, "a", "fig", None);
+        let keys = |scanned: Vec<(String, Entry)>| -> Vec<String> {
+            scanned.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(scan(&table, "", None, 10)),
+            ["apple", "banana", "fig", "pear"]
+        );
+        assert_eq!(keys(scan(&table, "b", Some("p"), 10)), ["banana", "fig"]);
+        assert_eq!(keys(scan(&table, "banana", None, 2)), ["banana", "fig"]);
+        assert!(scan(&table, "p", Some("b"), 10).is_empty());
+    }
+
+    #[test]
+    fn stale_replicas_dont_clobber_newer_writes() {
+        let mut table = Table::default();
+        write(&mut table, "a", "k", Some("old"));
+        let stale = table["k"].clone();
+        write(&mut table, "a", "k", Some("new"));
+        assert!(!merge(&mut table, "k".into(), stale));
+        assert_eq!(table["k"].values(), ["new"]);
+    }
+
+    #[test]
+    fn blind_writes_on_different_replicas_become_siblings() {
+        let mut left = Table::default();
+        let mut right = Table::default();
+        write(&mut left, "a", "k", Some("left"));
+        write(&mut right, "b", "k", Some("right"));
+        assert!(merge(&mut left, "k".into(), right["k"].clone()));
+        assert_eq!(left["k"].values(), ["left", "right"]);
+
+        // A client that read both resolves them.
+        let context = left["k"].context();
+        let dot = left["k"].next_dot("b", &context);
+        let resolved = stamp(&left, "k", Some("both".into()), Some(context), dot);
+        assert!(merge(&mut right, "k".into(), resolved.clone()));
+        assert_eq!(right["k"].values(), ["both"]);
+        assert!(merge(&mut left, "k".into(), resolved));
+        assert_eq!(left["k"], right["k"]);
+    }
+
+    #[test]
+    fn only_old_tombstones_are_collected() {
+        let grace = Duration::from_secs(60);
+        let now = 1_000_000_000;
+        let mut table = Table::default();
+        let entry = |value: Option<&str>, age: Duration| Entry {
+            siblings: vec![Sibling {
+                dot: Dot {
+                    node: "a".into(),
+                    counter: 1,
+                },
+                context: VectorClock::default(),
+                value: value.map(Into::into),
+                timestamp: now - age.as_millis() as u64,
+            }],
+        };
+        table.insert("old_value".into(), entry(Some("v"), grace * 2));
+        table.insert("old_tombstone".into(), entry(None, grace * 2));
+        table.insert("new_tombstone".into(), entry(None, grace / 2));
+
+        assert_eq!(collect_garbage(&mut table, grace, now), 1);
+        assert!(table.contains_key("old_value"));
+        assert!(!table.contains_key("old_tombstone"));
+        assert!(table.contains_key("new_tombstone"));
+    }
+}
@@ -0,0 +1,494 @@
+//! Write-ahead log. The table only makes it to disk every so often, so every change to it is also
+//! appended here and fsynced before we acknowledge it. On startup, whatever's in the log gets
+//! replayed on top of the last snapshot.
+//!
+//! The log is split into numbered segments, one per snapshot: segment `n` has every write since
+//! snapshot `n` was taken. Segments stick around as long as their snapshot does, so if the newest
+//! snapshot turns out to be bad, an older one plus the segments after it still has everything.
+//!
+//! Entries merge the same way no matter how many times or in what order they're applied, so
+//! replaying a record the snapshot already had (or one that was written twice) is harmless.
+
+use comm::Entry;
+use rmp_serde::Serializer;
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    snapshot,
+    table::{self, Table},
+    TABLE_SERVICE,
+};
+
+/// Each record is its payload's length and CRC-32 (little-endian), then the payload: a key and the
+/// entry that was merged into it, as MessagePack.
+const HEADER_LEN: usize = 8;
+
+static WAL: OnceLock<Wal> = OnceLock::new();
+
+/// A key and the entry that was merged into it.
+type Record = (String, Entry);
+
+struct Wal {
+    /// Segments are named after this plus their number.
+    base: PathBuf,
+    pending: Mutex<Pending>,
+    /// The segment we're appending to.
+    file: Mutex<File>,
+    /// Last record that's safely on disk, or why the log stopped working.
+    synced: watch::Sender<Result<u64, String>>,
+    /// Poked whenever there's something new to flush.
+    appended: Notify,
+    /// How long to hold records back so more can share an fsync.
+    group_commit: Duration,
+}
+
+/// Records that haven't been written out yet.
+#[derive(Default)]
+struct Pending {
+    buffer: Vec<u8>,
+    /// Records appended since we started, counting the ones in `buffer`.
+    count: u64,
+}
+
+fn encode(buffer: &mut Vec<u8>, key: &str, entry: &Entry) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0; HEADER_LEN]);
+    (key, entry)
+        .serialize(&mut Serializer::new(&mut *buffer))
+        .expect("Entries always serialize");
+    let payload = &buffer[start + HEADER_LEN..];
+    let len = (payload.len() as u32).to_le_bytes();
+    let crc = crc32fast::hash(payload).to_le_bytes();
+    buffer[start..start + 4].copy_from_slice(&len);
+    buffer[start + 4..start + HEADER_LEN].copy_from_slice(&crc);
+}
+
+/// Every whole record at the start of `bytes`, and how many bytes they take up. Stops at the first
+/// one that's cut short or doesn't match its checksum, since that's where we crashed mid-write.
+fn decode(bytes: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + HEADER_LEN) {
+        let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+        let Some(payload) = bytes.get(offset + HEADER_LEN..offset + HEADER_LEN + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        let Ok(record) = rmp_serde::from_slice(payload) else {
+            break;
+        };
+        records.push(record);
+        offset += HEADER_LEN + len;
+    }
+    (records, offset)
+}
+
+/// Everything in the segment at `path`. Anything after the last whole record is cut off, so new
+/// records don't end up stuck behind it.
+fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    let bytes = fs::read(path)?;
+    let (records, valid) = decode(&bytes);
+    if valid < bytes.len() {
+        eprintln!(
+            "[WARN] Dropping {} bytes of torn writes from the end of {path:?}",
+            bytes.len() - valid
+        );
+        let file = File::options().write(true).open(path)?;
+        file.set_len(valid as u64)?;
+        file.sync_all()?;
+    }
+    Ok(records)
+}
+
+fn open_segment(path: &Path) -> io::Result<File> {
+    let file = File::options().create(true).append(true).open(path)?;
+    // Make sure the segment itself survives a crash, not just what's in it.
+    snapshot::sync_parent(path)?;
+    Ok(file)
+}
+
+impl Wal {
+    /// Opens the log at `base`, for replaying on top of snapshot `since`. Returns it, the segment
+    /// it'll append to, and every record from `since` on.
+    fn open(
+        base: &Path,
+        since: u64,
+        group_commit: Duration,
+    ) -> io::Result<(Self, u64, Vec<Record>)> {
+        let segments: Vec<_> = snapshot::generations(base)?
+            .into_iter()
+            .filter(|&segment| segment >= since)
+            .collect();
+        // Before the log was split up, it was all in `base`.
+        let mut records = match fs::exists(base)? {
+            true => read_segment(base)?,
+            false => Vec::new(),
+        };
+        for &segment in &segments {
+            records.extend(read_segment(&snapshot::generation_path(base, segment))?);
+        }
+        // A snapshot that didn't make it might have left a newer segment than it.
+        let current = segments.last().copied().unwrap_or(since);
+        let file = open_segment(&snapshot::generation_path(base, current))?;
+        let wal = Self {
+            base: base.to_owned(),
+            pending: Mutex::new(Pending::default()),
+            file: Mutex::new(file),
+            synced: watch::Sender::new(Ok(0)),
+            appended: Notify::new(),
+            group_commit,
+        };
+        Ok((wal, current, records))
+    }
+
+    /// Queues records up to be written. Returns the number to wait on for the last of them.
+    fn append<'a>(&self, records: impl IntoIterator<Item = (&'a str, &'a Entry)>) -> u64 {
+        let mut pending = self.pending.lock().expect("Lock poisoned :(");
+        for (key, entry) in records {
+            encode(&mut pending.buffer, key, entry);
+            pending.count += 1;
+        }
+        self.appended.notify_one();
+        pending.count
+    }
+
+    /// Waits for everything up to record `seq` to be on disk.
+    async fn wait(&self, seq: u64) -> io::Result<()> {
+        let mut synced = self.synced.subscribe();
+        let synced = synced
+            .wait_for(|synced| synced.as_ref().map_or(true, |&synced| synced >= seq))
+            .await
+            .expect("The log outlives its waiters");
+        match &*synced {
+            Ok(_) => Ok(()),
+            Err(e) => Err(io::Error::other(e.clone())),
+        }
+    }
+
+    fn mark_synced(&self, seq: u64) {
+        self.synced.send_modify(|synced| {
+            if let Ok(synced) = synced {
+                *synced = seq.max(*synced);
+            }
+        });
+    }
+
+    /// Takes whatever's been appended. Only with the file locked (hence `_file`), so nothing else
+    /// can be taken, or marked synced, until these are written.
+    fn take_pending(&self, _file: &File) -> (Vec<u8>, u64) {
+        let mut pending = self.pending.lock().expect("Lock poisoned :(");
+        (mem::take(&mut pending.buffer), pending.count)
+    }
+
+    /// Writes out and fsyncs whatever's been appended. Everything that piled up while the last
+    /// fsync was going goes out in one go.
+    fn flush(&self) -> io::Result<()> {
+        let mut file = self.file.lock().expect("Lock poisoned :(");
+        let (buffer, count) = self.take_pending(&file);
+        if !buffer.is_empty() {
+            file.write_all(&buffer)?;
+            file.sync_data()?;
+        }
+        self.mark_synced(count);
+        Ok(())
+    }
+
+    /// Finishes the current segment and starts segment `next`. Only safe while nothing else can
+    /// be appended, so everything before the switch is in snapshot `next` and nothing after is.
+    fn rotate(&self, next: u64) -> io::Result<()> {
+        let mut file = self.file.lock().expect("Lock poisoned :(");
+        let (buffer, count) = self.take_pending(&file);
+        file.write_all(&buffer)?;
+        file.sync_data()?;
+        *file = open_segment(&snapshot::generation_path(&self.base, next))?;
+        self.mark_synced(count);
+        Ok(())
+    }
+
+    /// Deletes the segments from before snapshot `oldest`, once nothing could need them.
+    fn prune(&self, oldest: u64) -> io::Result<()> {
+        for segment in snapshot::generations(&self.base)? {
+            if segment < oldest {
+                fs::remove_file(snapshot::generation_path(&self.base, segment))?;
+            }
+        }
+        match fs::remove_file(&self.base) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Replays the log at `base` into `table`, which is snapshot `since`, and keeps logging there from
+/// now on. Returns the newest segment, which no snapshot has yet.
+pub fn recover(
+    base: &Path,
+    since: u64,
+    table: &mut Table,
+    group_commit: Duration,
+) -> io::Result<u64> {
+    let (wal, current, records) = Wal::open(base, since, group_commit)?;
+    let replayed = records.len();
+    for (key, entry) in records {
+        table::merge(table, key, entry);
+    }
+    eprintln!("[INFO] Replayed {replayed} writes from {base:?} since snapshot {since}");
+    WAL.set(wal)
+        .map_err(|_| io::Error::other("write-ahead log opened twice"))?;
+    Ok(current)
+}
+
+/// Folds `entries` into the table and queues up the ones that changed anything. Returns how many
+/// did, and the record to wait on if there's a log.
+fn apply(entries: impl IntoIterator<Item = (String, Entry)>) -> (usize, Option<u64>) {
+    let mut table = TABLE_SERVICE.write().expect("Lock poisoned :(");
+    let changed: Vec<_> = entries
+        .into_iter()
+        .filter(|(key, entry)| table::merge(&mut table, key.clone(), entry.clone()))
+        .collect();
+    // Appended with the table still locked, so a snapshot either has a change or sees it in the
+    // log, never neither.
+    let seq = WAL.get().filter(|_| !changed.is_empty()).map(|wal| {
+        let records = changed.iter().map(|(key, entry)| (key.as_str(), entry));
+        wal.append(records)
+    });
+    (changed.len(), seq)
+}
+
+/// Folds `entries` into the table, and doesn't return until the ones that changed anything are
+/// on disk. Returns how many did.
+pub async fn merge_all(entries: impl IntoIterator<Item = (String, Entry)>) -> io::Result<usize> {
+    let (changed, seq) = apply(entries);
+    if let (Some(wal), Some(seq)) = (WAL.get(), seq) {
+        wal.wait(seq).await?;
+    }
+    Ok(changed)
+}
+
+/// [`merge_all`] for one entry. Returns whether it changed anything.
+pub async fn merge(key: String, entry: Entry) -> io::Result<bool> {
+    Ok(merge_all([(key, entry)]).await? > 0)
+}
+
+/// Starts logging to segment `next`. Call it with the table still locked from copying it for
+/// snapshot `next`.
+pub fn rotate(next: u64) -> io::Result<()> {
+    match WAL.get() {
+        Some(wal) => wal.rotate(next),
+        None => Ok(()),
+    }
+}
+
+/// Forgets the segments from before snapshot `oldest`.
+pub fn prune(oldest: u64) -> io::Result<()> {
+    match WAL.get() {
+        Some(wal) => wal.prune(oldest),
+        None => Ok(()),
+    }
+}
+
+pub async fn flush_loop() {
+    let Some(wal) = WAL.get() else {
+        return;
+    };
+    loop {
+        wal.appended.notified().await;
+        // Give other writes a moment to catch up, so they can share the fsync.
+        if !wal.group_commit.is_zero() {
+            tokio::time::sleep(wal.group_commit).await;
+        }
+        let flushed = tokio::task::spawn_blocking(|| wal.flush()).await;
+        if let Ok(Err(e)) = flushed {
+            // Whatever made it to disk is still good, but nothing after it can be vouched for.
+            eprintln!("[ERROR] Couldn't write to the write-ahead log: {e}");
+            wal.synced
+                .send_modify(|synced| *synced = Err(e.to_string()));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::{
+        version::{Dot, Sibling},
+        VectorClock,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn entry(node: &str, value: Option<&str>) -> Entry {
+        Entry {
+            siblings: vec![Sibling {
+                dot: Dot {
+                    node: node.into(),
+                    counter: 1,
+                },
+                context: VectorClock::default(),
+                value: value.map(Into::into),
+                timestamp: 0,
+            }],
+        }
+    }
+
+    fn records() -> Vec<(String, Entry)> {
+        vec![
+            ("apple".into(), entry("a", Some("red"))),
+            ("banana".into(), entry("b", Some("yellow"))),
+            ("apple".into(), entry("b", None)),
+            ("".into(), entry("c", Some(""))),
+            ("cherry".into(), entry("a", Some(&"pit".repeat(100)))),
+        ]
+    }
+
+    fn refs(records: &[(String, Entry)]) -> impl Iterator<Item = (&str, &Entry)> {
+        records.iter().map(|(key, entry)| (key.as_str(), entry))
+    }
+
+    /// Where to put a log, in a directory of its own.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir.join("node.wal")
+    }
+
+    #[test]
+    fn crashing_anywhere_keeps_every_whole_record() {
+        let records = records();
+        let mut bytes = Vec::new();
+        let mut ends = vec![0];
+        for (key, entry) in &records {
+            encode(&mut bytes, key, entry);
+            ends.push(bytes.len());
+        }
+
+        let base = scratch("torn");
+        let segment = snapshot::generation_path(&base, 0);
+        for cut in 0..=bytes.len() {
+            fs::write(&segment, &bytes[..cut]).unwrap();
+            let (wal, current, recovered) = Wal::open(&base, 0, Duration::ZERO).unwrap();
+            assert_eq!(current, 0);
+            let whole = ends.iter().filter(|&&end| end <= cut).count() - 1;
+            assert_eq!(recovered, records[..whole], "cut at {cut}");
+            // The torn bit is gone, so the next record lands right after the last good one.
+            assert_eq!(fs::metadata(&segment).unwrap().len(), ends[whole] as u64);
+            wal.append([("after", &records[0].1)]);
+            wal.flush().unwrap();
+            drop(wal);
+            let (_, _, recovered) = Wal::open(&base, 0, Duration::ZERO).unwrap();
+            assert_eq!(recovered.len(), whole + 1, "cut at {cut}");
+        }
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn corrupt_records_end_the_replay() {
+        let records = records();
+        let mut bytes = Vec::new();
+        for (key, entry) in &records {
+            encode(&mut bytes, key, entry);
+        }
+        let (_, whole) = decode(&bytes);
+        assert_eq!(whole, bytes.len());
+
+        // Flip a bit in the second record's payload.
+        let mut first = Vec::new();
+        encode(&mut first, &records[0].0, &records[0].1);
+        bytes[first.len() + HEADER_LEN + 2] ^= 1;
+        let (recovered, valid) = decode(&bytes);
+        assert_eq!(recovered, records[..1]);
+        assert_eq!(valid, first.len());
+    }
+
+    #[tokio::test]
+    async fn segments_replay_on_top_of_their_snapshots() {
+        let base = scratch("segments");
+        let records = records();
+        let (wal, current, recovered) = Wal::open(&base, 0, Duration::ZERO).unwrap();
+        assert_eq!(current, 0);
+        assert!(recovered.is_empty());
+        let seq = wal.append(refs(&records[..2]));
+        assert_eq!(seq, 2);
+        wal.flush().unwrap();
+        wal.wait(seq).await.unwrap();
+        // Rotating writes out whatever's still waiting, so it's acknowledged right away.
+        let seq = wal.append(refs(&records[2..3]));
+        wal.rotate(1).unwrap();
+        wal.wait(seq).await.unwrap();
+        wal.append(refs(&records[3..]));
+        wal.flush().unwrap();
+        drop(wal);
+
+        // Snapshot 1 has the first three, so only the rest replay on top of it. Snapshot 0 (the
+        // empty table) needs all of them.
+        let (_, current, recovered) = Wal::open(&base, 1, Duration::ZERO).unwrap();
+        assert_eq!(current, 1);
+        assert_eq!(recovered, records[3..]);
+        let (wal, current, recovered) = Wal::open(&base, 0, Duration::ZERO).unwrap();
+        assert_eq!(current, 1);
+        assert_eq!(recovered, records);
+
+        wal.prune(1).unwrap();
+        assert_eq!(snapshot::generations(&base).unwrap(), [1]);
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_acknowledged_before_its_on_disk() {
+        let base = scratch("race");
+        let (wal, _, _) = Wal::open(&base, 0, Duration::ZERO).unwrap();
+        let (key, entry) = &records()[0];
+        let on_disk = || -> usize {
+            let segments = snapshot::generations(&base).unwrap();
+            let segments = segments.into_iter().map(|segment| {
+                let bytes = fs::read(snapshot::generation_path(&base, segment)).unwrap();
+                decode(&bytes).0.len()
+            });
+            segments.sum()
+        };
+
+        // Rotating while records are still coming in isn't how the store uses it, but a flush
+        // can still be halfway done when a snapshot starts.
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..400 {
+                    wal.append([(key.as_str(), entry)]);
+                    wal.flush().unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            });
+            scope.spawn(|| {
+                for next in 1.. {
+                    if done.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    wal.rotate(next).unwrap();
+                }
+            });
+            // Holding the file keeps everyone else waiting on it, so flushes and rotations pile up
+            // behind us in every order.
+            while !done.load(Ordering::SeqCst) {
+                let _file = wal.file.lock().unwrap();
+                let synced = *wal.synced.borrow().as_ref().unwrap();
+                assert!(on_disk() as u64 >= synced, "{synced} acknowledged");
+            }
+        });
+        wal.flush().unwrap();
+        assert_eq!(on_disk(), 400);
+        fs::remove_dir_all(base.parent().unwrap()).unwrap();
+    }
+}